@@ -0,0 +1,235 @@
+//! Buckets used by [`Histogram`](crate::Histogram)s.
+
+/// The number of buckets in a [`HistogramValue`].
+///
+/// There is a bucket for zero and a bucket for each power of two up to `2^64`. Linear and explicit buckets may use up
+/// to one less than this for their bounds, the last bucket is kept for values larger than any bound.
+pub const BPF_HISTOGRAM_BUCKETS: usize = 65;
+
+/// The number of steps needed to binary search the bounds of [`Buckets::Explicit`].
+const EXPLICIT_SEARCH_STEPS: usize = (usize::BITS - (BPF_HISTOGRAM_BUCKETS - 1).leading_zeros()) as usize;
+
+/// The buckets values of a [`Histogram`](crate::Histogram) are counted in.
+///
+/// Each bucket has an inclusive upper bound and counts values larger than the bound of the previous bucket. Linear
+/// and explicit buckets have an additional bucket for values larger than the last bound.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Buckets {
+    /// Buckets for each power of two, see [`log2_bucket`].
+    #[default]
+    Log2,
+    /// `count` buckets of equal width, with bounds `start`, `start + step`, ..., `start + (count - 1) * step`.
+    ///
+    /// The first bucket counts every value up to `start`. `count` should be less than [`BPF_HISTOGRAM_BUCKETS`] and
+    /// `step` should not be zero.
+    Linear {
+        /// The bound of the first bucket.
+        start: u64,
+        /// The width of each bucket.
+        step: u64,
+        /// The number of bounds.
+        count: u32,
+    },
+    /// Buckets with explicit bounds.
+    ///
+    /// The bounds must be sorted in ascending order and there should be less than [`BPF_HISTOGRAM_BUCKETS`].
+    Explicit(&'static [u64]),
+}
+
+impl Buckets {
+    /// The bucket a value is counted in.
+    ///
+    /// This is always less than [`Buckets::num_buckets`] and compiles to a bounded search which the verifier accepts.
+    #[inline(always)]
+    pub fn bucket(&self, value: u64) -> usize {
+        match *self {
+            Buckets::Log2 => log2_bucket(value),
+            Buckets::Linear { start, step, count } => {
+                let count = (count as usize).min(BPF_HISTOGRAM_BUCKETS - 1);
+                if value <= start || step == 0 {
+                    0
+                } else {
+                    // Round up, avoiding an overflow when adding the step to the value
+                    let bucket = (value - start - 1) / step + 1;
+                    (bucket as usize).min(count)
+                }
+            }
+            Buckets::Explicit(bounds) => {
+                // Find the first bound which the value does not exceed, or the last bucket if it exceeds all of them.
+                // The number of steps is fixed so the verifier can prove the loop terminates.
+                let (mut low, mut high) = (0, bounds.len().min(BPF_HISTOGRAM_BUCKETS - 1));
+                for _ in 0..EXPLICIT_SEARCH_STEPS {
+                    if low >= high {
+                        break;
+                    }
+                    let mid = low + (high - low) / 2;
+                    match bounds.get(mid) {
+                        Some(bound) if value <= *bound => high = mid,
+                        _ => low = mid + 1,
+                    }
+                }
+                low
+            }
+        }
+    }
+
+    /// The number of buckets, including the bucket for values larger than the last bound.
+    pub fn num_buckets(&self) -> usize {
+        match *self {
+            Buckets::Log2 => BPF_HISTOGRAM_BUCKETS,
+            Buckets::Linear { count, .. } => (count as usize).min(BPF_HISTOGRAM_BUCKETS - 1) + 1,
+            Buckets::Explicit(bounds) => bounds.len().min(BPF_HISTOGRAM_BUCKETS - 1) + 1,
+        }
+    }
+
+    /// The largest value which is counted in a bucket.
+    ///
+    /// This is [`u64::MAX`] for the last bucket.
+    pub fn upper_bound(&self, bucket: usize) -> u64 {
+        if bucket + 1 >= self.num_buckets() {
+            return u64::MAX;
+        }
+        match *self {
+            Buckets::Log2 => log2_upper_bound(bucket),
+            Buckets::Linear { start, step, .. } => start.saturating_add(step.saturating_mul(bucket as u64)),
+            Buckets::Explicit(bounds) => bounds.get(bucket).copied().unwrap_or(u64::MAX),
+        }
+    }
+}
+
+/// The buckets of a [`Histogram`](crate::Histogram) held by a single CPU in a BPF per CPU array.
+///
+/// Each bucket counts the number of values recorded into it, see [`Buckets`].
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct HistogramValue {
+    /// The number of values recorded into each bucket.
+    pub buckets: [u64; BPF_HISTOGRAM_BUCKETS],
+}
+
+impl HistogramValue {
+    /// A histogram with no recorded values.
+    pub const EMPTY: HistogramValue = HistogramValue {
+        buckets: [0u64; BPF_HISTOGRAM_BUCKETS],
+    };
+}
+
+impl Default for HistogramValue {
+    fn default() -> Self {
+        HistogramValue::EMPTY
+    }
+}
+
+// SAFETY: HistogramValue is `repr(C)` and only contains `u64` fields, so it has no padding.
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for HistogramValue {}
+
+/// The log2 bucket of a value.
+///
+/// Zero is counted in bucket `0` and any other value is counted in bucket `n` where `2^(n-1) <= value < 2^n`.
+#[inline(always)]
+pub fn log2_bucket(value: u64) -> usize {
+    (u64::BITS - value.leading_zeros()) as usize
+}
+
+/// The largest value which is counted in a log2 bucket, see [`log2_bucket`].
+pub fn log2_upper_bound(bucket: usize) -> u64 {
+    match bucket {
+        0 => 0,
+        bucket if bucket >= u64::BITS as usize => u64::MAX,
+        bucket => (1u64 << bucket) - 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case(0, 0)]
+    #[case(1, 1)]
+    #[case(2, 2)]
+    #[case(3, 2)]
+    #[case(4, 3)]
+    #[case(1500, 11)]
+    #[case(u64::MAX, 64)]
+    fn test_log2_bucket(#[case] value: u64, #[case] bucket: usize) {
+        assert_eq!(log2_bucket(value), bucket);
+        assert!(value <= log2_upper_bound(bucket));
+        if bucket > 0 {
+            assert!(value > log2_upper_bound(bucket - 1));
+        }
+    }
+
+    const BOUNDS: &[u64] = &[1, 5, 10, 50, 100];
+
+    #[rstest]
+    #[case(Buckets::Log2, 1500, 11)]
+    #[case(Buckets::Linear { start: 1, step: 1, count: 10 }, 0, 0)]
+    #[case(Buckets::Linear { start: 1, step: 1, count: 10 }, 1, 0)]
+    #[case(Buckets::Linear { start: 1, step: 1, count: 10 }, 2, 1)]
+    #[case(Buckets::Linear { start: 1, step: 1, count: 10 }, 10, 9)]
+    #[case(Buckets::Linear { start: 1, step: 1, count: 10 }, 11, 10)]
+    #[case(Buckets::Linear { start: 1, step: 1, count: 10 }, u64::MAX, 10)]
+    #[case(Buckets::Linear { start: 0, step: 250, count: 4 }, 250, 1)]
+    #[case(Buckets::Linear { start: 0, step: 250, count: 4 }, 251, 2)]
+    #[case(Buckets::Linear { start: 0, step: 250, count: 4 }, 751, 4)]
+    #[case(Buckets::Explicit(BOUNDS), 0, 0)]
+    #[case(Buckets::Explicit(BOUNDS), 1, 0)]
+    #[case(Buckets::Explicit(BOUNDS), 2, 1)]
+    #[case(Buckets::Explicit(BOUNDS), 10, 2)]
+    #[case(Buckets::Explicit(BOUNDS), 99, 4)]
+    #[case(Buckets::Explicit(BOUNDS), 100, 4)]
+    #[case(Buckets::Explicit(BOUNDS), 101, 5)]
+    #[case(Buckets::Explicit(&[]), 101, 0)]
+    fn test_bucket(#[case] buckets: Buckets, #[case] value: u64, #[case] bucket: usize) {
+        assert_eq!(buckets.bucket(value), bucket);
+        assert!(bucket < buckets.num_buckets());
+        assert!(value <= buckets.upper_bound(bucket));
+        if bucket > 0 {
+            assert!(value > buckets.upper_bound(bucket - 1));
+        }
+    }
+
+    #[test]
+    fn test_explicit_bucket_search_is_exhaustive() {
+        let bounds: Vec<u64> = (1..BPF_HISTOGRAM_BUCKETS as u64).map(|bound| bound * 10).collect();
+        let buckets = Buckets::Explicit(bounds.leak());
+        assert_eq!(buckets.num_buckets(), BPF_HISTOGRAM_BUCKETS);
+        for (bucket, bound) in (0..BPF_HISTOGRAM_BUCKETS).map(|bucket| (bucket, buckets.upper_bound(bucket))) {
+            assert_eq!(buckets.bucket(bound), bucket);
+        }
+    }
+
+    #[test]
+    fn test_num_buckets() {
+        assert_eq!(Buckets::Log2.num_buckets(), BPF_HISTOGRAM_BUCKETS);
+        assert_eq!(
+            Buckets::Linear {
+                start: 0,
+                step: 1,
+                count: 10
+            }
+            .num_buckets(),
+            11
+        );
+        assert_eq!(
+            Buckets::Linear {
+                start: 0,
+                step: 1,
+                count: 1000
+            }
+            .num_buckets(),
+            BPF_HISTOGRAM_BUCKETS
+        );
+        assert_eq!(Buckets::Explicit(BOUNDS).num_buckets(), 6);
+    }
+
+    #[test]
+    fn test_log2_buckets_fit() {
+        assert_eq!(log2_bucket(u64::MAX), BPF_HISTOGRAM_BUCKETS - 1);
+        assert_eq!(log2_upper_bound(BPF_HISTOGRAM_BUCKETS - 1), u64::MAX);
+    }
+}
@@ -0,0 +1,161 @@
+//! Registers used by [`DistinctCount`](crate::DistinctCount) meters.
+
+use core::{mem::size_of, slice};
+
+/// The number of bits of a hash used to select a register of a [`HyperLogLogValue`].
+///
+/// This gives a standard error of about `1.04 / sqrt(BPF_HLL_REGISTERS)`, or 3.25%.
+pub const BPF_HLL_PRECISION: u32 = 10;
+
+/// The number of registers in a [`HyperLogLogValue`].
+pub const BPF_HLL_REGISTERS: usize = 1 << BPF_HLL_PRECISION;
+
+/// The HyperLogLog registers of a [`DistinctCount`](crate::DistinctCount) held by a single CPU in a BPF per CPU array.
+///
+/// Each register holds the largest rank, the position of the first set bit, of the hashes selecting it. The number of
+/// distinct values recorded is estimated from the ranks, see [`HyperLogLogValue::estimate`].
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct HyperLogLogValue {
+    /// The largest rank of each register.
+    pub registers: [u8; BPF_HLL_REGISTERS],
+}
+
+impl HyperLogLogValue {
+    /// Registers with no recorded values.
+    pub const EMPTY: HyperLogLogValue = HyperLogLogValue {
+        registers: [0u8; BPF_HLL_REGISTERS],
+    };
+
+    /// Record the hash of a value, see [`hll_hash`].
+    ///
+    /// The first [`BPF_HLL_PRECISION`] bits select a register and the remaining bits give the rank.
+    #[inline(always)]
+    pub fn record(&mut self, hash: u64) {
+        let register = (hash >> (u64::BITS - BPF_HLL_PRECISION)) as usize;
+        let rank = ((hash << BPF_HLL_PRECISION).leading_zeros() + 1).min(u64::BITS - BPF_HLL_PRECISION + 1) as u8;
+        // The register is always in bounds but the check keeps the verifier happy.
+        if let Some(register) = self.registers.get_mut(register) {
+            if rank > *register {
+                *register = rank;
+            }
+        }
+    }
+
+    /// Merge the registers of another value, such as the value of another CPU.
+    #[cfg(any(test, feature = "user"))]
+    pub fn merge(&mut self, other: &HyperLogLogValue) {
+        for (register, other) in self.registers.iter_mut().zip(other.registers) {
+            *register = (*register).max(other);
+        }
+    }
+
+    /// Estimate the number of distinct values recorded.
+    ///
+    /// Small cardinalities are estimated by linear counting of the empty registers. A 64 bit hash needs no correction
+    /// for large cardinalities.
+    #[cfg(any(test, feature = "user"))]
+    pub fn estimate(&self) -> f64 {
+        let m = BPF_HLL_REGISTERS as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let sum: f64 = self.registers.iter().map(|rank| 2f64.powi(-(*rank as i32))).sum();
+        let estimate = alpha * m * m / sum;
+
+        let zeros = self.registers.iter().filter(|rank| **rank == 0).count();
+        if estimate <= 2.5 * m && zeros > 0 {
+            m * (m / zeros as f64).ln()
+        } else {
+            estimate
+        }
+    }
+}
+
+impl Default for HyperLogLogValue {
+    fn default() -> Self {
+        HyperLogLogValue::EMPTY
+    }
+}
+
+// SAFETY: HyperLogLogValue is `repr(C)` and only contains `u8` fields, so it has no padding.
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for HyperLogLogValue {}
+
+/// Hash the bytes of a value, which should not contain padding.
+///
+/// This is FNV-1a followed by the finalizer of MurmurHash3, so every bit of the hash depends on every byte of the
+/// value. It is not keyed, so values chosen to collide can skew an estimate.
+#[inline(always)]
+pub fn hll_hash<K: Copy>(value: &K) -> u64 {
+    // SAFETY: The value is `Copy` and is read as bytes for the size of its type.
+    let bytes = unsafe { slice::from_raw_parts(value as *const K as *const u8, size_of::<K>()) };
+
+    let mut hash = 0xcbf2_9ce4_8422_2325u64;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^ (hash >> 33)
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[test]
+    fn test_record() {
+        let mut value = HyperLogLogValue::EMPTY;
+
+        // The first register with a rank of one, as the first bit after the register is set
+        value.record(1 << (u64::BITS - BPF_HLL_PRECISION - 1));
+        assert_eq!(value.registers[0], 1);
+
+        // The last register with the largest rank, as no bits after the register are set
+        value.record(u64::MAX << (u64::BITS - BPF_HLL_PRECISION));
+        assert_eq!(value.registers[BPF_HLL_REGISTERS - 1], (u64::BITS - BPF_HLL_PRECISION + 1) as u8);
+
+        // A smaller rank does not replace a larger one
+        value.record(u64::MAX >> BPF_HLL_PRECISION);
+        assert_eq!(value.registers[0], 1);
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut value = HyperLogLogValue::EMPTY;
+        let mut other = HyperLogLogValue::EMPTY;
+        value.registers[0] = 3;
+        other.registers[0] = 1;
+        other.registers[1] = 2;
+        value.merge(&other);
+        assert_eq!(&value.registers[..3], &[3, 2, 0]);
+    }
+
+    #[rstest]
+    #[case(0)]
+    #[case(10)]
+    #[case(1_000)]
+    #[case(100_000)]
+    fn test_estimate(#[case] count: u32) {
+        let mut value = HyperLogLogValue::EMPTY;
+        for n in 0..count {
+            // Record every value twice, duplicates must not be counted
+            value.record(hll_hash(&n));
+            value.record(hll_hash(&n));
+        }
+        let error = (value.estimate() - count as f64).abs() / (count as f64).max(1.0);
+        assert!(error < 0.05, "estimated {} for {count} distinct values", value.estimate());
+    }
+
+    #[test]
+    fn test_hll_hash() {
+        assert_eq!(hll_hash(&1u32), hll_hash(&1u32));
+        assert_ne!(hll_hash(&1u32), hll_hash(&2u32));
+        assert_ne!(hll_hash(&[10u8, 0, 0, 1]), hll_hash(&[10u8, 0, 0, 2]));
+    }
+}
@@ -0,0 +1,80 @@
+//! Keys used by meters which hold a value per key, such as [`KeyedCounter`](crate::KeyedCounter)s and timers.
+
+use core::{marker::PhantomData, mem::size_of, ptr};
+
+/// The maximum size in bytes of a key given to a [`KeyedCounter`](crate::KeyedCounter) or a timer.
+pub const BPF_KEYED_KEY_SIZE: usize = 20;
+
+/// Fails to compile when a key does not fit in a [`MeterKey`].
+struct KeySize<K>(PhantomData<K>);
+
+impl<K> KeySize<K> {
+    const FITS: () =
+        assert!(size_of::<K>() <= BPF_KEYED_KEY_SIZE, "keys of meters must not be larger than BPF_KEYED_KEY_SIZE");
+}
+
+/// The key of a value held per key by a meter in a BPF hash map, such as a [`KeyedCounter`](crate::KeyedCounter) series.
+///
+/// Keys smaller than [`BPF_KEYED_KEY_SIZE`] are padded with zeroes, so a key should not contain any padding of its own
+/// or equal keys may be held in different values.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct MeterKey {
+    /// The index of the meter.
+    pub index: u32,
+    /// The bytes of the key.
+    pub key: [u8; BPF_KEYED_KEY_SIZE],
+}
+
+impl MeterKey {
+    /// Create the key of a value from the index of a meter and a key.
+    ///
+    /// Fails to compile if `K` is larger than [`BPF_KEYED_KEY_SIZE`].
+    #[inline(always)]
+    pub fn new<K: Copy>(index: u32, key: &K) -> Self {
+        #[allow(clippy::let_unit_value)]
+        let () = KeySize::<K>::FITS;
+
+        let mut bytes = [0u8; BPF_KEYED_KEY_SIZE];
+        // SAFETY: `K` is no larger than the buffer, which was checked at compile time.
+        unsafe { ptr::copy_nonoverlapping(key as *const K as *const u8, bytes.as_mut_ptr(), size_of::<K>()) };
+        MeterKey { index, key: bytes }
+    }
+
+    /// Read the key back out of the key of a value.
+    ///
+    /// Fails to compile if `K` is larger than [`BPF_KEYED_KEY_SIZE`].
+    #[cfg(feature = "user")]
+    pub fn key<K: aya::Pod>(&self) -> K {
+        #[allow(clippy::let_unit_value)]
+        let () = KeySize::<K>::FITS;
+
+        // SAFETY: `K` is no larger than the buffer and is valid for any bit pattern, as it is `Pod`.
+        unsafe { ptr::read_unaligned(self.key.as_ptr() as *const K) }
+    }
+}
+
+// SAFETY: MeterKey is `repr(C)` and has a `u32` followed by a byte array whose length is a multiple of four, so
+// it has no padding.
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for MeterKey {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_meter_key() {
+        let key = MeterKey::new(3, &[192u8, 168, 0, 1]);
+        assert_eq!(key.index, 3);
+        assert_eq!(&key.key[..4], &[192, 168, 0, 1]);
+        assert!(key.key[4..].iter().all(|byte| *byte == 0));
+        assert_eq!(size_of::<MeterKey>(), 4 + BPF_KEYED_KEY_SIZE);
+    }
+
+    #[test]
+    fn test_meter_key_is_padded_with_zeroes() {
+        assert_eq!(MeterKey::new(0, &1u32), MeterKey::new(0, &1u64));
+        assert_ne!(MeterKey::new(0, &1u32), MeterKey::new(1, &1u32));
+    }
+}
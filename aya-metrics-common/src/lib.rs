@@ -6,37 +6,290 @@
 //! Provides common metric functionality for use in both user space and in BPF.
 //!
 
-/// The maximum number of counters that can be inserted the BPF per CPU array.
-/// Setting it to 64 is arbitrary and this should be generalized before releasing [aya-metrics-ebpf].
+/// The default number of counters that can be inserted the BPF per CPU array.
+///
+/// The map may be resized when the eBPF program is loaded, see [`Counter::MAX_ENTRIES`].
 pub const BPF_COUNTERS_MAX_ENTRIES: usize = 64;
 
-/// The kind of [`Meter`].
+/// The default number of CPUs which can each increment their own counters in a BPF array mapped into user space.
+///
+/// The map may be resized when the eBPF program is loaded, see [`CounterStorage::Mmap`].
+pub const BPF_MMAP_COUNTERS_MAX_CPUS: usize = 64;
+
+/// The maximum number of gauges that can be inserted the BPF per CPU array.
+pub const BPF_GAUGES_MAX_ENTRIES: usize = 64;
+
+/// The maximum number of histograms that can be inserted the BPF per CPU array.
+pub const BPF_HISTOGRAMS_MAX_ENTRIES: usize = 64;
+
+/// The maximum number of up down counters that can be inserted the BPF per CPU array.
+pub const BPF_UP_DOWN_COUNTERS_MAX_ENTRIES: usize = 64;
+
+/// The maximum number of max meters that can be inserted the BPF per CPU array.
+pub const BPF_MAXES_MAX_ENTRIES: usize = 64;
+
+/// The maximum number of min meters that can be inserted the BPF per CPU array.
+pub const BPF_MINS_MAX_ENTRIES: usize = 64;
+
+/// The maximum number of distinct counts that can be inserted the BPF per CPU array.
+pub const BPF_DISTINCT_COUNTS_MAX_ENTRIES: usize = 64;
+
+/// The maximum number of heavy hitters that can be inserted the BPF per CPU array of count-min sketches.
+pub const BPF_HEAVY_HITTERS_MAX_ENTRIES: usize = 16;
+
+/// The maximum number of compound counters that can be inserted the BPF per CPU array.
+pub const BPF_COMPOUND_COUNTERS_MAX_ENTRIES: usize = 64;
+
+/// The maximum number of fields of each compound counter, see [`CompoundCounter::FIELDS`].
+pub const BPF_COMPOUND_COUNTER_FIELDS: usize = 4;
+
+/// The maximum number of candidate keys that can be inserted the BPF per CPU hash map, across all heavy hitters.
+pub const BPF_HEAVY_HITTER_CANDIDATES_MAX_ENTRIES: usize = 1024;
+
+/// The name of the BPF map holding the candidate keys of [`HeavyHitter`]s, alongside the map of their sketches.
+pub const HEAVY_HITTER_CANDIDATES_MAP_NAME: &str = "HEAVY_HITTER_CANDIDATES";
+
+/// The maximum number of keyed counter series that can be inserted the BPF per CPU hash map.
 ///
-/// Currently only [`MeterKind::Counter`] is supported but other meters could be added.
+/// Each key seen by any keyed counter holds its own entry.
+pub const BPF_KEYED_COUNTERS_MAX_ENTRIES: usize = 1024;
+
+/// The maximum number of keyed counters, each of which counts its increments dropped in BPF in the per CPU array named
+/// [`KEYED_COUNTERS_DROPPED_MAP_NAME`].
+pub const BPF_KEYED_COUNTERS_DROPPED_MAX_ENTRIES: usize = 64;
+
+/// The name of the BPF map counting the increments of [`KeyedCounter`]s which were dropped as their key could not be
+/// inserted, alongside the map of their keys.
+pub const KEYED_COUNTERS_DROPPED_MAP_NAME: &str = "KEYED_COUNTERS_DROPPED";
+
+/// The maximum number of timers which can be started and not yet stopped at once, across all histograms.
+pub const BPF_TIMERS_MAX_ENTRIES: usize = 10240;
+
+mod histogram;
+mod hll;
+mod keyed;
+mod sketch;
+
+pub use histogram::*;
+pub use hll::*;
+pub use keyed::*;
+pub use sketch::*;
+
+/// Derive [`Counter`] for an enumeration, see [`aya_metrics_derive::Counter`].
+#[cfg(feature = "derive")]
+pub use aya_metrics_derive::Counter;
+
+/// Defines the dimension with which a meter is emitted.
+#[cfg(any(test, feature = "user"))]
+#[derive(Clone, Debug, PartialEq)]
+pub enum Dimension {
+    /// Dimension with additional labels.
+    By(Vec<metrics::Label>),
+    /// Dimension with cpu and additional labels.
+    ByCpu(Vec<metrics::Label>),
+}
+
+/// The name of a meter, which borrows static names rather than allocating them.
+#[cfg(any(test, feature = "user"))]
+pub use metrics::SharedString;
+
+/// Iterate over every meter of an enumeration, such as by deriving `strum_macros::EnumIter`.
+#[cfg(feature = "user")]
+pub use strum::{self, IntoEnumIterator};
+
+// Items used by the expansion of derive macros, which are not part of the public API.
+#[doc(hidden)]
+#[cfg(any(test, feature = "user"))]
+pub mod __private {
+    pub use metrics;
+    pub use std::string::String;
+}
+
+/// Expands to the user space only items of a trait implementation when they are available, used by derive macros.
+#[doc(hidden)]
+#[macro_export]
+#[cfg(any(test, feature = "user"))]
+macro_rules! __user {
+    ($($tt:tt)*) => {
+        $($tt)*
+    };
+}
+
+/// Expands to the user space only items of a trait implementation when they are available, used by derive macros.
+#[doc(hidden)]
+#[macro_export]
+#[cfg(not(any(test, feature = "user")))]
+macro_rules! __user {
+    ($($tt:tt)*) => {};
+}
+
+/// The kind of [`Meter`].
 pub enum MeterKind {
     /// Counters monitor monotonically increasing values. Counters may never be reset to a lesser value.
     Counter,
+    /// Gauges monitor values which can go up and down, such as the depth of a queue.
+    Gauge,
+    /// Histograms monitor the distribution of values, such as packet sizes or latencies.
+    Histogram,
+    /// Up down counters monitor values which are incremented and decremented, such as active connections.
+    UpDownCounter,
+    /// Keyed counters monitor monotonically increasing values for each key seen in BPF, such as a source address.
+    KeyedCounter,
+    /// Max meters monitor the largest value recorded in each period, such as the largest burst of packets.
+    Max,
+    /// Min meters monitor the smallest value recorded in each period, such as the smallest free space in a queue.
+    Min,
+    /// Distinct counts monitor the approximate number of distinct values recorded in each period, such as source
+    /// addresses.
+    DistinctCount,
+    /// Heavy hitters monitor the keys with the largest counts in each period, such as the busiest flows.
+    HeavyHitter,
+    /// Compound counters monitor several monotonically increasing values which are incremented together, such as the
+    /// packets and bytes seen by a program.
+    CompoundCounter,
 }
 
 impl MeterKind {
-    /// The name of the BPF map for this kind of [`Meter`].
-    pub fn map_name(&self) -> &str {
+    /// The name of the default BPF map for this kind of [`Meter`].
+    ///
+    /// Counters may be held in a map with another name, see [`Counter::MAP_NAME`].
+    pub const fn map_name(&self) -> &'static str {
         match self {
             MeterKind::Counter => "COUNTERS",
+            MeterKind::Gauge => "GAUGES",
+            MeterKind::Histogram => "HISTOGRAMS",
+            MeterKind::UpDownCounter => "UP_DOWN_COUNTERS",
+            MeterKind::KeyedCounter => "KEYED_COUNTERS",
+            MeterKind::Max => "MAXES",
+            MeterKind::Min => "MINS",
+            MeterKind::DistinctCount => "DISTINCT_COUNTS",
+            MeterKind::HeavyHitter => "HEAVY_HITTERS",
+            MeterKind::CompoundCounter => "COMPOUND_COUNTERS",
         }
     }
 }
 
+/// The prefix of the ELF section holding the layout of a BPF map, followed by the name of the map.
+///
+/// Sections starting with `.rodata` are loaded as single entry arrays named by the section, see [`Counter::LAYOUT`].
+pub const LAYOUT_SECTION_PREFIX: &str = ".rodata.layout.";
+
+/// Hash the layout of a map holding meters of a kind, given the index and name of each meter in order of index.
+///
+/// This is a 64 bit FNV-1a hash, so it can be computed in a const context on both sides of the eBPF program. It is
+/// never zero, as zero means that the layout is unknown.
+pub const fn layout_hash(kind: MeterKind, meters: &[(u32, &str)]) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+
+    const fn write(mut hash: u64, bytes: &[u8]) -> u64 {
+        let mut i = 0;
+        while i < bytes.len() {
+            hash ^= bytes[i] as u64;
+            hash = hash.wrapping_mul(PRIME);
+            i += 1;
+        }
+        // Separate each field, so that moving bytes between adjacent names changes the hash
+        hash ^= 0xff;
+        hash.wrapping_mul(PRIME)
+    }
+
+    let mut hash = write(OFFSET_BASIS, kind.map_name().as_bytes());
+    let mut i = 0;
+    while i < meters.len() {
+        let (index, name) = meters[i];
+        hash = write(hash, &index.to_le_bytes());
+        hash = write(hash, name.as_bytes());
+        i += 1;
+    }
+
+    if hash == 0 {
+        1
+    } else {
+        hash
+    }
+}
+
+/// How the counters of an enumeration are held in BPF, see [`Counter::STORAGE`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CounterStorage {
+    /// A per CPU array, declared with `counter_map!` from aya-metrics-ebpf or the default map used by `counter`.
+    ///
+    /// User space reads the counters with a syscall each period.
+    PerCpuArray,
+    /// An array created with `BPF_F_MMAPABLE`, declared with `mmap_counter_map!` from aya-metrics-ebpf.
+    ///
+    /// Each CPU increments its own stride of the array, see [`mmap_counters_stride`], and user space reads the
+    /// counters from memory without a syscall. The map is declared with room for [`BPF_MMAP_COUNTERS_MAX_CPUS`], so it
+    /// must be resized with `EbpfLoader::set_max_entries` on hosts with more possible CPUs.
+    Mmap,
+}
+
+/// The number of entries of each CPU in a BPF array of counters mapped into user space, see [`CounterStorage::Mmap`].
+///
+/// This is [`Counter::MAX_ENTRIES`] rounded up to a whole cache line, so no two CPUs write to the same line.
+pub const fn mmap_counters_stride(max_entries: u32) -> u32 {
+    const COUNTERS_PER_CACHE_LINE: u32 = 64 / core::mem::size_of::<u64>() as u32;
+    max_entries.div_ceil(COUNTERS_PER_CACHE_LINE) * COUNTERS_PER_CACHE_LINE
+}
+
+/// Type level markers for each [`MeterKind`].
+///
+/// Each kind trait, such as [`Counter`] or [`Gauge`], provides a blanket implementation of [`Meter`] for its own
+/// marker. This keeps the implementations from overlapping while the kind is still known at compile time.
+pub mod kind {
+    /// Marker for [`Counter`](crate::Counter) meters.
+    #[derive(Debug)]
+    pub enum Counter {}
+
+    /// Marker for [`Gauge`](crate::Gauge) meters.
+    #[derive(Debug)]
+    pub enum Gauge {}
+
+    /// Marker for [`Histogram`](crate::Histogram) meters.
+    #[derive(Debug)]
+    pub enum Histogram {}
+
+    /// Marker for [`UpDownCounter`](crate::UpDownCounter) meters.
+    #[derive(Debug)]
+    pub enum UpDownCounter {}
+
+    /// Marker for [`KeyedCounter`](crate::KeyedCounter) meters.
+    #[derive(Debug)]
+    pub enum KeyedCounter {}
+
+    /// Marker for [`Max`](crate::Max) meters.
+    #[derive(Debug)]
+    pub enum Max {}
+
+    /// Marker for [`Min`](crate::Min) meters.
+    #[derive(Debug)]
+    pub enum Min {}
+
+    /// Marker for [`DistinctCount`](crate::DistinctCount) meters.
+    #[derive(Debug)]
+    pub enum DistinctCount {}
+
+    /// Marker for [`HeavyHitter`](crate::HeavyHitter) meters.
+    #[derive(Debug)]
+    pub enum HeavyHitter {}
+
+    /// Marker for [`CompoundCounter`](crate::CompoundCounter) meters.
+    #[derive(Debug)]
+    pub enum CompoundCounter {}
+}
+
 /// Seal traits with a supertrait.
 /// A public supertrait whose name is not publicly exported.
 mod private {
-    pub trait Sealed {}
+    pub trait Sealed<K> {}
 }
 
 /// A base trait for all kinds of meters.
 ///
 /// It is expected to be used on an enum representing a set of meters of the same kind in the same BPF map.
-pub trait Meter: Copy + private::Sealed {
+/// The type parameter is a marker from the [`kind`] module and defaults to [`kind::Counter`].
+pub trait Meter<K = kind::Counter>: Copy + private::Sealed<K> {
     /// The kind of meter.
     fn kind() -> MeterKind;
 
@@ -45,60 +298,448 @@ pub trait Meter: Copy + private::Sealed {
 
     /// The name of the meter.
     #[cfg(any(test, feature = "user"))]
-    fn name(self) -> String;
+    fn name(self) -> SharedString;
 
     /// The description of the meter.
     #[cfg(any(test, feature = "user"))]
     fn description(self) -> String;
+
+    /// The unit of the meter, if it declares one.
+    #[cfg(any(test, feature = "user"))]
+    fn unit(self) -> Option<metrics::Unit> {
+        None
+    }
+
+    /// The dimensions with which the meter is emitted unless others are given.
+    #[cfg(any(test, feature = "user"))]
+    fn default_dimensions(self) -> Vec<Dimension> {
+        vec![Dimension::By(vec![])]
+    }
+}
+
+/// Implements [`Meter`] for all implementations of a kind trait, such as [`Counter`].
+///
+/// Kinds declaring more of the optional items of [`Meter`] forward them in a block.
+macro_rules! impl_meter {
+    ($kind:ident) => {
+        impl_meter!($kind, {});
+    };
+    ($kind:ident, { $($items:tt)* }) => {
+        impl<T: $kind> private::Sealed<kind::$kind> for T {}
+
+        #[doc = concat!("Blanket implementation for all [`", stringify!($kind), "`]s.")]
+        impl<T: $kind> Meter<kind::$kind> for T {
+            fn kind() -> MeterKind {
+                MeterKind::$kind
+            }
+
+            fn index(&self) -> u32 {
+                $kind::index(self)
+            }
+
+            #[cfg(any(test, feature = "user"))]
+            fn name(self) -> SharedString {
+                $kind::name(self)
+            }
+
+            #[cfg(any(test, feature = "user"))]
+            fn description(self) -> String {
+                $kind::description(self)
+            }
+
+            $($items)*
+        }
+    };
 }
 
 /// A trait which should be implemented over an enumeration defining counters in the same BPF map.
 ///
 /// Counters monitor monotonically increasing values and never reset to a lesser value.
-/// Each enumeration should represents an index into a BPF map.
-pub trait Counter: Meter {
+/// Each variant is the index of a counter in the map named by [`Counter::MAP_NAME`], `COUNTERS` by default.
+pub trait Counter: Copy {
+    /// The number of entries of the BPF map, which must be larger than the index of every counter.
+    ///
+    /// This is usually derived from the enumeration, such as `MyCounter::Last as u32 + 1`. The map is declared with
+    /// [`BPF_COUNTERS_MAX_ENTRIES`] entries, so it must be resized to this when the eBPF program is loaded with
+    /// `EbpfLoader::set_max_entries`. Implementing this is optional and by default will return
+    /// [`BPF_COUNTERS_MAX_ENTRIES`].
+    const MAX_ENTRIES: u32 = BPF_COUNTERS_MAX_ENTRIES as u32;
+
+    /// The name of the BPF map holding the counters.
+    ///
+    /// Enumerations sharing a map must not use the same indices, so independent enumerations, such as those of
+    /// different libraries linked into the same eBPF object, should each have a map of their own. The map is declared
+    /// in BPF with `counter_map!` from aya-metrics-ebpf. Implementing this is optional and by default will return the
+    /// name of the map used by `counter` in BPF, see [`MeterKind::map_name`].
+    const MAP_NAME: &'static str = MeterKind::Counter.map_name();
+
+    /// A hash of the name and index of every counter, see [`layout_hash`].
+    ///
+    /// The eBPF program embeds this in the [`LAYOUT_SECTION_PREFIX`] section of its map with `counter_map!` or
+    /// `counter_layout!` from aya-metrics-ebpf, and user space refuses to report counters from a program built with
+    /// another layout. This is computed by `#[derive(Counter)]`. Implementing this is optional and by default will
+    /// return zero, which skips the check.
+    const LAYOUT: u64 = 0;
+
+    /// How the counters are held in BPF, see [`CounterStorage`].
+    ///
+    /// Counters stored with [`CounterStorage::Mmap`] must be declared in BPF with `mmap_counter_map!` from
+    /// aya-metrics-ebpf, so they also need a [`Counter::MAP_NAME`] of their own. Implementing this is optional and by
+    /// default will return [`CounterStorage::PerCpuArray`].
+    const STORAGE: CounterStorage = CounterStorage::PerCpuArray;
+
     /// The index of the counter in a BPF map.
     fn index(&self) -> u32;
 
     /// The name of the counter.
+    ///
+    /// Names are usually static, such as `"packets".into()`, so they are not allocated each time they are used. Names
+    /// built at runtime are converted from a `String` in the same way.
     #[cfg(any(test, feature = "user"))]
-    fn name(self) -> String;
+    fn name(self) -> SharedString;
 
-    /// The description of the meter.
+    /// The description of the counter, which is empty unless implemented.
+    #[cfg(any(test, feature = "user"))]
+    fn description(self) -> String {
+        String::new()
+    }
+
+    /// The unit of the counter, which is used unless another is given in user space.
+    ///
+    /// Implementing this is optional and by default will return `None`.
+    #[cfg(any(test, feature = "user"))]
+    fn unit(self) -> Option<metrics::Unit> {
+        None
+    }
+
+    /// The dimensions with which the counter is emitted unless others are given in user space.
     ///
-    /// Implementing this is optional and by default will return an empty string.
+    /// Implementing this is optional and by default will return a single dimension without labels.
+    #[cfg(any(test, feature = "user"))]
+    fn default_dimensions(self) -> Vec<Dimension> {
+        vec![Dimension::By(vec![])]
+    }
+}
+
+impl_meter!(Counter, {
+    #[cfg(any(test, feature = "user"))]
+    fn unit(self) -> Option<metrics::Unit> {
+        Counter::unit(self)
+    }
+
+    #[cfg(any(test, feature = "user"))]
+    fn default_dimensions(self) -> Vec<Dimension> {
+        Counter::default_dimensions(self)
+    }
+});
+
+/// A trait which should be implemented over an enumeration defining gauges in the same BPF map.
+///
+/// Gauges monitor values which can go up and down. Each CPU holds its own value, so reporting a gauge requires a
+/// policy to merge the values across CPUs.
+/// Each variant is the index of a gauge in the `GAUGES` map.
+pub trait Gauge: Copy {
+    /// The index of the gauge in a BPF map.
+    fn index(&self) -> u32;
+
+    /// The name of the gauge.
+    #[cfg(any(test, feature = "user"))]
+    fn name(self) -> SharedString;
+
+    /// The description of the gauge, which is empty unless implemented.
     #[cfg(any(test, feature = "user"))]
     fn description(self) -> String {
         String::new()
     }
 }
 
-impl<T: Counter> private::Sealed for T {}
+impl_meter!(Gauge);
+
+/// A trait which should be implemented over an enumeration defining histograms in the same BPF map.
+///
+/// Histograms monitor the distribution of values. Each value is counted in a bucket, see [`Buckets`].
+/// Each variant is the index of a histogram in the `HISTOGRAMS` map, which also holds the durations of timers.
+pub trait Histogram: Copy {
+    /// The index of the histogram in a BPF map.
+    fn index(&self) -> u32;
 
-/// Blanket implementation for all [`Counter`]s.
-impl<T: Counter> Meter for T {
-    fn kind() -> MeterKind {
-        MeterKind::Counter
+    /// The buckets values are counted in.
+    ///
+    /// This is used both in BPF, to count values, and in user space, to label the buckets. Implementing this is
+    /// optional and by default will return [`Buckets::Log2`].
+    fn buckets(&self) -> Buckets {
+        Buckets::Log2
     }
 
-    fn index(&self) -> u32 {
-        Counter::index(self)
+    /// The name of the histogram.
+    #[cfg(any(test, feature = "user"))]
+    fn name(self) -> SharedString;
+
+    /// The description of the histogram, which is empty unless implemented.
+    #[cfg(any(test, feature = "user"))]
+    fn description(self) -> String {
+        String::new()
     }
+}
+
+impl_meter!(Histogram);
+
+/// A trait which should be implemented over an enumeration defining up down counters in the same BPF map.
+///
+/// Up down counters monitor values which are incremented and decremented, possibly on different CPUs. Each CPU holds
+/// a signed value and the value of the counter is the sum across all CPUs.
+/// Each variant is the index of an up down counter in the `UP_DOWN_COUNTERS` map.
+pub trait UpDownCounter: Copy {
+    /// The index of the up down counter in a BPF map.
+    fn index(&self) -> u32;
+
+    /// The name of the up down counter.
+    #[cfg(any(test, feature = "user"))]
+    fn name(self) -> SharedString;
 
+    /// The description of the up down counter, which is empty unless implemented.
     #[cfg(any(test, feature = "user"))]
-    fn name(self) -> String {
-        Counter::name(self)
+    fn description(self) -> String {
+        String::new()
     }
+}
+
+impl_meter!(UpDownCounter);
 
+/// A trait which should be implemented over an enumeration defining keyed counters in the same BPF map.
+///
+/// Keyed counters monitor monotonically increasing values, like [`Counter`]s, but hold a separate series for each key
+/// seen in BPF. The key is copied into a [`MeterKey`] alongside the index of the keyed counter.
+/// Each variant is the index held by the keys of its series in the `KEYED_COUNTERS` map.
+pub trait KeyedCounter: Copy {
+    /// The index of the keyed counter in a BPF map.
+    fn index(&self) -> u32;
+
+    /// The name of the keyed counter.
+    #[cfg(any(test, feature = "user"))]
+    fn name(self) -> SharedString;
+
+    /// The description of the keyed counter, which is empty unless implemented.
+    #[cfg(any(test, feature = "user"))]
+    fn description(self) -> String {
+        String::new()
+    }
+}
+
+impl_meter!(KeyedCounter);
+
+/// A trait which should be implemented over an enumeration defining max meters in the same BPF map.
+///
+/// Max meters monitor the largest value recorded in each period, such as the peak burst of packets. Each CPU holds the
+/// largest value it has seen, which user space reads and resets each period.
+/// Each variant is the index of a max meter in the `MAXES` map.
+pub trait Max: Copy {
+    /// The index of the max meter in a BPF map.
+    fn index(&self) -> u32;
+
+    /// The name of the max meter.
+    #[cfg(any(test, feature = "user"))]
+    fn name(self) -> SharedString;
+
+    /// The description of the max meter, which is empty unless implemented.
+    #[cfg(any(test, feature = "user"))]
+    fn description(self) -> String {
+        String::new()
+    }
+}
+
+impl_meter!(Max);
+
+/// A trait which should be implemented over an enumeration defining min meters in the same BPF map.
+///
+/// Min meters monitor the smallest value recorded in each period. Each CPU holds the smallest value it has seen, which
+/// user space reads and resets each period.
+/// Each variant is the index of a min meter in the `MINS` map.
+pub trait Min: Copy {
+    /// The index of the min meter in a BPF map.
+    fn index(&self) -> u32;
+
+    /// The name of the min meter.
+    #[cfg(any(test, feature = "user"))]
+    fn name(self) -> SharedString;
+
+    /// The description of the min meter, which is empty unless implemented.
     #[cfg(any(test, feature = "user"))]
     fn description(self) -> String {
-        Counter::description(self)
+        String::new()
+    }
+}
+
+impl_meter!(Min);
+
+/// A trait which should be implemented over an enumeration defining distinct counts in the same BPF map.
+///
+/// Distinct counts monitor the approximate number of distinct values recorded in each period. Each CPU holds the
+/// registers of a HyperLogLog, see [`HyperLogLogValue`], which user space merges, estimates and resets each period.
+/// Each variant is the index of a distinct count in the `DISTINCT_COUNTS` map.
+pub trait DistinctCount: Copy {
+    /// The index of the distinct count in a BPF map.
+    fn index(&self) -> u32;
+
+    /// The name of the distinct count.
+    #[cfg(any(test, feature = "user"))]
+    fn name(self) -> SharedString;
+
+    /// The description of the distinct count, which is empty unless implemented.
+    #[cfg(any(test, feature = "user"))]
+    fn description(self) -> String {
+        String::new()
+    }
+}
+
+impl_meter!(DistinctCount);
+
+/// A trait which should be implemented over an enumeration defining heavy hitters in the same BPF map.
+///
+/// Heavy hitters monitor the keys with the largest counts in each period without holding a counter for every key. Each
+/// CPU counts keys in a [`CountMinSketch`], and keys whose estimated count reaches [`HeavyHitter::threshold`] are
+/// inserted into a table of candidates, from which user space reports the top keys and resets each period.
+/// Each variant is the index of a sketch in the `HEAVY_HITTERS` map, which is also held by the keys of its candidates.
+pub trait HeavyHitter: Copy {
+    /// The index of the heavy hitter in a BPF map.
+    fn index(&self) -> u32;
+
+    /// The estimated count a key must reach on a CPU within a period to become a candidate.
+    ///
+    /// This keeps rare keys from filling the table of candidates. Implementing this is optional and by default every
+    /// key is a candidate.
+    fn threshold(&self) -> u64 {
+        1
+    }
+
+    /// The name of the heavy hitter.
+    #[cfg(any(test, feature = "user"))]
+    fn name(self) -> SharedString;
+
+    /// The description of the heavy hitter, which is empty unless implemented.
+    #[cfg(any(test, feature = "user"))]
+    fn description(self) -> String {
+        String::new()
+    }
+}
+
+impl_meter!(HeavyHitter);
+
+/// A trait which should be implemented over an enumeration defining compound counters in the same BPF map.
+///
+/// Each compound counter is a slot holding several counters, its fields, which are incremented together with a single
+/// lookup of the BPF map, such as the packets and bytes seen by a program. Every slot of an enumeration has the same
+/// fields, and each field is reported as a counter of its own.
+/// Each variant is the index of a slot in the `COMPOUND_COUNTERS` map.
+pub trait CompoundCounter: Copy {
+    /// The names of the fields of each slot, in the order they are held in a [`CompoundCounterValue`].
+    ///
+    /// Each field is reported as a counter named by the slot followed by the field, such as `xdp_packets` for the
+    /// `packets` field of an `xdp` slot. There may be at most [`BPF_COMPOUND_COUNTER_FIELDS`] fields.
+    const FIELDS: &'static [&'static str];
+
+    /// The index of the compound counter in a BPF map.
+    fn index(&self) -> u32;
+
+    /// The name of the compound counter, which prefixes the name of each of its fields.
+    #[cfg(any(test, feature = "user"))]
+    fn name(self) -> SharedString;
+
+    /// The description of the compound counter, which is empty unless implemented.
+    #[cfg(any(test, feature = "user"))]
+    fn description(self) -> String {
+        String::new()
+    }
+
+    /// The unit of a field, by its index in [`CompoundCounter::FIELDS`], such as bytes for a `bytes` field.
+    ///
+    /// Fields without a unit of their own, which is all of them unless implemented, are reported with the unit of the
+    /// metric.
+    #[cfg(any(test, feature = "user"))]
+    fn field_unit(self, _field: usize) -> Option<metrics::Unit> {
+        None
     }
 }
 
+impl_meter!(CompoundCounter);
+
+/// Fails to compile when a compound counter has more fields than a [`CompoundCounterValue`] holds.
+///
+/// Checked by both user space and BPF with `let () = CompoundCounterFields::<T>::FIT;`.
+#[doc(hidden)]
+pub struct CompoundCounterFields<T>(core::marker::PhantomData<T>);
+
+impl<T: CompoundCounter> CompoundCounterFields<T> {
+    /// Fails to compile when `T` has more than [`BPF_COMPOUND_COUNTER_FIELDS`] fields.
+    pub const FIT: () = assert!(
+        T::FIELDS.len() <= BPF_COMPOUND_COUNTER_FIELDS,
+        "compound counters must not have more fields than BPF_COMPOUND_COUNTER_FIELDS"
+    );
+}
+
+/// The value of a [`Gauge`] held by a single CPU in a BPF per CPU array.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct GaugeValue {
+    /// The last value written to the gauge, which may be negative.
+    pub value: i64,
+    /// The time the value was last written in nanoseconds since boot, or zero if it was never written.
+    pub timestamp: u64,
+}
+
+// SAFETY: GaugeValue is `repr(C)` and only contains 8 byte fields, so it has no padding.
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for GaugeValue {}
+
+/// The value of a [`Max`] or [`Min`] meter held by a single CPU in a BPF per CPU array.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct WatermarkValue {
+    /// The largest or smallest value recorded since the value was last reset.
+    pub value: u64,
+    /// The number of values recorded since the value was last reset, the value is unset when this is zero.
+    pub count: u64,
+}
+
+impl WatermarkValue {
+    /// Record a value, keeping it if it is larger than the current value or the value is unset.
+    #[inline(always)]
+    pub fn record_max(&mut self, value: u64) {
+        if self.count == 0 || value > self.value {
+            self.value = value;
+        }
+        self.count += 1;
+    }
+
+    /// Record a value, keeping it if it is smaller than the current value or the value is unset.
+    #[inline(always)]
+    pub fn record_min(&mut self, value: u64) {
+        if self.count == 0 || value < self.value {
+            self.value = value;
+        }
+        self.count += 1;
+    }
+}
+
+// SAFETY: WatermarkValue is `repr(C)` and only contains `u64` fields, so it has no padding.
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for WatermarkValue {}
+
+/// The fields of a [`CompoundCounter`] held by a single CPU in a BPF per CPU array.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct CompoundCounterValue {
+    /// The value of each field, in the order of [`CompoundCounter::FIELDS`]. Fields beyond those are unused.
+    pub fields: [u64; BPF_COMPOUND_COUNTER_FIELDS],
+}
+
+// SAFETY: CompoundCounterValue is `repr(C)` and only contains an array of `u64`, so it has no padding.
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for CompoundCounterValue {}
+
 #[cfg(test)]
 mod tests {
-    use super::{Meter, MeterKind};
+    use super::{kind, Meter, MeterKind, SharedString};
 
     // Create a test enum implementing Counter
     #[derive(Debug, Copy, Clone)]
@@ -108,10 +749,10 @@ mod tests {
     }
 
     impl super::Counter for MockCounter {
-        fn name(self) -> String {
+        fn name(self) -> SharedString {
             match self {
-                MockCounter::First => "first_counter".to_string(),
-                MockCounter::Second => "second_counter".to_string(),
+                MockCounter::First => "first_counter".into(),
+                MockCounter::Second => "second_counter".into(),
             }
         }
 
@@ -129,12 +770,15 @@ mod tests {
         let second = MockCounter::Second;
 
         // Test names
-        assert_eq!(first.name(), "first_counter");
-        assert_eq!(second.name(), "second_counter");
+        assert_eq!(&*first.name(), "first_counter");
+        assert_eq!(&*second.name(), "second_counter");
 
         // Test index values
         assert_eq!(first.index(), 0);
         assert_eq!(second.index(), 1);
+
+        // Test the default map
+        assert_eq!(<MockCounter as super::Counter>::MAP_NAME, "COUNTERS");
     }
 
     #[test]
@@ -148,11 +792,133 @@ mod tests {
         assert_eq!(<MockCounter as Meter>::index(&counter), 0);
 
         // Test that name matches through Meter trait
-        assert_eq!(<MockCounter as Meter>::name(counter), "first_counter");
+        assert_eq!(&*<MockCounter as Meter>::name(counter), "first_counter");
+    }
+
+    #[derive(Debug, Copy, Clone)]
+    enum MockBytesCounter {
+        Bytes,
+    }
+
+    impl super::Counter for MockBytesCounter {
+        fn name(self) -> SharedString {
+            // Names built at runtime are owned
+            String::from("bytes").into()
+        }
+
+        fn index(&self) -> u32 {
+            0
+        }
+
+        fn unit(self) -> Option<metrics::Unit> {
+            Some(metrics::Unit::Bytes)
+        }
+
+        fn default_dimensions(self) -> Vec<super::Dimension> {
+            vec![super::Dimension::ByCpu(vec![])]
+        }
+    }
+
+    #[test]
+    fn test_meter_unit_and_default_dimensions() {
+        // Counters forward their declarations
+        assert_eq!(<MockBytesCounter as Meter>::unit(MockBytesCounter::Bytes), Some(metrics::Unit::Bytes));
+        assert_eq!(
+            <MockBytesCounter as Meter>::default_dimensions(MockBytesCounter::Bytes),
+            vec![super::Dimension::ByCpu(vec![])]
+        );
+
+        // Other meters use the defaults
+        assert_eq!(<MockCounter as Meter>::unit(MockCounter::First), None);
+        assert_eq!(<MockGauge as Meter<kind::Gauge>>::unit(MockGauge::QueueDepth), None);
+        assert_eq!(
+            <MockGauge as Meter<kind::Gauge>>::default_dimensions(MockGauge::QueueDepth),
+            vec![super::Dimension::By(vec![])]
+        );
+    }
+
+    // Create a test enum implementing Gauge
+    #[derive(Debug, Copy, Clone)]
+    enum MockGauge {
+        QueueDepth,
+    }
+
+    impl super::Gauge for MockGauge {
+        fn name(self) -> SharedString {
+            match self {
+                MockGauge::QueueDepth => "queue_depth".into(),
+            }
+        }
+
+        fn index(&self) -> u32 {
+            match self {
+                MockGauge::QueueDepth => 3,
+            }
+        }
+    }
+
+    #[test]
+    fn test_gauge_meter_trait() {
+        let gauge = MockGauge::QueueDepth;
+
+        // Test that kind returns Gauge
+        assert!(matches!(MockGauge::kind(), MeterKind::Gauge));
+
+        // Test that index and name match through Meter trait
+        assert_eq!(<MockGauge as Meter<kind::Gauge>>::index(&gauge), 3);
+        assert_eq!(&*<MockGauge as Meter<kind::Gauge>>::name(gauge), "queue_depth");
+        assert_eq!(<MockGauge as Meter<kind::Gauge>>::description(gauge), "");
     }
 
     #[test]
     fn test_meter_kind() {
         assert_eq!(MeterKind::Counter.map_name(), "COUNTERS");
+        assert_eq!(MeterKind::Gauge.map_name(), "GAUGES");
+        assert_eq!(MeterKind::Histogram.map_name(), "HISTOGRAMS");
+        assert_eq!(MeterKind::UpDownCounter.map_name(), "UP_DOWN_COUNTERS");
+        assert_eq!(MeterKind::KeyedCounter.map_name(), "KEYED_COUNTERS");
+        assert_eq!(MeterKind::Max.map_name(), "MAXES");
+        assert_eq!(MeterKind::Min.map_name(), "MINS");
+        assert_eq!(MeterKind::DistinctCount.map_name(), "DISTINCT_COUNTS");
+        assert_eq!(MeterKind::HeavyHitter.map_name(), "HEAVY_HITTERS");
+        assert_eq!(MeterKind::CompoundCounter.map_name(), "COMPOUND_COUNTERS");
+    }
+
+    #[test]
+    fn test_layout_hash() {
+        use super::layout_hash;
+
+        let layout = layout_hash(MeterKind::Counter, &[(0, "packets"), (1, "bytes")]);
+        assert_ne!(layout, 0);
+        assert_eq!(layout, layout_hash(MeterKind::Counter, &[(0, "packets"), (1, "bytes")]));
+
+        // Any change of kind, index or name changes the layout
+        assert_ne!(layout, layout_hash(MeterKind::Gauge, &[(0, "packets"), (1, "bytes")]));
+        assert_ne!(layout, layout_hash(MeterKind::Counter, &[(1, "packets"), (0, "bytes")]));
+        assert_ne!(layout, layout_hash(MeterKind::Counter, &[(0, "packet"), (1, "sbytes")]));
+        assert_ne!(layout, layout_hash(MeterKind::Counter, &[(0, "packets")]));
+        assert_ne!(layout_hash(MeterKind::Counter, &[]), 0);
+    }
+
+    #[test]
+    fn test_mmap_counters_stride() {
+        use super::mmap_counters_stride;
+
+        assert_eq!(mmap_counters_stride(0), 0);
+        assert_eq!(mmap_counters_stride(1), 8);
+        assert_eq!(mmap_counters_stride(8), 8);
+        assert_eq!(mmap_counters_stride(9), 16);
+    }
+
+    #[test]
+    fn test_watermark_value() {
+        let mut max = super::WatermarkValue::default();
+        let mut min = super::WatermarkValue::default();
+        for value in [5, 9, 2] {
+            max.record_max(value);
+            min.record_min(value);
+        }
+        assert_eq!(max, super::WatermarkValue { value: 9, count: 3 });
+        assert_eq!(min, super::WatermarkValue { value: 2, count: 3 });
     }
 }
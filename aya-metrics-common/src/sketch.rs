@@ -0,0 +1,98 @@
+//! Count-min sketches used by [`HeavyHitter`](crate::HeavyHitter) meters.
+
+/// The number of rows of a [`CountMinSketch`], each counting values in a column chosen by a different hash.
+pub const BPF_SKETCH_DEPTH: usize = 4;
+
+/// The number of columns of each row of a [`CountMinSketch`], which must be a power of two.
+///
+/// The count of a key is overestimated by at most `e / BPF_SKETCH_WIDTH` of the total count, or about 1%, with a
+/// probability of `1 - e^-BPF_SKETCH_DEPTH`, or about 98%.
+pub const BPF_SKETCH_WIDTH: usize = 256;
+
+/// A count-min sketch of a [`HeavyHitter`](crate::HeavyHitter) held by a single CPU in a BPF per CPU array.
+///
+/// Each key is counted in one column of each row. Columns are shared by colliding keys, so the smallest count of the
+/// columns of a key is an estimate which is never less than its count.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct CountMinSketch {
+    /// The counts of each column of each row.
+    pub counts: [[u64; BPF_SKETCH_WIDTH]; BPF_SKETCH_DEPTH],
+}
+
+impl CountMinSketch {
+    /// A sketch with no recorded values.
+    pub const EMPTY: CountMinSketch = CountMinSketch {
+        counts: [[0u64; BPF_SKETCH_WIDTH]; BPF_SKETCH_DEPTH],
+    };
+
+    /// Record a value for the hash of a key, see [`hll_hash`](crate::hll_hash), returning the estimated count of the
+    /// key.
+    #[inline(always)]
+    pub fn record(&mut self, hash: u64, value: u64) -> u64 {
+        let mut estimate = u64::MAX;
+        for (row, counts) in self.counts.iter_mut().enumerate() {
+            // The column is always in bounds but the check keeps the verifier happy.
+            if let Some(count) = counts.get_mut(sketch_column(hash, row)) {
+                *count = count.saturating_add(value);
+                estimate = estimate.min(*count);
+            }
+        }
+        estimate
+    }
+}
+
+impl Default for CountMinSketch {
+    fn default() -> Self {
+        CountMinSketch::EMPTY
+    }
+}
+
+// SAFETY: CountMinSketch is `repr(C)` and only contains `u64` fields, so it has no padding.
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for CountMinSketch {}
+
+/// The column of a row a hash is counted in.
+///
+/// The hash of each row is derived from the two halves of the hash of the key, so a key is only hashed once.
+#[inline(always)]
+pub fn sketch_column(hash: u64, row: usize) -> usize {
+    let (low, high) = (hash as u32, (hash >> 32) as u32);
+    (low.wrapping_add((row as u32).wrapping_mul(high)) as usize) & (BPF_SKETCH_WIDTH - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hll_hash;
+
+    #[test]
+    fn test_record() {
+        let mut sketch = CountMinSketch::EMPTY;
+        assert_eq!(sketch.record(hll_hash(&1u32), 5), 5);
+        assert_eq!(sketch.record(hll_hash(&1u32), 2), 7);
+        assert_eq!(sketch.record(hll_hash(&2u32), 1), 1);
+
+        // Each row holds the count of every key
+        for counts in sketch.counts {
+            assert_eq!(counts.iter().sum::<u64>(), 8);
+        }
+    }
+
+    #[test]
+    fn test_record_never_underestimates() {
+        let mut sketch = CountMinSketch::EMPTY;
+        for key in 0..10_000u32 {
+            sketch.record(hll_hash(&key), 1);
+        }
+        // A heavy hitter stands out from the keys colliding with it
+        let estimate = sketch.record(hll_hash(&42u32), 999);
+        assert!((1_000..1_000 + 10_000 * 3 / BPF_SKETCH_WIDTH as u64).contains(&estimate));
+    }
+
+    #[test]
+    fn test_sketch_column() {
+        assert!((0..BPF_SKETCH_DEPTH).all(|row| sketch_column(u64::MAX, row) < BPF_SKETCH_WIDTH));
+        assert_ne!(sketch_column(hll_hash(&1u32), 0), sketch_column(hll_hash(&1u32), 1));
+    }
+}
@@ -0,0 +1,450 @@
+#![deny(missing_docs)]
+#![deny(clippy::unwrap_used)]
+
+//! Provides derive macros implementing the meter traits of `aya-metrics-common`.
+//!
+//! The macros are re-exported by `aya-metrics-common` with the `derive` feature. The generated code refers to
+//! `::aya_metrics_common`, so it must be a dependency of the crate deriving the traits. It only uses `core` in BPF, as
+//! the user space only items are expanded when the `user` feature of `aya-metrics-common` is enabled.
+
+use std::collections::HashMap;
+
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::{format_ident, quote};
+use syn::{parse_macro_input, spanned::Spanned, Attribute, Data, DeriveInput, Error, Fields, Ident, LitInt, LitStr};
+
+/// Derive `aya_metrics_common::Counter` for an enumeration of counters.
+///
+/// Each variant is a counter with an index given by its position in the enumeration, starting from zero, so indices
+/// are always dense and unique. The enumeration may be annotated with:
+///
+/// * `#[counter(map_name = "...")]` - The name of the BPF map holding the counters, see `Counter::MAP_NAME`.
+/// * `#[counter(max_entries = ...)]` - The number of entries of the BPF map, see `Counter::MAX_ENTRIES`.
+/// * `#[counter(mmap)]` - Hold the counters in a BPF array mapped into user space, see `CounterStorage::Mmap`.
+///
+/// Each variant may be annotated with:
+///
+/// * `#[counter(name = "...")]` - The name of the counter, which defaults to the variant in snake case.
+/// * `#[counter(description = "...")]` - The description of the counter, which defaults to an empty string.
+/// * `#[counter(unit = "...")]` - The unit of the counter as a label of `metrics::Unit`, such as `"bytes"`.
+/// * `#[counter(index = ...)]` - The index of the counter. Following variants continue from this index.
+///
+/// ```ignore
+/// #[derive(Copy, Clone, aya_metrics_common::Counter)]
+/// #[counter(map_name = "MY_COUNTERS")]
+/// pub enum MyCounter {
+///     #[counter(name = "packets_counter", description = "Packets received")]
+///     Packets,
+///     #[counter(unit = "bytes")]
+///     Bytes,
+/// }
+/// ```
+///
+/// The layout of the enumeration is hashed into `Counter::LAYOUT`, so user space can detect an eBPF program built
+/// with other names or indices.
+///
+/// Fails to compile if two counters have the same name or index, or if an index does not fit in the BPF map.
+#[proc_macro_derive(Counter, attributes(counter))]
+pub fn derive_counter(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand_counter(input).unwrap_or_else(Error::into_compile_error).into()
+}
+
+/// The `#[counter(...)]` attributes of an enumeration.
+#[derive(Default)]
+struct EnumAttrs {
+    map_name: Option<LitStr>,
+    max_entries: Option<LitInt>,
+    mmap: Option<Span>,
+}
+
+impl EnumAttrs {
+    fn parse(attrs: &[Attribute]) -> syn::Result<Self> {
+        let mut parsed = EnumAttrs::default();
+        for attr in attrs.iter().filter(|attr| attr.path().is_ident("counter")) {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("map_name") {
+                    parsed.map_name = Some(meta.value()?.parse()?);
+                } else if meta.path.is_ident("max_entries") {
+                    parsed.max_entries = Some(meta.value()?.parse()?);
+                } else if meta.path.is_ident("mmap") {
+                    parsed.mmap = Some(meta.path.span());
+                } else {
+                    return Err(meta.error("expected `map_name`, `max_entries` or `mmap`"));
+                }
+                Ok(())
+            })?;
+        }
+        Ok(parsed)
+    }
+}
+
+/// The `#[counter(...)]` attributes of a variant.
+#[derive(Default)]
+struct VariantAttrs {
+    name: Option<LitStr>,
+    description: Option<LitStr>,
+    unit: Option<LitStr>,
+    index: Option<LitInt>,
+}
+
+impl VariantAttrs {
+    fn parse(attrs: &[Attribute]) -> syn::Result<Self> {
+        let mut parsed = VariantAttrs::default();
+        for attr in attrs.iter().filter(|attr| attr.path().is_ident("counter")) {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("name") {
+                    parsed.name = Some(meta.value()?.parse()?);
+                } else if meta.path.is_ident("description") {
+                    parsed.description = Some(meta.value()?.parse()?);
+                } else if meta.path.is_ident("unit") {
+                    parsed.unit = Some(meta.value()?.parse()?);
+                } else if meta.path.is_ident("index") {
+                    parsed.index = Some(meta.value()?.parse()?);
+                } else {
+                    return Err(meta.error("expected `name`, `description`, `unit` or `index`"));
+                }
+                Ok(())
+            })?;
+        }
+        Ok(parsed)
+    }
+}
+
+/// A counter defined by a variant.
+struct CounterVariant {
+    ident: Ident,
+    index: u32,
+    name: String,
+    description: String,
+    unit: Option<Ident>,
+}
+
+/// The label of each variant of `metrics::Unit`, as parsed by `Unit::from_string`, so units are checked when deriving
+/// without a dependency on the metrics crate.
+const UNITS: &[(&str, &str)] = &[
+    ("count", "Count"),
+    ("percent", "Percent"),
+    ("seconds", "Seconds"),
+    ("milliseconds", "Milliseconds"),
+    ("microseconds", "Microseconds"),
+    ("nanoseconds", "Nanoseconds"),
+    ("tebibytes", "Tebibytes"),
+    ("gibibytes", "Gibibytes"),
+    ("mebibytes", "Mebibytes"),
+    ("kibibytes", "Kibibytes"),
+    ("bytes", "Bytes"),
+    ("terabits_per_second", "TerabitsPerSecond"),
+    ("gigabits_per_second", "GigabitsPerSecond"),
+    ("megabits_per_second", "MegabitsPerSecond"),
+    ("kilobits_per_second", "KilobitsPerSecond"),
+    ("bits_per_second", "BitsPerSecond"),
+    ("count_per_second", "CountPerSecond"),
+];
+
+/// Convert the name of a variant to snake case, such as `PacketsDropped` to `packets_dropped`.
+fn snake_case(ident: &Ident) -> String {
+    let chars: Vec<char> = ident.to_string().chars().collect();
+    let mut name = String::new();
+    for (i, c) in chars.iter().enumerate() {
+        if c.is_uppercase() && i > 0 {
+            let prev = chars[i - 1];
+            let next_is_lower = chars.get(i + 1).is_some_and(|next| next.is_lowercase());
+            // Split before a word, keeping acronyms such as `HTTP` together
+            if prev.is_lowercase() || prev.is_ascii_digit() || (prev.is_uppercase() && next_is_lower) {
+                name.push('_');
+            }
+        }
+        name.extend(c.to_lowercase());
+    }
+    name
+}
+
+/// Validate the variants of an enumeration and assign their indices.
+fn parse_variants(input: &DeriveInput, max_entries: Option<u32>) -> syn::Result<Vec<CounterVariant>> {
+    let Data::Enum(data) = &input.data else {
+        return Err(Error::new_spanned(&input.ident, "`Counter` can only be derived for enumerations"));
+    };
+
+    let mut variants = Vec::new();
+    let mut indices: HashMap<u32, &Ident> = HashMap::new();
+    let mut names: HashMap<String, &Ident> = HashMap::new();
+    let mut next_index = 0;
+
+    for variant in &data.variants {
+        if !matches!(variant.fields, Fields::Unit) {
+            return Err(Error::new_spanned(&variant.fields, "counters must be unit variants"));
+        }
+        let attrs = VariantAttrs::parse(&variant.attrs)?;
+
+        let (index, span) = match &attrs.index {
+            Some(lit) => (lit.base10_parse::<u32>()?, lit.span()),
+            None => (next_index, variant.ident.span()),
+        };
+        if let Some(other) = indices.insert(index, &variant.ident) {
+            return Err(Error::new(span, format!("index {index} is already used by `{other}`")));
+        }
+        if let Some(max_entries) = max_entries.filter(|max_entries| index >= *max_entries) {
+            return Err(Error::new(span, format!("index {index} does not fit in a map of {max_entries} entries")));
+        }
+        next_index = index.saturating_add(1);
+
+        let (name, span) = match &attrs.name {
+            Some(lit) => (lit.value(), lit.span()),
+            None => (snake_case(&variant.ident), variant.ident.span()),
+        };
+        if let Some(other) = names.insert(name.clone(), &variant.ident) {
+            return Err(Error::new(span, format!("name `{name}` is already used by `{other}`")));
+        }
+
+        let unit = match &attrs.unit {
+            Some(lit) => match UNITS.iter().find(|(label, _)| *label == lit.value()) {
+                Some((_, unit)) => Some(format_ident!("{unit}")),
+                None => {
+                    return Err(Error::new(
+                        lit.span(),
+                        "expected a label of `metrics::Unit`, such as \"count\" or \"bytes\"",
+                    ))
+                }
+            },
+            None => None,
+        };
+
+        variants.push(CounterVariant {
+            ident: variant.ident.clone(),
+            index,
+            name,
+            description: attrs.description.map(|lit| lit.value()).unwrap_or_default(),
+            unit,
+        });
+    }
+
+    Ok(variants)
+}
+
+fn expand_counter(input: DeriveInput) -> syn::Result<TokenStream2> {
+    if !input.generics.params.is_empty() {
+        return Err(Error::new_spanned(&input.generics, "`Counter` cannot be derived for generic enumerations"));
+    }
+
+    let attrs = EnumAttrs::parse(&input.attrs)?;
+    // The default map is a per CPU array, declared by aya-metrics-ebpf
+    if let (Some(span), None) = (attrs.mmap, &attrs.map_name) {
+        return Err(Error::new(span, "`mmap` counters need a map of their own, named with `map_name`"));
+    }
+    let max_entries = attrs.max_entries.as_ref().map(LitInt::base10_parse::<u32>).transpose()?;
+    let variants = parse_variants(&input, max_entries)?;
+
+    let ident = &input.ident;
+    let idents: Vec<_> = variants.iter().map(|variant| &variant.ident).collect();
+    let indices = variants.iter().map(|variant| variant.index);
+    let names = variants.iter().map(|variant| &variant.name);
+    let descriptions = variants.iter().map(|variant| &variant.description);
+    let units = variants.iter().map(|variant| match &variant.unit {
+        Some(unit) => quote!(::core::option::Option::Some(::aya_metrics_common::__private::metrics::Unit::#unit)),
+        None => quote!(::core::option::Option::None),
+    });
+
+    let map_name = attrs.map_name.map(|map_name| quote!(const MAP_NAME: &'static str = #map_name;));
+    let max_entries_const = max_entries.map(|max_entries| quote!(const MAX_ENTRIES: u32 = #max_entries;));
+    let storage = attrs.mmap.map(|_| {
+        quote!(
+            const STORAGE: ::aya_metrics_common::CounterStorage = ::aya_metrics_common::CounterStorage::Mmap;
+        )
+    });
+
+    // Hash the counters in order of index, so reordering variants with explicit indices keeps the layout
+    let mut layout: Vec<_> = variants.iter().map(|variant| (variant.index, &variant.name)).collect();
+    layout.sort_unstable_by_key(|(index, _)| *index);
+    let layout = layout.iter().map(|(index, name)| quote!((#index, #name)));
+
+    // The default number of entries is only known once the trait is implemented
+    let fits = match (max_entries, variants.iter().map(|variant| variant.index).max()) {
+        (None, Some(max_index)) => {
+            let message = format!("the indices of `{ident}` do not fit in a map of `Counter::MAX_ENTRIES` entries");
+            quote! {
+                const _: () = ::core::assert!(
+                    #max_index < <#ident as ::aya_metrics_common::Counter>::MAX_ENTRIES,
+                    #message,
+                );
+            }
+        }
+        _ => TokenStream2::new(),
+    };
+
+    Ok(quote! {
+        impl ::aya_metrics_common::Counter for #ident {
+            #max_entries_const
+            #map_name
+            #storage
+            const LAYOUT: u64 = ::aya_metrics_common::layout_hash(
+                ::aya_metrics_common::MeterKind::Counter,
+                &[#(#layout),*],
+            );
+
+            fn index(&self) -> u32 {
+                match *self {
+                    #(#ident::#idents => #indices,)*
+                }
+            }
+
+            ::aya_metrics_common::__user! {
+                fn name(self) -> ::aya_metrics_common::SharedString {
+                    match self {
+                        #(#ident::#idents => ::aya_metrics_common::SharedString::const_str(#names),)*
+                    }
+                }
+
+                fn description(self) -> ::aya_metrics_common::__private::String {
+                    match self {
+                        #(#ident::#idents => ::aya_metrics_common::__private::String::from(#descriptions),)*
+                    }
+                }
+
+                fn unit(self) -> ::core::option::Option<::aya_metrics_common::__private::metrics::Unit> {
+                    match self {
+                        #(#ident::#idents => #units,)*
+                    }
+                }
+            }
+        }
+
+        #fits
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use syn::parse_quote;
+
+    use super::*;
+
+    fn expand_error(input: DeriveInput) -> String {
+        match expand_counter(input) {
+            Ok(_) => panic!("Expected an error"),
+            Err(err) => err.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_snake_case() {
+        assert_eq!(snake_case(&format_ident!("Packets")), "packets");
+        assert_eq!(snake_case(&format_ident!("PacketsDropped")), "packets_dropped");
+        assert_eq!(snake_case(&format_ident!("HTTPRequests")), "http_requests");
+        assert_eq!(snake_case(&format_ident!("Ipv4Packets")), "ipv4_packets");
+    }
+
+    #[test]
+    fn test_parse_variants() -> syn::Result<()> {
+        let input: DeriveInput = parse_quote! {
+            enum MyCounter {
+                Packets,
+                #[counter(name = "bytes_counter", description = "Bytes received", unit = "bytes")]
+                Bytes,
+                #[counter(index = 10)]
+                Errors,
+                Drops,
+            }
+        };
+        let variants = parse_variants(&input, None)?;
+
+        let indices: Vec<_> = variants.iter().map(|variant| variant.index).collect();
+        assert_eq!(indices, [0, 1, 10, 11]);
+        let names: Vec<_> = variants.iter().map(|variant| variant.name.as_str()).collect();
+        assert_eq!(names, ["packets", "bytes_counter", "errors", "drops"]);
+        assert_eq!(variants[1].description, "Bytes received");
+        assert_eq!(variants[1].unit, Some(format_ident!("Bytes")));
+        assert_eq!(variants[0].unit, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_duplicate_name() {
+        let error = expand_error(parse_quote! {
+            enum MyCounter {
+                Packets,
+                #[counter(name = "packets")]
+                OtherPackets,
+            }
+        });
+        assert_eq!(error, "name `packets` is already used by `Packets`");
+    }
+
+    #[test]
+    fn test_duplicate_index() {
+        let error = expand_error(parse_quote! {
+            enum MyCounter {
+                Packets,
+                Bytes,
+                #[counter(index = 1)]
+                Errors,
+            }
+        });
+        assert_eq!(error, "index 1 is already used by `Bytes`");
+    }
+
+    #[test]
+    fn test_index_out_of_bounds() {
+        let error = expand_error(parse_quote! {
+            #[counter(max_entries = 2)]
+            enum MyCounter {
+                Packets,
+                Bytes,
+                Errors,
+            }
+        });
+        assert_eq!(error, "index 2 does not fit in a map of 2 entries");
+    }
+
+    #[test]
+    fn test_mmap_without_map_name() {
+        let error = expand_error(parse_quote! {
+            #[counter(mmap)]
+            enum MyCounter {
+                Packets,
+            }
+        });
+        assert_eq!(error, "`mmap` counters need a map of their own, named with `map_name`");
+
+        let expanded = expand_counter(parse_quote! {
+            #[counter(map_name = "MY_COUNTERS", mmap)]
+            enum MyCounter {
+                Packets,
+            }
+        });
+        assert!(expanded.is_ok_and(|tokens| tokens.to_string().contains("CounterStorage :: Mmap")));
+    }
+
+    #[test]
+    fn test_invalid_input() {
+        let error = expand_error(parse_quote! {
+            struct MyCounter;
+        });
+        assert_eq!(error, "`Counter` can only be derived for enumerations");
+
+        let error = expand_error(parse_quote! {
+            enum MyCounter {
+                Packets(u32),
+            }
+        });
+        assert_eq!(error, "counters must be unit variants");
+
+        let error = expand_error(parse_quote! {
+            enum MyCounter {
+                #[counter(unit = "furlongs")]
+                Packets,
+            }
+        });
+        assert_eq!(error, "expected a label of `metrics::Unit`, such as \"count\" or \"bytes\"");
+
+        let error = expand_error(parse_quote! {
+            enum MyCounter {
+                #[counter(label = "packets")]
+                Packets,
+            }
+        });
+        assert_eq!(error, "expected `name`, `description`, `unit` or `index`");
+    }
+}
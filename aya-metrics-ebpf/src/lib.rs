@@ -3,23 +3,130 @@
 #![deny(missing_docs)]
 #![deny(clippy::unwrap_used)]
 
-//! Provides counter functionality with testable no_std implementations for use in BPF.
+//! Provides counter, compound counter, gauge, histogram, timer, up down counter, max, min, distinct count, keyed counter
+//! and heavy hitter functionality with testable no_std implementations for use in BPF.
 
 #[cfg(any(test, target_arch = "bpf"))]
-use aya_metrics_common::{Counter, Meter, BPF_COUNTERS_MAX_ENTRIES};
+use core::{
+    marker::PhantomData,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+#[cfg(any(test, target_arch = "bpf"))]
+use aya_metrics_common::{
+    hll_hash, mmap_counters_stride, CompoundCounter, CompoundCounterFields, CompoundCounterValue, CountMinSketch,
+    Counter, DistinctCount, Gauge, GaugeValue, HeavyHitter, Histogram, HistogramValue, HyperLogLogValue, KeyedCounter,
+    Max, MeterKey, MeterKind, Min, UpDownCounter, WatermarkValue, BPF_COMPOUND_COUNTERS_MAX_ENTRIES,
+    BPF_COMPOUND_COUNTER_FIELDS, BPF_COUNTERS_MAX_ENTRIES, BPF_DISTINCT_COUNTS_MAX_ENTRIES, BPF_GAUGES_MAX_ENTRIES,
+    BPF_HEAVY_HITTERS_MAX_ENTRIES, BPF_HEAVY_HITTER_CANDIDATES_MAX_ENTRIES, BPF_HISTOGRAMS_MAX_ENTRIES,
+    BPF_KEYED_COUNTERS_DROPPED_MAX_ENTRIES, BPF_KEYED_COUNTERS_MAX_ENTRIES, BPF_MAXES_MAX_ENTRIES,
+    BPF_MINS_MAX_ENTRIES, BPF_MMAP_COUNTERS_MAX_CPUS, BPF_TIMERS_MAX_ENTRIES, BPF_UP_DOWN_COUNTERS_MAX_ENTRIES,
+};
+
+/// Borrows a map declared as `static mut`, like in the aya templates, through a raw pointer rather than a reference to
+/// the mutable static. Maps are only ever borrowed shared, as their helpers take `&self`, see the SAFETY comments where
+/// they are referenced.
+#[cfg(any(test, target_arch = "bpf"))]
+macro_rules! static_map {
+    ($map:ident) => {
+        (*&raw const $map)
+    };
+}
 
 // Module with implementations depending on the `aya-bpf` module.
 // The `bpf` module only compiles with the `bpf` feature enabled.
 #[cfg(target_arch = "bpf")]
 mod bpf {
     use super::*;
+    use aya_ebpf::bindings::BPF_F_MMAPABLE;
+    pub use aya_ebpf::bindings::BPF_NOEXIST;
+    pub use aya_ebpf::helpers::{bpf_get_smp_processor_id, bpf_ktime_get_ns};
     use aya_ebpf::macros::map;
-    use aya_ebpf::maps::PerCpuArray;
+    use aya_ebpf::maps::{Array, PerCpuArray};
+    #[cfg(not(feature = "lru"))]
+    use aya_ebpf::maps::{HashMap as TimersMap, PerCpuHashMap as KeyedCountersMap};
+    #[cfg(feature = "lru")]
+    use aya_ebpf::maps::{LruHashMap as TimersMap, LruPerCpuHashMap as KeyedCountersMap};
 
     // A BPF map to store counter metrics
     #[map(name = "COUNTERS")]
     pub static mut COUNTERS: PerCpuArray<u64> =
         PerCpuArray::<u64>::with_max_entries(BPF_COUNTERS_MAX_ENTRIES as u32, 0);
+
+    // The BPF map of counters declared with `counter_map!`
+    pub type CounterArray = PerCpuArray<u64>;
+
+    pub const fn counter_array(max_entries: u32) -> CounterArray {
+        PerCpuArray::<u64>::with_max_entries(max_entries, 0)
+    }
+
+    // The BPF map of counters declared with `mmap_counter_map!`
+    pub type MmapCounterArray = Array<u64>;
+
+    pub const fn mmap_counter_array(max_entries: u32) -> MmapCounterArray {
+        Array::<u64>::with_max_entries(max_entries, BPF_F_MMAPABLE as u32)
+    }
+
+    // A BPF map to store gauge metrics
+    #[map(name = "GAUGES")]
+    pub static mut GAUGES: PerCpuArray<GaugeValue> =
+        PerCpuArray::<GaugeValue>::with_max_entries(BPF_GAUGES_MAX_ENTRIES as u32, 0);
+
+    // A BPF map to store histogram metrics
+    #[map(name = "HISTOGRAMS")]
+    pub static mut HISTOGRAMS: PerCpuArray<HistogramValue> =
+        PerCpuArray::<HistogramValue>::with_max_entries(BPF_HISTOGRAMS_MAX_ENTRIES as u32, 0);
+
+    // A BPF map to store up down counter metrics
+    #[map(name = "UP_DOWN_COUNTERS")]
+    pub static mut UP_DOWN_COUNTERS: PerCpuArray<i64> =
+        PerCpuArray::<i64>::with_max_entries(BPF_UP_DOWN_COUNTERS_MAX_ENTRIES as u32, 0);
+
+    // A BPF map to store max metrics
+    #[map(name = "MAXES")]
+    pub static mut MAXES: PerCpuArray<WatermarkValue> =
+        PerCpuArray::<WatermarkValue>::with_max_entries(BPF_MAXES_MAX_ENTRIES as u32, 0);
+
+    // A BPF map to store min metrics
+    #[map(name = "MINS")]
+    pub static mut MINS: PerCpuArray<WatermarkValue> =
+        PerCpuArray::<WatermarkValue>::with_max_entries(BPF_MINS_MAX_ENTRIES as u32, 0);
+
+    // A BPF map to store distinct count metrics
+    #[map(name = "DISTINCT_COUNTS")]
+    pub static mut DISTINCT_COUNTS: PerCpuArray<HyperLogLogValue> =
+        PerCpuArray::<HyperLogLogValue>::with_max_entries(BPF_DISTINCT_COUNTS_MAX_ENTRIES as u32, 0);
+
+    // A BPF map to store keyed counter metrics, which evicts the least recently used keys with the `lru` feature
+    #[map(name = "KEYED_COUNTERS")]
+    pub static mut KEYED_COUNTERS: KeyedCountersMap<MeterKey, u64> =
+        KeyedCountersMap::<MeterKey, u64>::with_max_entries(BPF_KEYED_COUNTERS_MAX_ENTRIES as u32, 0);
+
+    // A BPF map to count the increments of each keyed counter dropped as their key could not be inserted
+    #[map(name = "KEYED_COUNTERS_DROPPED")]
+    pub static mut KEYED_COUNTERS_DROPPED: PerCpuArray<u64> =
+        PerCpuArray::<u64>::with_max_entries(BPF_KEYED_COUNTERS_DROPPED_MAX_ENTRIES as u32, 0);
+
+    // A BPF map to store the count-min sketches of heavy hitter metrics
+    #[map(name = "HEAVY_HITTERS")]
+    pub static mut HEAVY_HITTERS: PerCpuArray<CountMinSketch> =
+        PerCpuArray::<CountMinSketch>::with_max_entries(BPF_HEAVY_HITTERS_MAX_ENTRIES as u32, 0);
+
+    // A BPF map to store the candidate keys of heavy hitter metrics, which evicts the least recently used keys with the
+    // `lru` feature
+    #[map(name = "HEAVY_HITTER_CANDIDATES")]
+    pub static mut HEAVY_HITTER_CANDIDATES: KeyedCountersMap<MeterKey, u64> =
+        KeyedCountersMap::<MeterKey, u64>::with_max_entries(BPF_HEAVY_HITTER_CANDIDATES_MAX_ENTRIES as u32, 0);
+
+    // A BPF map to store compound counter metrics
+    #[map(name = "COMPOUND_COUNTERS")]
+    pub static mut COMPOUND_COUNTERS: PerCpuArray<CompoundCounterValue> =
+        PerCpuArray::<CompoundCounterValue>::with_max_entries(BPF_COMPOUND_COUNTERS_MAX_ENTRIES as u32, 0);
+
+    // A BPF map to store the start time of timers, which evicts the least recently used timers with the `lru` feature
+    #[map(name = "TIMERS")]
+    pub static mut TIMERS: TimersMap<MeterKey, u64> =
+        TimersMap::<MeterKey, u64>::with_max_entries(BPF_TIMERS_MAX_ENTRIES as u32, 0);
 }
 
 // Include everything from the `bpf` module.
@@ -27,6 +134,223 @@ mod bpf {
 #[cfg(target_arch = "bpf")]
 use bpf::*;
 
+// Items used by the expansion of macros, which are not part of the public API.
+#[doc(hidden)]
+pub mod __private {
+    #[cfg(target_arch = "bpf")]
+    pub use aya_ebpf::macros::map;
+    pub use aya_metrics_common::{Counter, CounterStorage};
+
+    /// Whether two strings are equal, which `str::eq` cannot tell in a constant.
+    pub const fn str_eq(a: &str, b: &str) -> bool {
+        let (a, b) = (a.as_bytes(), b.as_bytes());
+        if a.len() != b.len() {
+            return false;
+        }
+        let mut i = 0;
+        while i < a.len() {
+            if a[i] != b[i] {
+                return false;
+            }
+            i += 1;
+        }
+        true
+    }
+}
+
+/// Declares a BPF map holding the counters of an enumeration, see [`CounterMap`].
+///
+/// The map is a `static` named by [`Counter::MAP_NAME`], as the BPF map takes the name of the static, and has
+/// [`Counter::MAX_ENTRIES`] entries so it does not need to be resized when loading. This lets independent
+/// enumerations, such as those of different libraries linked into the same eBPF object, each have a map of their own.
+///
+/// ```ignore
+/// aya_metrics_ebpf::counter_map!(pub LIBRARY_COUNTERS: LibraryCounter);
+///
+/// LIBRARY_COUNTERS.increment(LibraryCounter::Packets, 1);
+/// ```
+///
+/// Fails to compile if the static is not named by [`Counter::MAP_NAME`].
+#[macro_export]
+macro_rules! counter_map {
+    ($(#[$attr:meta])* $vis:vis $name:ident: $counter:ty) => {
+        $(#[$attr])*
+        #[cfg_attr(target_arch = "bpf", $crate::__private::map)]
+        $vis static $name: $crate::CounterMap<$counter> = $crate::CounterMap::new();
+
+        const _: () = assert!(
+            ::core::matches!(
+                <$counter as $crate::__private::Counter>::STORAGE,
+                $crate::__private::CounterStorage::PerCpuArray,
+            ),
+            concat!("the counters of ", stringify!($counter), " must be stored with CounterStorage::PerCpuArray"),
+        );
+
+        $crate::counter_layout!($name: $counter);
+    };
+}
+
+/// Declares a BPF array holding the counters of an enumeration which user space maps into memory, see
+/// [`MmapCounterMap`].
+///
+/// Like [`counter_map!`], the map is a `static` named by [`Counter::MAP_NAME`]. It is created with `BPF_F_MMAPABLE`
+/// and holds a stride of [`mmap_counters_stride`] entries for each of [`BPF_MMAP_COUNTERS_MAX_CPUS`] CPUs, so user
+/// space reads the counters from memory rather than with a syscall. This suits short collection periods, where the
+/// syscalls reading a per CPU array would dominate.
+///
+/// ```ignore
+/// aya_metrics_ebpf::mmap_counter_map!(pub FAST_COUNTERS: FastCounter);
+///
+/// FAST_COUNTERS.increment(FastCounter::Packets, 1);
+/// ```
+///
+/// Fails to compile if the static is not named by [`Counter::MAP_NAME`], or if the counters are not stored with
+/// [`CounterStorage::Mmap`](aya_metrics_common::CounterStorage::Mmap).
+#[macro_export]
+macro_rules! mmap_counter_map {
+    ($(#[$attr:meta])* $vis:vis $name:ident: $counter:ty) => {
+        $(#[$attr])*
+        #[cfg_attr(target_arch = "bpf", $crate::__private::map)]
+        $vis static $name: $crate::MmapCounterMap<$counter> = $crate::MmapCounterMap::new();
+
+        const _: () = assert!(
+            ::core::matches!(
+                <$counter as $crate::__private::Counter>::STORAGE,
+                $crate::__private::CounterStorage::Mmap,
+            ),
+            concat!("the counters of ", stringify!($counter), " must be stored with CounterStorage::Mmap"),
+        );
+
+        $crate::counter_layout!($name: $counter);
+    };
+}
+
+/// Embeds [`Counter::LAYOUT`] of an enumeration in the eBPF object, alongside the map holding its counters.
+///
+/// The layout is a `.rodata` global in its own section, named by the map after
+/// [`LAYOUT_SECTION_PREFIX`](aya_metrics_common::LAYOUT_SECTION_PREFIX), which user space compares to the layout of
+/// its own enumeration. This is already done by [`counter_map!`], so it is only needed for counters held by the
+/// default map.
+///
+/// ```ignore
+/// aya_metrics_ebpf::counter_layout!(COUNTERS: MyCounter);
+/// ```
+///
+/// Fails to compile if the map is not named by [`Counter::MAP_NAME`].
+#[macro_export]
+macro_rules! counter_layout {
+    ($name:ident: $counter:ty) => {
+        const _: () = {
+            assert!(
+                $crate::__private::str_eq(<$counter as $crate::__private::Counter>::MAP_NAME, stringify!($name)),
+                concat!("the counter map must be named by Counter::MAP_NAME of ", stringify!($counter)),
+            );
+
+            #[used]
+            #[export_name = concat!("AYA_METRICS_LAYOUT_", stringify!($name))]
+            #[link_section = concat!(".rodata.layout.", stringify!($name))]
+            static LAYOUT: u64 = <$counter as $crate::__private::Counter>::LAYOUT;
+        };
+    };
+}
+
+/// A BPF map holding the counters of an enumeration, declared with [`counter_map!`].
+///
+/// Counters of enumerations which do not set [`Counter::MAP_NAME`] are held by the default map, see [`counter`].
+#[cfg(any(test, target_arch = "bpf"))]
+#[repr(transparent)]
+pub struct CounterMap<T> {
+    map: CounterArray,
+    _t: PhantomData<T>,
+}
+
+// SAFETY: Instances of PerCpuArray are thread local in eBPF, see `counter`.
+#[cfg(any(test, target_arch = "bpf"))]
+unsafe impl<T> Sync for CounterMap<T> {}
+
+#[cfg(any(test, target_arch = "bpf"))]
+impl<T: Counter> CounterMap<T> {
+    /// Create a map with [`Counter::MAX_ENTRIES`] entries, see [`counter_map!`].
+    #[doc(hidden)]
+    #[allow(clippy::new_without_default)]
+    pub const fn new() -> Self {
+        CounterMap {
+            map: counter_array(T::MAX_ENTRIES),
+            _t: PhantomData,
+        }
+    }
+
+    /// Increments a counter held by this map, see [`counter`].
+    ///
+    /// # Arguments
+    ///
+    /// * `counter` - An identifier for a counter metric. It is used as an index into the map.
+    /// * `value`   - The amount by which the counter should be incremented.
+    ///
+    #[inline(always)]
+    pub fn increment(&self, counter: T, value: u64) {
+        if let Some(counter) = self.map.get_ptr_mut(Counter::index(&counter)) {
+            // SAFETY: The value is only accessed by the current CPU, see `counter`.
+            unsafe { *counter += value };
+        }
+    }
+}
+
+/// A BPF array holding the counters of an enumeration which user space maps into memory, declared with
+/// [`mmap_counter_map!`].
+///
+/// Each CPU increments its own stride of the array, so like a per CPU array no two CPUs write to the same counter.
+#[cfg(any(test, target_arch = "bpf"))]
+#[repr(transparent)]
+pub struct MmapCounterMap<T> {
+    map: MmapCounterArray,
+    _t: PhantomData<T>,
+}
+
+// SAFETY: Each CPU only writes to its own stride of the array, see `MmapCounterMap::increment`.
+#[cfg(any(test, target_arch = "bpf"))]
+unsafe impl<T> Sync for MmapCounterMap<T> {}
+
+#[cfg(any(test, target_arch = "bpf"))]
+impl<T: Counter> MmapCounterMap<T> {
+    /// The number of entries of each CPU.
+    const STRIDE: u32 = mmap_counters_stride(T::MAX_ENTRIES);
+
+    /// Create a map with a stride for each of [`BPF_MMAP_COUNTERS_MAX_CPUS`] CPUs, see [`mmap_counter_map!`].
+    #[doc(hidden)]
+    #[allow(clippy::new_without_default)]
+    pub const fn new() -> Self {
+        MmapCounterMap {
+            map: mmap_counter_array(Self::STRIDE * BPF_MMAP_COUNTERS_MAX_CPUS as u32),
+            _t: PhantomData,
+        }
+    }
+
+    /// Increments a counter held by this map, see [`counter`].
+    ///
+    /// # Arguments
+    ///
+    /// * `counter` - An identifier for a counter metric. It is used as an index into the stride of the current CPU.
+    /// * `value`   - The amount by which the counter should be incremented.
+    ///
+    #[inline(always)]
+    pub fn increment(&self, counter: T, value: u64) {
+        let index = Counter::index(&counter);
+        // Never write to the stride of another CPU
+        if index >= Self::STRIDE {
+            return;
+        }
+        // SAFETY: The helper has no preconditions.
+        let cpu_id = unsafe { bpf_get_smp_processor_id() };
+        if let Some(counter) = self.map.get_ptr_mut(cpu_id * Self::STRIDE + index) {
+            // SAFETY: The map holds aligned u64s which live as long as the program.
+            let counter = unsafe { AtomicU64::from_ptr(counter) };
+            // Only the current CPU writes to its stride, but user space reads it concurrently, so the store is atomic
+            counter.store(counter.load(Ordering::Relaxed) + value, Ordering::Relaxed);
+        }
+    }
+}
+
 /// Increments a counter.
 ///
 /// Counters represent a single monotonic value, which means the value can only be incremented, not decremented, and
@@ -37,14 +361,334 @@ use bpf::*;
 /// * `counter` - An identifier for a counter metric. It is used as an index into the underlying BPF map.
 /// * `value`   - The amount by which the counter should be incremented.
 ///
+/// Fails to compile if [`Counter::MAP_NAME`] is not the default map, as counters with a map of their own are
+/// incremented through the map declared with [`counter_map!`].
 #[cfg(any(test, target_arch = "bpf"))]
 #[inline(always)]
 pub fn counter<T: Counter>(counter: T, value: u64) {
+    const {
+        assert!(
+            __private::str_eq(T::MAP_NAME, MeterKind::Counter.map_name()),
+            "counters with a map of their own must be incremented through the map declared with counter_map!",
+        )
+    };
+
     // SAFETY: Instances of PerCpuArray are thread local in eBPF. We can therefore be sure that concurrent
     // accesses will not happen on other threads and, within this function, counter is the sole reference to COUNTERS.
     // It is not leaked from this function, so concurrent &mut references cannot be introduced by calling this function multiple times.
-    if let Some(counter) = unsafe { COUNTERS.get_ptr_mut(Meter::index(&counter)) } {
+    if let Some(counter) = unsafe { static_map!(COUNTERS).get_ptr_mut(Counter::index(&counter)) } {
+        unsafe { *counter += value };
+    }
+}
+
+/// Sets a gauge to a value.
+///
+/// Gauges represent a single value which can go up and down. Each CPU holds its own value along with the time it was
+/// last written, which user space uses to merge the values across CPUs.
+///
+/// # Arguments
+///
+/// * `gauge` - An identifier for a gauge metric. It is used as an index into the underlying BPF map.
+/// * `value` - The value the gauge should be set to.
+///
+#[cfg(any(test, target_arch = "bpf"))]
+#[inline(always)]
+pub fn gauge_set<T: Gauge>(gauge: T, value: i64) {
+    // SAFETY: See `counter`, the same reasoning applies to GAUGES.
+    if let Some(gauge) = unsafe { static_map!(GAUGES).get_ptr_mut(Gauge::index(&gauge)) } {
+        unsafe {
+            (*gauge).value = value;
+            (*gauge).timestamp = bpf_ktime_get_ns();
+        };
+    }
+}
+
+/// Adds to the value of a gauge.
+///
+/// The value is added to the gauge held by the current CPU, see [`gauge_set`].
+///
+/// # Arguments
+///
+/// * `gauge` - An identifier for a gauge metric. It is used as an index into the underlying BPF map.
+/// * `value` - The amount by which the gauge should be incremented, or decremented when negative.
+///
+#[cfg(any(test, target_arch = "bpf"))]
+#[inline(always)]
+pub fn gauge_add<T: Gauge>(gauge: T, value: i64) {
+    // SAFETY: See `counter`, the same reasoning applies to GAUGES.
+    if let Some(gauge) = unsafe { static_map!(GAUGES).get_ptr_mut(Gauge::index(&gauge)) } {
+        unsafe {
+            (*gauge).value = (*gauge).value.wrapping_add(value);
+            (*gauge).timestamp = bpf_ktime_get_ns();
+        };
+    }
+}
+
+/// Records a value in a histogram.
+///
+/// Histograms represent the distribution of values. The value is counted in one of the buckets of the histogram, see
+/// [`Histogram::buckets`].
+///
+/// # Arguments
+///
+/// * `histogram` - An identifier for a histogram metric. It is used as an index into the underlying BPF map.
+/// * `value`     - The value to record.
+///
+#[cfg(any(test, target_arch = "bpf"))]
+#[inline(always)]
+pub fn histogram<T: Histogram>(histogram: T, value: u64) {
+    let bucket = Histogram::buckets(&histogram).bucket(value);
+    // SAFETY: See `counter`, the same reasoning applies to HISTOGRAMS.
+    if let Some(histogram) = unsafe { static_map!(HISTOGRAMS).get_ptr_mut(Histogram::index(&histogram)) } {
+        // The bucket is always in bounds but the check keeps the verifier happy.
+        if let Some(bucket) = unsafe { (*histogram).buckets.get_mut(bucket) } {
+            *bucket += 1;
+        }
+    }
+}
+
+/// Starts a timer.
+///
+/// Timers measure the time between a start and a stop, such as a kprobe and its kretprobe, and record the elapsed
+/// nanoseconds into a histogram when stopped. The start time is stored in a BPF hash map under the histogram and the
+/// key, so a timer may be stopped on a different CPU. Starting a timer again replaces its start time, and a timer is
+/// dropped if the map is full.
+///
+/// # Arguments
+///
+/// * `histogram` - An identifier for a histogram metric which the elapsed time is recorded into.
+/// * `key`       - The key of the timer, such as a thread id, which should not contain padding. It must be no larger
+///   than [`BPF_KEYED_KEY_SIZE`](aya_metrics_common::BPF_KEYED_KEY_SIZE).
+///
+#[cfg(any(test, target_arch = "bpf"))]
+#[inline(always)]
+pub fn timer_start<T: Histogram, K: Copy>(histogram: T, key: K) {
+    let key = MeterKey::new(Histogram::index(&histogram), &key);
+    let start = unsafe { bpf_ktime_get_ns() };
+    // SAFETY: TIMERS is shared by all CPUs, but entries are only replaced or removed by BPF helpers.
+    let _ = unsafe { static_map!(TIMERS).insert(&key, &start, 0) };
+}
+
+/// Stops a timer, recording the nanoseconds elapsed since it was started into a histogram.
+///
+/// Nothing is recorded if the timer was not started, see [`timer_start`].
+///
+/// # Arguments
+///
+/// * `histogram` - An identifier for a histogram metric which the elapsed time is recorded into.
+/// * `key`       - The key the timer was started with.
+///
+#[cfg(any(test, target_arch = "bpf"))]
+#[inline(always)]
+pub fn timer_stop<T: Histogram, K: Copy>(histogram: T, key: K) {
+    let key = MeterKey::new(Histogram::index(&histogram), &key);
+    let stop = unsafe { bpf_ktime_get_ns() };
+    // SAFETY: See `timer_start`, the start time is copied out before the entry is removed.
+    if let Some(start) = unsafe { static_map!(TIMERS).get_ptr(&key) } {
+        let start = unsafe { *start };
+        let _ = unsafe { static_map!(TIMERS).remove(&key) };
+        crate::histogram(histogram, stop.saturating_sub(start));
+    }
+}
+
+/// Adds to an up down counter.
+///
+/// Up down counters represent a single value which can be incremented and decremented, for example incremented when a
+/// connection is opened and decremented when it is closed. Each CPU holds its own signed value, so the value on a
+/// single CPU may be negative, but the sum across all CPUs is the value of the counter.
+///
+/// # Arguments
+///
+/// * `counter` - An identifier for an up down counter metric. It is used as an index into the underlying BPF map.
+/// * `value`   - The amount by which the counter should be incremented, or decremented when negative.
+///
+#[cfg(any(test, target_arch = "bpf"))]
+#[inline(always)]
+pub fn up_down_counter<T: UpDownCounter>(counter: T, value: i64) {
+    // SAFETY: See `counter`, the same reasoning applies to UP_DOWN_COUNTERS.
+    if let Some(counter) = unsafe { static_map!(UP_DOWN_COUNTERS).get_ptr_mut(UpDownCounter::index(&counter)) } {
+        unsafe { *counter = (*counter).wrapping_add(value) };
+    }
+}
+
+/// Records a value in a max meter, keeping it if it is the largest value seen in the current period.
+///
+/// Each CPU holds the largest value it has seen. User space reads and resets the values each period and reports the
+/// largest value across all CPUs.
+///
+/// # Arguments
+///
+/// * `meter` - An identifier for a max metric. It is used as an index into the underlying BPF map.
+/// * `value` - The value to record.
+///
+#[cfg(any(test, target_arch = "bpf"))]
+#[inline(always)]
+pub fn max<T: Max>(meter: T, value: u64) {
+    // SAFETY: See `counter`, the same reasoning applies to MAXES.
+    if let Some(max) = unsafe { static_map!(MAXES).get_ptr_mut(Max::index(&meter)) } {
+        unsafe { (*max).record_max(value) };
+    }
+}
+
+/// Records a value in a min meter, keeping it if it is the smallest value seen in the current period.
+///
+/// Each CPU holds the smallest value it has seen. User space reads and resets the values each period and reports the
+/// smallest value across all CPUs.
+///
+/// # Arguments
+///
+/// * `meter` - An identifier for a min metric. It is used as an index into the underlying BPF map.
+/// * `value` - The value to record.
+///
+#[cfg(any(test, target_arch = "bpf"))]
+#[inline(always)]
+pub fn min<T: Min>(meter: T, value: u64) {
+    // SAFETY: See `counter`, the same reasoning applies to MINS.
+    if let Some(min) = unsafe { static_map!(MINS).get_ptr_mut(Min::index(&meter)) } {
+        unsafe { (*min).record_min(value) };
+    }
+}
+
+/// Records a value in a distinct count.
+///
+/// Distinct counts estimate the number of distinct values recorded in each period, for example the number of unique
+/// source addresses. The value is hashed into the HyperLogLog registers of the current CPU, which user space merges
+/// across CPUs and resets each period.
+///
+/// # Arguments
+///
+/// * `meter` - An identifier for a distinct count metric. It is used as an index into the underlying BPF map.
+/// * `value` - The value to count, which should not contain padding.
+///
+#[cfg(any(test, target_arch = "bpf"))]
+#[inline(always)]
+pub fn distinct_count<T: DistinctCount, K: Copy>(meter: T, value: K) {
+    let hash = hll_hash(&value);
+    // SAFETY: See `counter`, the same reasoning applies to DISTINCT_COUNTS.
+    if let Some(registers) = unsafe { static_map!(DISTINCT_COUNTS).get_ptr_mut(DistinctCount::index(&meter)) } {
+        unsafe { (*registers).record(hash) };
+    }
+}
+
+/// Increments a keyed counter.
+///
+/// Keyed counters are counters which hold a separate series for each key, for example the source address of a packet.
+/// The first time a key is seen it is inserted into the underlying BPF map, so a key is dropped if the map is full.
+/// Dropped increments are counted for each keyed counter, which user space reports as dropped keys. With the `lru`
+/// feature the least recently used key is evicted instead, which user space reports as evicted.
+///
+/// # Arguments
+///
+/// * `counter` - An identifier for a keyed counter metric. It is stored alongside the key in the underlying BPF map.
+/// * `key`     - The key of the series to increment, which should not contain padding. It must be no larger than
+///   [`BPF_KEYED_KEY_SIZE`](aya_metrics_common::BPF_KEYED_KEY_SIZE).
+/// * `value`   - The amount by which the counter should be incremented.
+///
+#[cfg(any(test, target_arch = "bpf"))]
+#[inline(always)]
+pub fn keyed_counter<T: KeyedCounter, K: Copy>(counter: T, key: K, value: u64) {
+    let index = KeyedCounter::index(&counter);
+    let key = MeterKey::new(index, &key);
+    // SAFETY: See `counter`, the same reasoning applies to KEYED_COUNTERS as values are per CPU.
+    if let Some(counter) = unsafe { static_map!(KEYED_COUNTERS).get_ptr_mut(&key) } {
         unsafe { *counter += value };
+        return;
+    }
+
+    // Values of other CPUs are zeroed when a key is first inserted.
+    if unsafe { static_map!(KEYED_COUNTERS).insert(&key, &value, BPF_NOEXIST as u64) }.is_err() {
+        // Another CPU may have inserted the key in the meantime, otherwise the map is full and the value is dropped.
+        if let Some(counter) = unsafe { static_map!(KEYED_COUNTERS).get_ptr_mut(&key) } {
+            unsafe { *counter += value };
+            return;
+        }
+
+        // SAFETY: See `counter`, the same reasoning applies to KEYED_COUNTERS_DROPPED.
+        if let Some(dropped) = unsafe { static_map!(KEYED_COUNTERS_DROPPED).get_ptr_mut(index) } {
+            unsafe { *dropped += 1 };
+        }
+    }
+}
+
+/// Counts a key in a heavy hitter.
+///
+/// Heavy hitters report the keys with the largest counts in each period, for example the flows sending the most bytes,
+/// without a counter for every key. The key is counted in the count-min sketch of the current CPU, and once its
+/// estimated count reaches [`HeavyHitter::threshold`] the estimate is written to a table of candidates shared by all
+/// heavy hitters. New candidates are dropped once the table is full, or with the `lru` feature the least recently used
+/// candidate is evicted instead.
+///
+/// # Arguments
+///
+/// * `meter` - An identifier for a heavy hitter metric. It is used as an index into the underlying BPF maps.
+/// * `key`   - The key to count, which should not contain padding. It must be no larger than
+///   [`BPF_KEYED_KEY_SIZE`](aya_metrics_common::BPF_KEYED_KEY_SIZE).
+/// * `value` - The amount by which the count of the key should be incremented.
+///
+#[cfg(any(test, target_arch = "bpf"))]
+#[inline(always)]
+pub fn heavy_hitter<T: HeavyHitter, K: Copy>(meter: T, key: K, value: u64) {
+    let key = MeterKey::new(HeavyHitter::index(&meter), &key);
+    // SAFETY: See `counter`, the same reasoning applies to HEAVY_HITTERS.
+    let Some(sketch) = (unsafe { static_map!(HEAVY_HITTERS).get_ptr_mut(key.index) }) else {
+        return;
+    };
+    let estimate = unsafe { (*sketch).record(hll_hash(&key.key), value) };
+    if estimate < HeavyHitter::threshold(&meter) {
+        return;
+    }
+
+    // SAFETY: See `keyed_counter`, the same reasoning applies to HEAVY_HITTER_CANDIDATES.
+    if let Some(candidate) = unsafe { static_map!(HEAVY_HITTER_CANDIDATES).get_ptr_mut(&key) } {
+        unsafe { *candidate = estimate };
+        return;
+    }
+
+    // Another CPU may have inserted the key in the meantime, otherwise the table is full and the key is dropped.
+    if unsafe { static_map!(HEAVY_HITTER_CANDIDATES).insert(&key, &estimate, BPF_NOEXIST as u64) }.is_err() {
+        if let Some(candidate) = unsafe { static_map!(HEAVY_HITTER_CANDIDATES).get_ptr_mut(&key) } {
+            unsafe { *candidate = estimate };
+        }
+    }
+}
+
+/// Fails to compile when the values given to a compound counter are not one for each of its fields.
+#[cfg(any(test, target_arch = "bpf"))]
+struct FieldValues<T, const N: usize>(PhantomData<T>);
+
+#[cfg(any(test, target_arch = "bpf"))]
+impl<T: CompoundCounter, const N: usize> FieldValues<T, N> {
+    const FIT: () = {
+        #[allow(clippy::let_unit_value)]
+        let () = CompoundCounterFields::<T>::FIT;
+        assert!(N == T::FIELDS.len(), "compound counters must be given a value for each of their fields");
+    };
+}
+
+/// Increments every field of a compound counter at once.
+///
+/// Compound counters hold several counters in a single slot, such as the packets and bytes seen by a program, so they
+/// are incremented with a single lookup of the underlying BPF map rather than a lookup for each counter. Fields which
+/// did not change are given zero.
+///
+/// # Arguments
+///
+/// * `counter` - An identifier for a compound counter metric. It is used as an index into the underlying BPF map.
+/// * `values`  - The amount by which each field should be incremented, in the order of [`CompoundCounter::FIELDS`].
+///
+/// Fails to compile if there is not a value for each field.
+#[cfg(any(test, target_arch = "bpf"))]
+#[inline(always)]
+pub fn compound_counter<T: CompoundCounter, const N: usize>(counter: T, values: [u64; N]) {
+    #[allow(clippy::let_unit_value)]
+    let () = FieldValues::<T, N>::FIT;
+
+    // SAFETY: See `counter`, the same reasoning applies to COMPOUND_COUNTERS.
+    if let Some(slot) = unsafe { static_map!(COMPOUND_COUNTERS).get_ptr_mut(CompoundCounter::index(&counter)) } {
+        let fields = unsafe { &mut (*slot).fields };
+        for (field, value) in fields.iter_mut().zip(values) {
+            *field += value;
+        }
     }
 }
 
@@ -55,91 +699,823 @@ pub fn counter<T: Counter>(counter: T, value: u64) {
 #[cfg(test)]
 mod bpf_mocks {
     use std::cell::Cell;
+    use std::sync::atomic::{AtomicU64, Ordering};
 
-    use super::BPF_COUNTERS_MAX_ENTRIES;
+    use super::{
+        CompoundCounterValue, CountMinSketch, GaugeValue, HistogramValue, HyperLogLogValue, MeterKey, WatermarkValue,
+        BPF_COMPOUND_COUNTERS_MAX_ENTRIES, BPF_COMPOUND_COUNTER_FIELDS, BPF_COUNTERS_MAX_ENTRIES,
+        BPF_DISTINCT_COUNTS_MAX_ENTRIES, BPF_GAUGES_MAX_ENTRIES, BPF_HEAVY_HITTERS_MAX_ENTRIES,
+        BPF_HEAVY_HITTER_CANDIDATES_MAX_ENTRIES, BPF_HISTOGRAMS_MAX_ENTRIES, BPF_KEYED_COUNTERS_DROPPED_MAX_ENTRIES,
+        BPF_KEYED_COUNTERS_MAX_ENTRIES, BPF_MAXES_MAX_ENTRIES, BPF_MINS_MAX_ENTRIES, BPF_MMAP_COUNTERS_MAX_CPUS,
+        BPF_TIMERS_MAX_ENTRIES, BPF_UP_DOWN_COUNTERS_MAX_ENTRIES,
+    };
 
     pub struct PerCpuArray<T, const N: usize> {
         pub data: Cell<[T; N]>,
         _t: core::marker::PhantomData<T>,
     }
 
-    impl<const N: usize> PerCpuArray<u64, N> {
-        pub const fn new() -> PerCpuArray<u64, N> {
+    impl<T: Copy, const N: usize> PerCpuArray<T, N> {
+        pub const fn new(value: T) -> PerCpuArray<T, N> {
             PerCpuArray {
-                data: Cell::new([0u64; N]),
+                data: Cell::new([value; N]),
                 _t: core::marker::PhantomData,
             }
         }
 
-        pub fn get_ptr_mut(&mut self, index: u32) -> Option<*mut u64> {
-            let data = self.data.get_mut();
-            let ptr = data as *mut u64;
-            let ptr_at = unsafe { ptr.offset(index.try_into().unwrap()) };
+        pub fn get_ptr_mut(&self, index: u32) -> Option<*mut T> {
+            let ptr = self.data.as_ptr() as *mut T;
+            let ptr_at = unsafe { ptr.add(index as usize) };
             Some(ptr_at)
         }
     }
 
     pub static mut COUNTERS: PerCpuArray<u64, BPF_COUNTERS_MAX_ENTRIES> =
-        PerCpuArray::<u64, BPF_COUNTERS_MAX_ENTRIES>::new();
-}
-
-// Include everything from the `bpf_mocks` module for tests.
-#[cfg(test)]
-use bpf_mocks::*;
+        PerCpuArray::<u64, BPF_COUNTERS_MAX_ENTRIES>::new(0);
 
-#[cfg(test)]
-mod test {
-    use super::*;
+    // Mocked with the default number of entries, which must not be exceeded by the tests.
+    pub type CounterArray = PerCpuArray<u64, BPF_COUNTERS_MAX_ENTRIES>;
 
-    #[derive(Copy, Clone, Debug)]
-    enum MockCounter {
-        Test1,
-        Test2,
+    pub const fn counter_array(_max_entries: u32) -> CounterArray {
+        PerCpuArray::<u64, BPF_COUNTERS_MAX_ENTRIES>::new(0)
     }
 
-    impl Counter for MockCounter {
-        fn name(self) -> String {
-            match self {
-                MockCounter::Test1 => "test1".to_string(),
-                MockCounter::Test2 => "test2".to_string(),
-            }
-        }
+    // Mocked with room for a stride of the default number of entries for each CPU.
+    pub type MmapCounterArray = PerCpuArray<u64, { BPF_COUNTERS_MAX_ENTRIES * BPF_MMAP_COUNTERS_MAX_CPUS }>;
 
-        fn index(&self) -> u32 {
-            match self {
-                MockCounter::Test1 => 0,
-                MockCounter::Test2 => BPF_COUNTERS_MAX_ENTRIES as u32 - 1,
-            }
-        }
+    pub const fn mmap_counter_array(_max_entries: u32) -> MmapCounterArray {
+        MmapCounterArray::new(0)
     }
 
-    #[test]
-    fn test_counter() {
-        let mut expected = [0u64; BPF_COUNTERS_MAX_ENTRIES];
+    pub static mut GAUGES: PerCpuArray<GaugeValue, BPF_GAUGES_MAX_ENTRIES> =
+        PerCpuArray::<GaugeValue, BPF_GAUGES_MAX_ENTRIES>::new(GaugeValue { value: 0, timestamp: 0 });
 
-        let actual = unsafe { COUNTERS.data.get() };
-        assert_eq!(actual, expected);
+    pub static mut HISTOGRAMS: PerCpuArray<HistogramValue, BPF_HISTOGRAMS_MAX_ENTRIES> =
+        PerCpuArray::<HistogramValue, BPF_HISTOGRAMS_MAX_ENTRIES>::new(HistogramValue::EMPTY);
 
-        // test adding some numbers
-        counter(MockCounter::Test1, 1);
-        counter(MockCounter::Test2, 42);
-        let actual = unsafe { COUNTERS.data.get() };
-        *expected.first_mut().unwrap() = 1;
-        *expected.last_mut().unwrap() = 42;
-        assert_eq!(actual, expected);
+    pub static mut UP_DOWN_COUNTERS: PerCpuArray<i64, BPF_UP_DOWN_COUNTERS_MAX_ENTRIES> =
+        PerCpuArray::<i64, BPF_UP_DOWN_COUNTERS_MAX_ENTRIES>::new(0);
 
-        // test adding zero
-        counter(MockCounter::Test1, 0);
-        counter(MockCounter::Test2, 0);
-        let actual = unsafe { COUNTERS.data.get() };
-        assert_eq!(actual, expected);
+    pub static mut MAXES: PerCpuArray<WatermarkValue, BPF_MAXES_MAX_ENTRIES> =
+        PerCpuArray::<WatermarkValue, BPF_MAXES_MAX_ENTRIES>::new(WatermarkValue { value: 0, count: 0 });
 
-        // test adding again increments existing values
+    pub static mut MINS: PerCpuArray<WatermarkValue, BPF_MINS_MAX_ENTRIES> =
+        PerCpuArray::<WatermarkValue, BPF_MINS_MAX_ENTRIES>::new(WatermarkValue { value: 0, count: 0 });
+
+    pub static mut DISTINCT_COUNTS: PerCpuArray<HyperLogLogValue, BPF_DISTINCT_COUNTS_MAX_ENTRIES> =
+        PerCpuArray::<HyperLogLogValue, BPF_DISTINCT_COUNTS_MAX_ENTRIES>::new(HyperLogLogValue::EMPTY);
+
+    pub static mut HEAVY_HITTERS: PerCpuArray<CountMinSketch, BPF_HEAVY_HITTERS_MAX_ENTRIES> =
+        PerCpuArray::<CountMinSketch, BPF_HEAVY_HITTERS_MAX_ENTRIES>::new(CountMinSketch::EMPTY);
+
+    pub static mut COMPOUND_COUNTERS: PerCpuArray<CompoundCounterValue, BPF_COMPOUND_COUNTERS_MAX_ENTRIES> =
+        PerCpuArray::<CompoundCounterValue, BPF_COMPOUND_COUNTERS_MAX_ENTRIES>::new(CompoundCounterValue {
+            fields: [0; BPF_COMPOUND_COUNTER_FIELDS],
+        });
+
+    pub const BPF_NOEXIST: u32 = 1;
+
+    // Per CPU hash maps are mocked as hash maps, as tests run on a single CPU.
+    pub struct HashMap<K, V, const N: usize> {
+        pub data: Cell<[Option<(K, V)>; N]>,
+    }
+
+    impl<K: Copy + PartialEq, V: Copy, const N: usize> HashMap<K, V, N> {
+        pub const fn new() -> HashMap<K, V, N> {
+            HashMap {
+                data: Cell::new([None; N]),
+            }
+        }
+
+        pub fn get(&self, key: &K) -> Option<V> {
+            self.data.get().iter().flatten().find(|(k, _)| k == key).map(|(_, v)| *v)
+        }
+
+        pub fn get_ptr_mut(&self, key: &K) -> Option<*mut V> {
+            let index = self.data.get().iter().position(|entry| entry.is_some_and(|(k, _)| k == *key))?;
+            // SAFETY: The entry at the index is occupied, and values are only written through the pointer like in BPF.
+            let entry = unsafe { &mut *(self.data.as_ptr() as *mut Option<(K, V)>).add(index) };
+            entry.as_mut().map(|(_, v)| v as *mut V)
+        }
+
+        pub fn get_ptr(&self, key: &K) -> Option<*const V> {
+            self.get_ptr_mut(key).map(|v| v as *const V)
+        }
+
+        pub fn remove(&self, key: &K) -> Result<(), i64> {
+            let mut data = self.data.get();
+            let entry = data
+                .iter_mut()
+                .find(|entry| entry.is_some_and(|(k, _)| k == *key))
+                .ok_or(-2i64)?; // ENOENT
+            *entry = None;
+            self.data.set(data);
+            Ok(())
+        }
+
+        pub fn insert(&self, key: &K, value: &V, flags: u64) -> Result<(), i64> {
+            if flags == BPF_NOEXIST as u64 && self.get(key).is_some() {
+                return Err(-17); // EEXIST
+            }
+            let mut data = self.data.get();
+            let entry = data.iter_mut().find(|entry| entry.is_none()).ok_or(-7i64)?; // E2BIG
+            *entry = Some((*key, *value));
+            self.data.set(data);
+            Ok(())
+        }
+    }
+
+    pub static mut KEYED_COUNTERS: HashMap<MeterKey, u64, BPF_KEYED_COUNTERS_MAX_ENTRIES> =
+        HashMap::<MeterKey, u64, BPF_KEYED_COUNTERS_MAX_ENTRIES>::new();
+
+    pub static mut KEYED_COUNTERS_DROPPED: PerCpuArray<u64, BPF_KEYED_COUNTERS_DROPPED_MAX_ENTRIES> =
+        PerCpuArray::<u64, BPF_KEYED_COUNTERS_DROPPED_MAX_ENTRIES>::new(0);
+
+    pub static mut HEAVY_HITTER_CANDIDATES: HashMap<MeterKey, u64, BPF_HEAVY_HITTER_CANDIDATES_MAX_ENTRIES> =
+        HashMap::<MeterKey, u64, BPF_HEAVY_HITTER_CANDIDATES_MAX_ENTRIES>::new();
+
+    pub static mut TIMERS: HashMap<MeterKey, u64, BPF_TIMERS_MAX_ENTRIES> =
+        HashMap::<MeterKey, u64, BPF_TIMERS_MAX_ENTRIES>::new();
+
+    // A monotonic clock which ticks once per call, which tests may advance.
+    pub static KTIME_NS: AtomicU64 = AtomicU64::new(0);
+
+    pub unsafe fn bpf_ktime_get_ns() -> u64 {
+        KTIME_NS.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    thread_local! {
+        // The CPU each test runs on, which tests may change.
+        pub static SMP_PROCESSOR_ID: Cell<u32> = const { Cell::new(0) };
+    }
+
+    pub unsafe fn bpf_get_smp_processor_id() -> u32 {
+        SMP_PROCESSOR_ID.get()
+    }
+}
+
+// Include everything from the `bpf_mocks` module for tests.
+#[cfg(test)]
+use bpf_mocks::*;
+
+#[cfg(test)]
+mod test {
+    use anyhow::Context as _;
+    use aya_metrics_common::{Buckets, CounterStorage, SharedString};
+
+    use super::*;
+
+    #[derive(Copy, Clone, Debug)]
+    enum MockCounter {
+        Test1,
+        Test2,
+    }
+
+    impl Counter for MockCounter {
+        fn name(self) -> SharedString {
+            match self {
+                MockCounter::Test1 => "test1".into(),
+                MockCounter::Test2 => "test2".into(),
+            }
+        }
+
+        fn index(&self) -> u32 {
+            match self {
+                MockCounter::Test1 => 0,
+                MockCounter::Test2 => BPF_COUNTERS_MAX_ENTRIES as u32 - 1,
+            }
+        }
+    }
+
+    #[test]
+    fn test_counter() -> Result<(), anyhow::Error> {
+        let mut expected = [0u64; BPF_COUNTERS_MAX_ENTRIES];
+
+        let actual = unsafe { static_map!(COUNTERS).data.get() };
+        assert_eq!(actual, expected);
+
+        // test adding some numbers
+        counter(MockCounter::Test1, 1);
+        counter(MockCounter::Test2, 42);
+        let actual = unsafe { static_map!(COUNTERS).data.get() };
+        *expected.first_mut().context("no first entry")? = 1;
+        *expected.last_mut().context("no last entry")? = 42;
+        assert_eq!(actual, expected);
+
+        // test adding zero
+        counter(MockCounter::Test1, 0);
+        counter(MockCounter::Test2, 0);
+        let actual = unsafe { static_map!(COUNTERS).data.get() };
+        assert_eq!(actual, expected);
+
+        // test adding again increments existing values
         counter(MockCounter::Test1, 1);
         counter(MockCounter::Test2, 1);
-        let actual = unsafe { COUNTERS.data.get() };
-        *expected.first_mut().unwrap() = 2;
-        *expected.last_mut().unwrap() = 43;
+        let actual = unsafe { static_map!(COUNTERS).data.get() };
+        *expected.first_mut().context("no first entry")? = 2;
+        *expected.last_mut().context("no last entry")? = 43;
+        assert_eq!(actual, expected);
+
+        Ok(())
+    }
+
+    #[derive(Copy, Clone, Debug)]
+    enum MockLibraryCounter {
+        Test1,
+        Test2,
+    }
+
+    impl Counter for MockLibraryCounter {
+        const MAP_NAME: &'static str = "LIBRARY_COUNTERS";
+        const LAYOUT: u64 = 42;
+
+        fn name(self) -> SharedString {
+            match self {
+                MockLibraryCounter::Test1 => "library_test1".into(),
+                MockLibraryCounter::Test2 => "library_test2".into(),
+            }
+        }
+
+        fn index(&self) -> u32 {
+            match self {
+                MockLibraryCounter::Test1 => 0,
+                MockLibraryCounter::Test2 => 1,
+            }
+        }
+    }
+
+    counter_map!(LIBRARY_COUNTERS: MockLibraryCounter);
+
+    #[test]
+    fn test_counter_map() {
+        // test incrementing counters in their own map, which does not touch the default map
+        LIBRARY_COUNTERS.increment(MockLibraryCounter::Test1, 1);
+        LIBRARY_COUNTERS.increment(MockLibraryCounter::Test2, 42);
+        LIBRARY_COUNTERS.increment(MockLibraryCounter::Test2, 1);
+        let actual = LIBRARY_COUNTERS.map.data.get();
+        assert_eq!(actual[..3], [1, 43, 0]);
+    }
+
+    #[derive(Copy, Clone, Debug)]
+    enum MockMmapCounter {
+        Test1,
+        Test2,
+    }
+
+    impl Counter for MockMmapCounter {
+        const MAX_ENTRIES: u32 = 2;
+        const MAP_NAME: &'static str = "MMAP_COUNTERS";
+        const STORAGE: CounterStorage = CounterStorage::Mmap;
+
+        fn name(self) -> SharedString {
+            match self {
+                MockMmapCounter::Test1 => "mmap_test1".into(),
+                MockMmapCounter::Test2 => "mmap_test2".into(),
+            }
+        }
+
+        fn index(&self) -> u32 {
+            match self {
+                MockMmapCounter::Test1 => 0,
+                MockMmapCounter::Test2 => 1,
+            }
+        }
+    }
+
+    mmap_counter_map!(MMAP_COUNTERS: MockMmapCounter);
+
+    #[test]
+    fn test_mmap_counter_map() {
+        // test incrementing counters in the stride of the current CPU
+        MMAP_COUNTERS.increment(MockMmapCounter::Test1, 1);
+        MMAP_COUNTERS.increment(MockMmapCounter::Test2, 42);
+        SMP_PROCESSOR_ID.set(3);
+        MMAP_COUNTERS.increment(MockMmapCounter::Test2, 1);
+        SMP_PROCESSOR_ID.set(0);
+
+        // each CPU has a stride of a whole cache line
+        let actual = MMAP_COUNTERS.map.data.get();
+        assert_eq!(actual[..8], [1, 42, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(actual[24..26], [0, 1]);
+        assert_eq!(actual[8..24], [0; 16]);
+    }
+
+    #[test]
+    fn test_counter_layout() {
+        extern "Rust" {
+            #[link_name = "AYA_METRICS_LAYOUT_LIBRARY_COUNTERS"]
+            static LAYOUT: u64;
+        }
+
+        // test the layout embedded alongside the map
+        // SAFETY: The layout is a u64 declared by `counter_map!`.
+        assert_eq!(unsafe { LAYOUT }, 42);
+    }
+
+    #[test]
+    fn test_str_eq() {
+        assert!(__private::str_eq("COUNTERS", "COUNTERS"));
+        assert!(!__private::str_eq("COUNTERS", "COUNTERS2"));
+        assert!(!__private::str_eq("COUNTERS", "COUNTERZ"));
+    }
+
+    #[derive(Copy, Clone, Debug)]
+    enum MockGauge {
+        Test1,
+        Test2,
+    }
+
+    impl Gauge for MockGauge {
+        fn name(self) -> SharedString {
+            match self {
+                MockGauge::Test1 => "test1".into(),
+                MockGauge::Test2 => "test2".into(),
+            }
+        }
+
+        fn index(&self) -> u32 {
+            match self {
+                MockGauge::Test1 => 0,
+                MockGauge::Test2 => BPF_GAUGES_MAX_ENTRIES as u32 - 1,
+            }
+        }
+    }
+
+    #[test]
+    fn test_gauge() -> Result<(), anyhow::Error> {
+        let actual = unsafe { static_map!(GAUGES).data.get() };
+        assert_eq!(actual, [GaugeValue::default(); BPF_GAUGES_MAX_ENTRIES]);
+
+        // test setting some numbers
+        gauge_set(MockGauge::Test1, 10);
+        gauge_set(MockGauge::Test2, 42);
+        let actual = unsafe { static_map!(GAUGES).data.get() };
+        let (first, last) = (actual.first().context("no first entry")?, actual.last().context("no last entry")?);
+        assert_eq!(first.value, 10);
+        assert_eq!(last.value, 42);
+        assert!(first.timestamp > 0);
+        assert!(last.timestamp > first.timestamp);
+
+        // test setting replaces existing values
+        gauge_set(MockGauge::Test1, 5);
+        let actual = unsafe { static_map!(GAUGES).data.get() };
+        assert_eq!(actual.first().context("no first entry")?.value, 5);
+        assert!(actual.first().context("no first entry")?.timestamp > last.timestamp);
+
+        // test adding increments existing values
+        gauge_add(MockGauge::Test1, 3);
+        gauge_add(MockGauge::Test2, 0);
+        let actual = unsafe { static_map!(GAUGES).data.get() };
+        assert_eq!(actual.first().context("no first entry")?.value, 8);
+        assert_eq!(actual.last().context("no last entry")?.value, 42);
+        assert!(
+            actual.last().context("no last entry")?.timestamp > actual.first().context("no first entry")?.timestamp
+        );
+
+        // test adding a negative value decrements, below zero
+        gauge_add(MockGauge::Test1, -10);
+        let actual = unsafe { static_map!(GAUGES).data.get() };
+        assert_eq!(actual.first().context("no first entry")?.value, -2);
+
+        Ok(())
+    }
+
+    #[derive(Copy, Clone, Debug)]
+    enum MockHistogram {
+        Test1,
+        Test2,
+        Test3,
+        Test4,
+    }
+
+    impl Histogram for MockHistogram {
+        fn name(self) -> SharedString {
+            match self {
+                MockHistogram::Test1 => "test1".into(),
+                MockHistogram::Test2 => "test2".into(),
+                MockHistogram::Test3 => "test3".into(),
+                MockHistogram::Test4 => "test4".into(),
+            }
+        }
+
+        fn index(&self) -> u32 {
+            match self {
+                MockHistogram::Test1 => 0,
+                MockHistogram::Test2 => BPF_HISTOGRAMS_MAX_ENTRIES as u32 - 1,
+                MockHistogram::Test3 => 1,
+                MockHistogram::Test4 => 2,
+            }
+        }
+
+        fn buckets(&self) -> Buckets {
+            match self {
+                MockHistogram::Test1 | MockHistogram::Test2 => Buckets::Log2,
+                MockHistogram::Test3 => Buckets::Linear {
+                    start: 1_000_000,
+                    step: 1_000_000,
+                    count: 10,
+                },
+                MockHistogram::Test4 => Buckets::Explicit(&[100, 1_000, 10_000]),
+            }
+        }
+    }
+
+    #[test]
+    fn test_histogram() -> Result<(), anyhow::Error> {
+        let mut expected = [HistogramValue::EMPTY; BPF_HISTOGRAMS_MAX_ENTRIES];
+
+        let actual = unsafe { static_map!(HISTOGRAMS).data.get() };
         assert_eq!(actual, expected);
+
+        // test recording some numbers
+        histogram(MockHistogram::Test1, 0);
+        histogram(MockHistogram::Test1, 1500);
+        histogram(MockHistogram::Test2, u64::MAX);
+        let actual = unsafe { static_map!(HISTOGRAMS).data.get() };
+        expected[0].buckets[0] = 1;
+        expected[0].buckets[11] = 1;
+        *expected
+            .last_mut()
+            .context("no last entry")?
+            .buckets
+            .last_mut()
+            .context("no last entry")? = 1;
+        assert_eq!(actual, expected);
+
+        // test recording into the same bucket again increments it
+        histogram(MockHistogram::Test1, 1024);
+        let actual = unsafe { static_map!(HISTOGRAMS).data.get() };
+        expected[0].buckets[11] = 2;
+        assert_eq!(actual, expected);
+
+        // test recording into linear buckets
+        histogram(MockHistogram::Test3, 500_000);
+        histogram(MockHistogram::Test3, 2_500_000);
+        histogram(MockHistogram::Test3, 20_000_000);
+        let actual = unsafe { static_map!(HISTOGRAMS).data.get() };
+        expected[1].buckets[0] = 1;
+        expected[1].buckets[2] = 1;
+        expected[1].buckets[10] = 1;
+        assert_eq!(actual, expected);
+
+        // test recording into explicit buckets
+        histogram(MockHistogram::Test4, 100);
+        histogram(MockHistogram::Test4, 101);
+        histogram(MockHistogram::Test4, 10_001);
+        let actual = unsafe { static_map!(HISTOGRAMS).data.get() };
+        expected[2].buckets[0] = 1;
+        expected[2].buckets[1] = 1;
+        expected[2].buckets[3] = 1;
+        assert_eq!(actual, expected);
+
+        // test timers record the elapsed time, which the mock clock keeps small
+        let timer = |tid: u32| MeterKey::new(Histogram::index(&MockHistogram::Test4), &tid);
+        timer_stop(MockHistogram::Test4, 42u32);
+        timer_start(MockHistogram::Test4, 42u32);
+        timer_start(MockHistogram::Test4, 43u32);
+        assert!(unsafe { static_map!(TIMERS).get(&timer(42)) }.is_some());
+        timer_stop(MockHistogram::Test4, 42u32);
+        timer_stop(MockHistogram::Test4, 42u32);
+        let actual = unsafe { static_map!(HISTOGRAMS).data.get() };
+        expected[2].buckets[0] = 2;
+        assert_eq!(actual, expected);
+        assert!(unsafe { static_map!(TIMERS).get(&timer(42)) }.is_none());
+        assert!(unsafe { static_map!(TIMERS).get(&timer(43)) }.is_some());
+
+        // test a timer running longer than the last bound is counted in the last bucket
+        KTIME_NS.fetch_add(20_000, Ordering::Relaxed);
+        timer_stop(MockHistogram::Test4, 43u32);
+        let actual = unsafe { static_map!(HISTOGRAMS).data.get() };
+        expected[2].buckets[3] = 2;
+        assert_eq!(actual, expected);
+        assert!(unsafe { static_map!(TIMERS).get(&timer(43)) }.is_none());
+
+        Ok(())
+    }
+
+    #[derive(Copy, Clone, Debug)]
+    enum MockUpDownCounter {
+        Test1,
+        Test2,
+    }
+
+    impl UpDownCounter for MockUpDownCounter {
+        fn name(self) -> SharedString {
+            match self {
+                MockUpDownCounter::Test1 => "test1".into(),
+                MockUpDownCounter::Test2 => "test2".into(),
+            }
+        }
+
+        fn index(&self) -> u32 {
+            match self {
+                MockUpDownCounter::Test1 => 0,
+                MockUpDownCounter::Test2 => BPF_UP_DOWN_COUNTERS_MAX_ENTRIES as u32 - 1,
+            }
+        }
+    }
+
+    #[test]
+    fn test_up_down_counter() -> Result<(), anyhow::Error> {
+        let mut expected = [0i64; BPF_UP_DOWN_COUNTERS_MAX_ENTRIES];
+
+        let actual = unsafe { static_map!(UP_DOWN_COUNTERS).data.get() };
+        assert_eq!(actual, expected);
+
+        // test incrementing some numbers
+        up_down_counter(MockUpDownCounter::Test1, 1);
+        up_down_counter(MockUpDownCounter::Test2, 42);
+        let actual = unsafe { static_map!(UP_DOWN_COUNTERS).data.get() };
+        *expected.first_mut().context("no first entry")? = 1;
+        *expected.last_mut().context("no last entry")? = 42;
+        assert_eq!(actual, expected);
+
+        // test decrementing below zero
+        up_down_counter(MockUpDownCounter::Test1, -3);
+        up_down_counter(MockUpDownCounter::Test2, -1);
+        let actual = unsafe { static_map!(UP_DOWN_COUNTERS).data.get() };
+        *expected.first_mut().context("no first entry")? = -2;
+        *expected.last_mut().context("no last entry")? = 41;
+        assert_eq!(actual, expected);
+
+        Ok(())
+    }
+
+    #[derive(Copy, Clone, Debug)]
+    enum MockCompoundCounter {
+        Test1,
+        Test2,
+    }
+
+    impl CompoundCounter for MockCompoundCounter {
+        const FIELDS: &'static [&'static str] = &["packets", "bytes", "drops"];
+
+        fn name(self) -> SharedString {
+            match self {
+                MockCompoundCounter::Test1 => "test1".into(),
+                MockCompoundCounter::Test2 => "test2".into(),
+            }
+        }
+
+        fn index(&self) -> u32 {
+            match self {
+                MockCompoundCounter::Test1 => 0,
+                MockCompoundCounter::Test2 => BPF_COMPOUND_COUNTERS_MAX_ENTRIES as u32 - 1,
+            }
+        }
+    }
+
+    #[test]
+    fn test_compound_counter() -> Result<(), anyhow::Error> {
+        let mut expected = [CompoundCounterValue::default(); BPF_COMPOUND_COUNTERS_MAX_ENTRIES];
+
+        let actual = unsafe { static_map!(COMPOUND_COUNTERS).data.get() };
+        assert_eq!(actual, expected);
+
+        // test incrementing every field at once
+        compound_counter(MockCompoundCounter::Test1, [1, 64, 0]);
+        compound_counter(MockCompoundCounter::Test2, [1, 1500, 1]);
+        let actual = unsafe { static_map!(COMPOUND_COUNTERS).data.get() };
+        expected.first_mut().context("no first entry")?.fields = [1, 64, 0, 0];
+        expected.last_mut().context("no last entry")?.fields = [1, 1500, 1, 0];
+        assert_eq!(actual, expected);
+
+        // test incrementing again adds to each field
+        compound_counter(MockCompoundCounter::Test1, [2, 128, 1]);
+        let actual = unsafe { static_map!(COMPOUND_COUNTERS).data.get() };
+        expected.first_mut().context("no first entry")?.fields = [3, 192, 1, 0];
+        assert_eq!(actual, expected);
+
+        Ok(())
+    }
+
+    #[derive(Copy, Clone, Debug)]
+    enum MockWatermark {
+        Test1,
+        Test2,
+    }
+
+    impl Max for MockWatermark {
+        fn name(self) -> SharedString {
+            match self {
+                MockWatermark::Test1 => "test1".into(),
+                MockWatermark::Test2 => "test2".into(),
+            }
+        }
+
+        fn index(&self) -> u32 {
+            match self {
+                MockWatermark::Test1 => 0,
+                MockWatermark::Test2 => BPF_MAXES_MAX_ENTRIES as u32 - 1,
+            }
+        }
+    }
+
+    impl Min for MockWatermark {
+        fn name(self) -> SharedString {
+            Max::name(self)
+        }
+
+        fn index(&self) -> u32 {
+            Max::index(self)
+        }
+    }
+
+    #[test]
+    fn test_max_and_min() -> Result<(), anyhow::Error> {
+        let mut expected = [WatermarkValue::default(); BPF_MAXES_MAX_ENTRIES];
+
+        let actual = unsafe { static_map!(MAXES).data.get() };
+        assert_eq!(actual, expected);
+        let actual = unsafe { static_map!(MINS).data.get() };
+        assert_eq!(actual, expected);
+
+        // test recording some numbers
+        for value in [5, 9, 2] {
+            max(MockWatermark::Test1, value);
+            min(MockWatermark::Test1, value);
+        }
+        max(MockWatermark::Test2, 0);
+        min(MockWatermark::Test2, 0);
+
+        let actual = unsafe { static_map!(MAXES).data.get() };
+        *expected.first_mut().context("no first entry")? = WatermarkValue { value: 9, count: 3 };
+        *expected.last_mut().context("no last entry")? = WatermarkValue { value: 0, count: 1 };
+        assert_eq!(actual, expected);
+
+        let actual = unsafe { static_map!(MINS).data.get() };
+        *expected.first_mut().context("no first entry")? = WatermarkValue { value: 2, count: 3 };
+        assert_eq!(actual, expected);
+
+        // test a recorded zero is kept, unlike an unset value
+        min(MockWatermark::Test2, 7);
+        let actual = unsafe { static_map!(MINS).data.get() };
+        assert_eq!(actual.last().context("no last entry")?, &WatermarkValue { value: 0, count: 2 });
+
+        Ok(())
+    }
+
+    #[derive(Copy, Clone, Debug)]
+    enum MockDistinctCount {
+        Test1,
+        Test2,
+    }
+
+    impl DistinctCount for MockDistinctCount {
+        fn name(self) -> SharedString {
+            match self {
+                MockDistinctCount::Test1 => "test1".into(),
+                MockDistinctCount::Test2 => "test2".into(),
+            }
+        }
+
+        fn index(&self) -> u32 {
+            match self {
+                MockDistinctCount::Test1 => 0,
+                MockDistinctCount::Test2 => BPF_DISTINCT_COUNTS_MAX_ENTRIES as u32 - 1,
+            }
+        }
+    }
+
+    #[test]
+    fn test_distinct_count() -> Result<(), anyhow::Error> {
+        let mut expected = [HyperLogLogValue::EMPTY; BPF_DISTINCT_COUNTS_MAX_ENTRIES];
+
+        let actual = unsafe { static_map!(DISTINCT_COUNTS).data.get() };
+        assert_eq!(actual, expected);
+
+        // test recording some values, where duplicates do not change the registers
+        for addr in [[10u8, 0, 0, 1], [10u8, 0, 0, 2], [10u8, 0, 0, 1]] {
+            distinct_count(MockDistinctCount::Test1, addr);
+        }
+        distinct_count(MockDistinctCount::Test2, 42u64);
+        let actual = unsafe { static_map!(DISTINCT_COUNTS).data.get() };
+        expected[0].record(hll_hash(&[10u8, 0, 0, 1]));
+        expected[0].record(hll_hash(&[10u8, 0, 0, 2]));
+        expected.last_mut().context("no last entry")?.record(hll_hash(&42u64));
+        assert_eq!(actual, expected);
+        assert_eq!(actual[0].estimate().round(), 2.0);
+
+        Ok(())
+    }
+
+    #[derive(Copy, Clone, Debug)]
+    enum MockKeyedCounter {
+        Test1,
+        Test2,
+    }
+
+    impl KeyedCounter for MockKeyedCounter {
+        fn name(self) -> SharedString {
+            match self {
+                MockKeyedCounter::Test1 => "test1".into(),
+                MockKeyedCounter::Test2 => "test2".into(),
+            }
+        }
+
+        fn index(&self) -> u32 {
+            match self {
+                MockKeyedCounter::Test1 => 0,
+                MockKeyedCounter::Test2 => 1,
+            }
+        }
+    }
+
+    #[test]
+    fn test_keyed_counter() {
+        let get = |counter: MockKeyedCounter, key: [u8; 4]| unsafe {
+            static_map!(KEYED_COUNTERS).get(&MeterKey::new(KeyedCounter::index(&counter), &key))
+        };
+
+        assert_eq!(get(MockKeyedCounter::Test1, [10, 0, 0, 1]), None);
+
+        // test inserting some keys
+        keyed_counter(MockKeyedCounter::Test1, [10u8, 0, 0, 1], 1);
+        keyed_counter(MockKeyedCounter::Test1, [10u8, 0, 0, 2], 42);
+        keyed_counter(MockKeyedCounter::Test2, [10u8, 0, 0, 1], 7);
+        assert_eq!(get(MockKeyedCounter::Test1, [10, 0, 0, 1]), Some(1));
+        assert_eq!(get(MockKeyedCounter::Test1, [10, 0, 0, 2]), Some(42));
+        assert_eq!(get(MockKeyedCounter::Test2, [10, 0, 0, 1]), Some(7));
+        assert_eq!(get(MockKeyedCounter::Test2, [10, 0, 0, 2]), None);
+
+        // test adding again increments existing values
+        keyed_counter(MockKeyedCounter::Test1, [10u8, 0, 0, 1], 2);
+        keyed_counter(MockKeyedCounter::Test2, [10u8, 0, 0, 1], 0);
+        assert_eq!(get(MockKeyedCounter::Test1, [10, 0, 0, 1]), Some(3));
+        assert_eq!(get(MockKeyedCounter::Test2, [10, 0, 0, 1]), Some(7));
+
+        // test keys are dropped once the map is full
+        for key in 0..BPF_KEYED_COUNTERS_MAX_ENTRIES as u32 {
+            keyed_counter(MockKeyedCounter::Test2, key.to_be_bytes(), 1);
+        }
+        assert_eq!(get(MockKeyedCounter::Test2, 0u32.to_be_bytes()), Some(1));
+        let last = BPF_KEYED_COUNTERS_MAX_ENTRIES as u32 - 1;
+        assert_eq!(get(MockKeyedCounter::Test2, last.to_be_bytes()), None);
+        assert_eq!(get(MockKeyedCounter::Test1, [10, 0, 0, 1]), Some(3));
+
+        // test dropped increments are counted for their keyed counter, the map already held three keys
+        keyed_counter(MockKeyedCounter::Test2, last.to_be_bytes(), 1);
+        let dropped = unsafe { static_map!(KEYED_COUNTERS_DROPPED).data.get() };
+        assert_eq!(dropped[0], 0);
+        assert_eq!(dropped[1], 4);
+    }
+
+    #[derive(Copy, Clone, Debug)]
+    enum MockHeavyHitter {
+        Test1,
+        Test2,
+    }
+
+    impl HeavyHitter for MockHeavyHitter {
+        fn name(self) -> SharedString {
+            match self {
+                MockHeavyHitter::Test1 => "test1".into(),
+                MockHeavyHitter::Test2 => "test2".into(),
+            }
+        }
+
+        fn index(&self) -> u32 {
+            match self {
+                MockHeavyHitter::Test1 => 0,
+                MockHeavyHitter::Test2 => BPF_HEAVY_HITTERS_MAX_ENTRIES as u32 - 1,
+            }
+        }
+
+        fn threshold(&self) -> u64 {
+            match self {
+                MockHeavyHitter::Test1 => 1,
+                MockHeavyHitter::Test2 => 10,
+            }
+        }
+    }
+
+    #[test]
+    fn test_heavy_hitter() -> Result<(), anyhow::Error> {
+        let get = |meter: MockHeavyHitter, key: u32| unsafe {
+            static_map!(HEAVY_HITTER_CANDIDATES).get(&MeterKey::new(HeavyHitter::index(&meter), &key))
+        };
+
+        assert_eq!(get(MockHeavyHitter::Test1, 1), None);
+
+        // test counting some keys
+        heavy_hitter(MockHeavyHitter::Test1, 1u32, 5);
+        heavy_hitter(MockHeavyHitter::Test1, 2u32, 1);
+        heavy_hitter(MockHeavyHitter::Test1, 1u32, 2);
+        assert_eq!(get(MockHeavyHitter::Test1, 1), Some(7));
+        assert_eq!(get(MockHeavyHitter::Test1, 2), Some(1));
+
+        // test keys are only candidates once they reach the threshold
+        heavy_hitter(MockHeavyHitter::Test2, 1u32, 9);
+        assert_eq!(get(MockHeavyHitter::Test2, 1), None);
+        heavy_hitter(MockHeavyHitter::Test2, 1u32, 1);
+        assert_eq!(get(MockHeavyHitter::Test2, 1), Some(10));
+
+        // test each heavy hitter has its own sketch
+        let sketches = unsafe { static_map!(HEAVY_HITTERS).data.get() };
+        let total = |sketch: &CountMinSketch| sketch.counts[0].iter().sum::<u64>();
+        assert_eq!(total(&sketches[0]), 8);
+        assert_eq!(total(sketches.last().context("no last entry")?), 10);
+
+        Ok(())
     }
 }
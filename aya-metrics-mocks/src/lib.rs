@@ -8,8 +8,14 @@
 
 // GRCOV_STOP_COVERAGE
 
+use std::any::Any;
 use std::collections::HashMap;
+use std::fs::File;
 use std::hash::Hash;
+use std::io;
+use std::os::fd::{BorrowedFd, OwnedFd};
+use std::os::unix::{fs::MetadataExt, net::UnixDatagram};
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 
 use std::borrow::{Borrow, Cow};
@@ -17,16 +23,36 @@ use std::borrow::{Borrow, Cow};
 use aya::maps::lpm_trie::Key;
 use aya::{
     maps::{MapError, PerCpuValues},
+    pin::PinError,
     programs::Program,
+    sys::SyscallError,
     util::nr_cpus,
     Btf, EbpfError, Pod, VerifierLogLevel,
 };
 
-pub struct Map {}
+/// The number of entries of maps which were not resized with [`EbpfLoader::set_max_entries`].
+pub const DEFAULT_MAX_ENTRIES: u32 = 64;
+
+#[derive(Clone)]
+pub struct Map {
+    max_entries: u32,
+    data: Option<Vec<u8>>,
+}
+
+/// Mock maps are not typed, so the data of a map is the map itself.
+pub type MapData = Map;
 
 pub struct EbpfLoader<'a> {
     btf: Option<Cow<'a, Btf>>,
     verifier_log_level: VerifierLogLevel,
+    max_entries: HashMap<&'a str, u32>,
+    sections: HashMap<&'a str, Vec<u8>>,
+}
+
+impl Default for EbpfLoader<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl<'a> EbpfLoader<'a> {
@@ -34,11 +60,19 @@ impl<'a> EbpfLoader<'a> {
         Self {
             btf: Btf::from_sys_fs().ok().map(Cow::Owned),
             verifier_log_level: VerifierLogLevel::default(),
+            max_entries: HashMap::new(),
+            sections: HashMap::new(),
         }
     }
 
-    pub fn load(&mut self, data: &[u8]) -> Result<Ebpf, EbpfError> {
-        Ebpf::load(&[])
+    pub fn load(&mut self, _data: &[u8]) -> Result<Ebpf, EbpfError> {
+        let max_entries = self.max_entries.iter().map(|(name, size)| (name.to_string(), *size)).collect();
+        let sections = self
+            .sections
+            .iter()
+            .map(|(name, data)| (name.to_string(), data.clone()))
+            .collect();
+        Ok(Ebpf { max_entries, sections })
     }
 
     pub fn btf(&mut self, btf: Option<&'a Btf>) -> &mut Self {
@@ -52,15 +86,27 @@ impl<'a> EbpfLoader<'a> {
     }
 
     pub fn set_max_entries(&mut self, name: &'a str, size: u32) -> &mut Self {
+        self.max_entries.insert(name, size);
+        self
+    }
+
+    /// Mimics a `.rodata` section of the eBPF object, which is loaded as a single entry array named by the section.
+    ///
+    /// This is not part of the Aya API, which reads sections from the object itself.
+    pub fn set_section(&mut self, name: &'a str, data: &[u8]) -> &mut Self {
+        self.sections.insert(name, data.to_vec());
         self
     }
 }
 
-pub struct Ebpf {}
+pub struct Ebpf {
+    max_entries: HashMap<String, u32>,
+    sections: HashMap<String, Vec<u8>>,
+}
 
 impl Ebpf {
-    pub fn load(_data: &[u8]) -> Result<Ebpf, EbpfError> {
-        Ok(Self {})
+    pub fn load(data: &[u8]) -> Result<Ebpf, EbpfError> {
+        EbpfLoader::new().load(data)
     }
 
     pub fn program_mut(&mut self, _name: &str) -> Option<&mut Program> {
@@ -71,9 +117,87 @@ impl Ebpf {
         None
     }
 
-    pub fn take_map(&mut self, _name: &str) -> Option<Map> {
-        Some(Map {})
+    pub fn take_map(&mut self, name: &str) -> Option<Map> {
+        // Only the sections set with `EbpfLoader::set_section` exist
+        if name.starts_with(".rodata") {
+            let data = self.sections.remove(name)?;
+            return Some(Map {
+                max_entries: 1,
+                data: Some(data),
+            });
+        }
+        let max_entries = self.max_entries.get(name).copied().unwrap_or(DEFAULT_MAX_ENTRIES);
+        Some(Map {
+            max_entries,
+            data: None,
+        })
+    }
+}
+
+/// Maps pinned with `pin`, which are shared by the whole process as pinned maps are shared by every process.
+static PINNED: Mutex<Vec<(PathBuf, Box<dyn Any + Send>)>> = Mutex::new(Vec::new());
+
+fn pin<T: Any + Send>(path: &Path, map: T) -> Result<(), PinError> {
+    let mut pinned = PINNED.lock().unwrap();
+    if pinned.iter().any(|(pinned_path, _)| pinned_path == path) {
+        return Err(PinError::SyscallError(SyscallError {
+            call: "BPF_OBJ_PIN",
+            io_error: io::ErrorKind::AlreadyExists.into(),
+        }));
     }
+    pinned.push((path.to_path_buf(), Box::new(map)));
+    Ok(())
+}
+
+/// Open a map pinned with `pin`, which shares its values with the map pinned.
+///
+/// This is not part of the Aya API, where a pinned map is opened with `MapData::from_pin`.
+pub fn from_pin<T: Any + Clone, P: AsRef<Path>>(path: P) -> Result<T, MapError> {
+    let pinned = PINNED.lock().unwrap();
+    let (_, map) = pinned
+        .iter()
+        .find(|(pinned_path, _)| pinned_path == path.as_ref())
+        .ok_or_else(|| SyscallError {
+            call: "BPF_OBJ_GET",
+            io_error: io::ErrorKind::NotFound.into(),
+        })?;
+    map.downcast_ref::<T>().cloned().ok_or(MapError::InvalidMapType { map_type: 0 })
+}
+
+/// The device and inode of a file, which are shared by every file descriptor referring to it.
+type Inode = (u64, u64);
+
+/// Maps referred to by file descriptors from `fd`, keyed by the file the file descriptors refer to.
+static FDS: Mutex<Vec<(Inode, Box<dyn Any + Send>)>> = Mutex::new(Vec::new());
+
+fn fd<T: Any + Send>(map: T) -> io::Result<OwnedFd> {
+    // Any file will do, as long as it has an inode of its own
+    let fd = OwnedFd::from(UnixDatagram::unbound()?);
+    FDS.lock().unwrap().push((inode(fd.try_clone()?)?, Box::new(map)));
+    Ok(fd)
+}
+
+fn inode(fd: OwnedFd) -> io::Result<Inode> {
+    let metadata = File::from(fd).metadata()?;
+    Ok((metadata.dev(), metadata.ino()))
+}
+
+/// Open a map referred to by a file descriptor from `fd`, which shares its values with the map.
+///
+/// This is not part of the Aya API, where a map is opened from a file descriptor with `MapData::from_fd`.
+pub fn from_fd<T: Any + Clone>(fd: BorrowedFd<'_>) -> Result<T, MapError> {
+    // Any other file is not a map, as with `bpf_obj_get_info_by_fd`
+    let not_a_map = |io_error| SyscallError {
+        call: "bpf_obj_get_info_by_fd",
+        io_error,
+    };
+    let inode = inode(fd.try_clone_to_owned().map_err(not_a_map)?).map_err(not_a_map)?;
+    let fds = FDS.lock().unwrap();
+    let (_, map) = fds
+        .iter()
+        .find(|(map_inode, _)| *map_inode == inode)
+        .ok_or_else(|| not_a_map(io::ErrorKind::InvalidInput.into()))?;
+    map.downcast_ref::<T>().cloned().ok_or(MapError::InvalidMapType { map_type: 0 })
 }
 
 pub struct EbpfLogger;
@@ -92,7 +216,7 @@ pub struct PerCpuArray<V: Pod> {
 
 impl<V: Pod> PerCpuArray<V> {
     pub fn new(len: usize, val: V) -> Self {
-        let arr = vec![val.clone(); nr_cpus().unwrap()];
+        let arr = vec![val; nr_cpus().unwrap()];
         PerCpuArray {
             inner: Arc::new(Mutex::new(vec![arr.clone(); len])),
             _v: core::marker::PhantomData,
@@ -100,15 +224,36 @@ impl<V: Pod> PerCpuArray<V> {
     }
 }
 
-impl TryFrom<Map> for PerCpuArray<u64> {
+impl<V: Pod + Default> TryFrom<Map> for PerCpuArray<V> {
     type Error = MapError;
 
-    fn try_from(_map: Map) -> Result<PerCpuArray<u64>, MapError> {
-        Ok(PerCpuArray::new(1, 0u64))
+    fn try_from(map: Map) -> Result<PerCpuArray<V>, MapError> {
+        Ok(PerCpuArray::new(map.max_entries as usize, V::default()))
+    }
+}
+
+impl<V: Pod + Send> PerCpuArray<V> {
+    pub fn pin<P: AsRef<Path>>(self, path: P) -> Result<(), PinError> {
+        pin(path.as_ref(), self)
+    }
+
+    /// A file descriptor referring to the map, which is opened with `from_fd`.
+    ///
+    /// This is not part of the Aya API, where the file descriptor of a map is held by its `MapData`.
+    pub fn fd(&self) -> io::Result<OwnedFd> {
+        fd(self.clone())
     }
 }
 
 impl<V: Pod> PerCpuArray<V> {
+    pub fn len(&self) -> u32 {
+        self.inner.lock().unwrap().len() as u32
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
     pub fn get(&self, index: &u32, _flags: u64) -> Result<PerCpuValues<V>, MapError> {
         let guard = self.inner.lock().unwrap();
         let values = guard.get(*index as usize).ok_or(MapError::OutOfBounds {
@@ -119,15 +264,95 @@ impl<V: Pod> PerCpuArray<V> {
     }
 
     pub fn set(&mut self, index: u32, values: PerCpuValues<V>, _flags: u64) -> Result<(), MapError> {
-        let arr = (0..nr_cpus().unwrap())
-            .map(|i| values.get(i).unwrap().clone())
-            .collect::<Vec<V>>();
+        let arr = (0..nr_cpus().unwrap()).map(|i| *values.get(i).unwrap()).collect::<Vec<V>>();
         let mut guard = self.inner.lock().unwrap();
         guard[index as usize] = arr;
         Ok(())
     }
 }
 
+/// Keys along with their values per CPU, in insertion order.
+type PerCpuEntries<K, V> = Vec<(K, Vec<V>)>;
+
+#[derive(Clone)]
+pub struct PerCpuHashMap<K: Pod + PartialEq, V: Pod> {
+    inner: Arc<Mutex<PerCpuEntries<K, V>>>,
+}
+
+impl<K: Pod + PartialEq, V: Pod> Default for PerCpuHashMap<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Pod + PartialEq, V: Pod> PerCpuHashMap<K, V> {
+    pub fn new() -> Self {
+        PerCpuHashMap {
+            inner: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<K: Pod + PartialEq, V: Pod> TryFrom<Map> for PerCpuHashMap<K, V> {
+    type Error = MapError;
+
+    fn try_from(_map: Map) -> Result<PerCpuHashMap<K, V>, MapError> {
+        Ok(PerCpuHashMap::new())
+    }
+}
+
+impl<K: Pod + PartialEq, V: Pod> PerCpuHashMap<K, V> {
+    pub fn get(&self, key: &K, _flags: u64) -> Result<PerCpuValues<V>, MapError> {
+        let guard = self.inner.lock().unwrap();
+        let (_, values) = guard.iter().find(|(k, _)| k == key).ok_or(MapError::KeyNotFound)?;
+        PerCpuValues::try_from(values.clone()).map_err(|_| MapError::KeyNotFound)
+    }
+
+    pub fn insert(&mut self, key: impl Borrow<K>, values: PerCpuValues<V>, _flags: u64) -> Result<(), MapError> {
+        let key = *key.borrow();
+        let arr = (0..nr_cpus().unwrap()).map(|i| *values.get(i).unwrap()).collect::<Vec<V>>();
+        let mut guard = self.inner.lock().unwrap();
+        match guard.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, values)) => *values = arr,
+            None => guard.push((key, arr)),
+        }
+        Ok(())
+    }
+
+    pub fn remove(&mut self, key: &K) -> Result<(), MapError> {
+        let mut guard = self.inner.lock().unwrap();
+        let position = guard.iter().position(|(k, _)| k == key).ok_or(MapError::KeyNotFound)?;
+        guard.remove(position);
+        Ok(())
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = Result<K, MapError>> {
+        let guard = self.inner.lock().unwrap();
+        guard.iter().map(|(k, _)| Ok(*k)).collect::<Vec<_>>().into_iter()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Result<(K, PerCpuValues<V>), MapError>> {
+        let guard = self.inner.lock().unwrap();
+        guard
+            .iter()
+            .map(|(k, values)| {
+                PerCpuValues::try_from(values.clone())
+                    .map(|values| (*k, values))
+                    .map_err(|_| MapError::KeyNotFound)
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub struct LpmTrie<K: Eq + Hash, V: Eq + Copy> {
     // shameless plug, doesn't actually use a trie or suport LPM
@@ -136,6 +361,12 @@ pub struct LpmTrie<K: Eq + Hash, V: Eq + Copy> {
     _v: std::marker::PhantomData<V>,
 }
 
+impl<K: Eq + Hash + Pod, V: Eq + Copy + Pod> Default for LpmTrie<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<K: Eq + Hash + Pod, V: Eq + Copy + Pod> LpmTrie<K, V> {
     pub fn new() -> Self {
         Self {
@@ -167,49 +398,74 @@ impl<K: Eq + Hash + Pod, V: Eq + Copy + Pod> LpmTrie<K, V> {
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone)]
 pub struct Array<V: Pod> {
-    inner: Vec<V>,
-    size: usize,
-    _v: core::marker::PhantomData<V>,
+    inner: Arc<Mutex<Vec<V>>>,
 }
 
 impl<V: Pod + Default> Array<V> {
     pub fn new(size: usize) -> Self {
         Self {
-            inner: vec![Default::default(); size],
-            size,
-            _v: core::marker::PhantomData,
+            inner: Arc::new(Mutex::new(vec![Default::default(); size])),
         }
     }
+}
+
+impl<V: Pod + Send> Array<V> {
+    pub fn pin<P: AsRef<Path>>(self, path: P) -> Result<(), PinError> {
+        pin(path.as_ref(), self)
+    }
+
+    /// A file descriptor referring to the map, which is opened with `from_fd`.
+    ///
+    /// This is not part of the Aya API, where the file descriptor of a map is held by its `MapData`.
+    pub fn fd(&self) -> io::Result<OwnedFd> {
+        fd(self.clone())
+    }
+}
+
+impl<V: Pod> Array<V> {
+    pub fn len(&self) -> u32 {
+        self.inner.lock().unwrap().len() as u32
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 
     pub fn set(&mut self, index: u32, value: impl Borrow<V>, _flags: u64) -> Result<(), MapError> {
-        let ind: usize = index.try_into().map_err(|_| MapError::KeyNotFound)?;
-        if ind >= self.size {
-            return Err(MapError::OutOfBounds {
-                index,
-                max_entries: self.size as u32,
-            });
-        }
-        self.inner[ind] = value.borrow().clone();
+        let mut guard = self.inner.lock().unwrap();
+        let max_entries = guard.len() as u32;
+        let entry = guard
+            .get_mut(index as usize)
+            .ok_or(MapError::OutOfBounds { index, max_entries })?;
+        *entry = *value.borrow();
         Ok(())
     }
 
     pub fn get(&self, index: &u32, _flags: u64) -> Result<V, MapError> {
-        let ind: usize = (*index).try_into().map_err(|_| MapError::KeyNotFound)?;
-        if ind >= self.size {
-            return Err(MapError::OutOfBounds {
-                index: *index,
-                max_entries: self.size as u32,
-            });
-        }
-        Ok(self.inner[ind].clone())
+        let guard = self.inner.lock().unwrap();
+        guard.get(*index as usize).copied().ok_or(MapError::OutOfBounds {
+            index: *index,
+            max_entries: guard.len() as u32,
+        })
     }
 }
 
 impl<V: Pod + Default> TryFrom<Map> for Array<V> {
     type Error = MapError;
-    fn try_from(_value: Map) -> Result<Array<V>, MapError> {
-        Ok(Array::new(1))
+    fn try_from(value: Map) -> Result<Array<V>, MapError> {
+        let mut array = Array::new(value.max_entries as usize);
+        if let Some(data) = value.data {
+            if data.len() != std::mem::size_of::<V>() {
+                return Err(MapError::InvalidValueSize {
+                    size: data.len(),
+                    expected: std::mem::size_of::<V>(),
+                });
+            }
+            // SAFETY: V is Pod and the data has its size.
+            array.set(0, unsafe { std::ptr::read_unaligned(data.as_ptr().cast::<V>()) }, 0)?;
+        }
+        Ok(array)
     }
 }
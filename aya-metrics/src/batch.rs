@@ -0,0 +1,311 @@
+//! Reads many entries of a BPF per CPU array at once.
+
+use std::io;
+
+use aya::{maps::MapError, sys::SyscallError};
+use aya_obj::generated::{bpf_attr, bpf_attr__bindgen_ty_3};
+
+use crate::PerCpuArray;
+
+/// `ENOTSUPP`, returned by kernels where a map does not support batch operations.
+const ENOTSUPP: i32 = 524;
+
+/// Reads a range of entries of a per CPU array with `BPF_MAP_LOOKUP_BATCH`, reusing its buffers between reads.
+///
+/// Kernels without batch operations, before 5.6, fall back to a lookup per entry.
+pub(crate) struct BatchReader {
+    /// The first index to read.
+    start: u32,
+    /// The number of possible CPUs, each of which has a value for every entry.
+    cpu_count: usize,
+    /// The indices of the entries read, filled by the kernel.
+    keys: Vec<u32>,
+    /// The value of every CPU for each entry read, one entry after another.
+    values: Vec<u64>,
+    /// The number of entries read by the last read.
+    read: usize,
+    /// Whether batch lookups are supported, until the kernel rejects one.
+    batch: bool,
+}
+
+impl BatchReader {
+    /// Create a reader of every entry from the lowest to the highest of the indices.
+    pub(crate) fn new(indices: impl IntoIterator<Item = u32>, cpu_count: usize) -> Self {
+        let (start, end) = indices
+            .into_iter()
+            .fold(None, |range, index| match range {
+                None => Some((index, index)),
+                Some((start, end)) => Some((u32::min(start, index), u32::max(end, index))),
+            })
+            .map_or((0, 0), |(start, end)| (start, end + 1));
+        let count = (end - start) as usize;
+
+        BatchReader {
+            start,
+            cpu_count,
+            keys: vec![0; count],
+            values: vec![0; count * cpu_count],
+            read: 0,
+            batch: true,
+        }
+    }
+
+    /// Read every entry from the map into the buffers, replacing the values of the previous read.
+    pub(crate) fn read(&mut self, map: &PerCpuArray<u64>) -> Result<(), MapError> {
+        self.read_with(map, lookup_batch)
+    }
+
+    /// Read every entry from the map, with `lookup_batch` until the kernel rejects batches.
+    fn read_with(
+        &mut self,
+        map: &PerCpuArray<u64>,
+        lookup_batch: impl FnOnce(&PerCpuArray<u64>, u32, &mut [u32], &mut [u64]) -> io::Result<usize>,
+    ) -> Result<(), MapError> {
+        if self.keys.is_empty() {
+            return Ok(());
+        }
+
+        if self.batch {
+            match lookup_batch(map, self.start, &mut self.keys, &mut self.values) {
+                Ok(read) => {
+                    self.read = read;
+                    return Ok(());
+                }
+                // Remember the kernel does not support batches, so it is only tried once
+                Err(io_error) if is_unsupported(&io_error) => self.batch = false,
+                Err(io_error) => {
+                    return Err(MapError::SyscallError(SyscallError {
+                        call: "bpf_map_lookup_batch",
+                        io_error,
+                    }))
+                }
+            }
+        }
+
+        // Fall back to a lookup per entry
+        for (i, values) in self.values.chunks_exact_mut(self.cpu_count).enumerate() {
+            let index = self.start + i as u32;
+            values.copy_from_slice(&map.get(&index, 0)?);
+            self.keys[i] = index;
+        }
+        self.read = self.keys.len();
+        Ok(())
+    }
+
+    /// The value of every CPU at an index, as of the last read.
+    pub(crate) fn get(&self, index: u32) -> Option<&[u64]> {
+        let i = index.checked_sub(self.start)? as usize;
+        if i >= self.read {
+            return None;
+        }
+        self.values.chunks_exact(self.cpu_count).nth(i)
+    }
+}
+
+/// Whether an error means the kernel or the map does not support batch operations.
+fn is_unsupported(io_error: &io::Error) -> bool {
+    matches!(io_error.raw_os_error(), Some(libc::EINVAL | libc::EOPNOTSUPP | ENOTSUPP))
+}
+
+/// The key a batch lookup of the entries from `start` continues after, or `None` to start from the first key.
+fn in_batch(start: u32) -> Option<u32> {
+    start.checked_sub(1)
+}
+
+/// The attributes of `BPF_MAP_LOOKUP_BATCH`, reading `keys.len()` entries of a map after the key `in_batch`.
+///
+/// The pointers to the cursors and buffers must outlive the syscall, which writes the number of entries read to the
+/// count of the attributes.
+///
+/// Aya has no batch operations, so this is the only place building their attributes. The type of the `batch` field is
+/// named by bindgen, as `bpf_attr__bindgen_ty_3` in aya-obj 0.2.1, and may be renamed by any later aya-obj release.
+// Mock maps are looked up without any attributes
+#[cfg_attr(feature = "mocks", allow(dead_code))]
+fn lookup_batch_attr(
+    map_fd: u32,
+    in_batch: Option<&u32>,
+    out_batch: &mut u32,
+    keys: &mut [u32],
+    values: &mut [u64],
+) -> bpf_attr {
+    // SAFETY: The attributes are plain data, for which zero is a valid value.
+    let mut attr: bpf_attr = unsafe { std::mem::zeroed() };
+    attr.batch = bpf_attr__bindgen_ty_3 {
+        in_batch: in_batch.map_or(0, |key| key as *const u32 as u64),
+        out_batch: out_batch as *mut u32 as u64,
+        keys: keys.as_mut_ptr() as u64,
+        values: values.as_mut_ptr() as u64,
+        count: keys.len() as u32,
+        map_fd,
+        elem_flags: 0,
+        flags: 0,
+    };
+    attr
+}
+
+/// Look up consecutive entries of a per CPU array from `start` in a single syscall, returning the number read.
+#[cfg(not(feature = "mocks"))]
+fn lookup_batch(map: &PerCpuArray<u64>, start: u32, keys: &mut [u32], values: &mut [u64]) -> io::Result<usize> {
+    use std::{
+        mem,
+        os::fd::{AsFd, AsRawFd},
+    };
+
+    use aya::maps::IterableMap;
+    use aya_obj::generated::bpf_cmd;
+
+    let in_batch = in_batch(start);
+    let mut out_batch = 0u32;
+    let map_fd = map.map().fd().as_fd().as_raw_fd() as u32;
+    let mut attr = lookup_batch_attr(map_fd, in_batch.as_ref(), &mut out_batch, keys, values);
+
+    // SAFETY: The buffers hold `count` keys and `count` values for every possible CPU, where each u64 value is
+    // already aligned to 8 bytes as the kernel requires.
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_bpf,
+            bpf_cmd::BPF_MAP_LOOKUP_BATCH,
+            &mut attr as *mut bpf_attr,
+            mem::size_of::<bpf_attr>(),
+        )
+    };
+    // SAFETY: The kernel updates the count of the batch attributes written above.
+    let read = unsafe { attr.batch.count } as usize;
+
+    match ret {
+        0.. => Ok(read),
+        _ => match io::Error::last_os_error() {
+            // The end of the map was reached, after reading any entries before it
+            io_error if io_error.raw_os_error() == Some(libc::ENOENT) => Ok(read),
+            io_error => Err(io_error),
+        },
+    }
+}
+
+/// Look up consecutive entries of a mock per CPU array from `start`, returning the number read.
+#[cfg(feature = "mocks")]
+fn lookup_batch(map: &PerCpuArray<u64>, start: u32, keys: &mut [u32], values: &mut [u64]) -> io::Result<usize> {
+    let cpu_count = values.len() / keys.len();
+    // Continue after the same key as the kernel would
+    let first = in_batch(start).map_or(0, |key| key + 1);
+    for (i, key) in keys.iter_mut().enumerate() {
+        let index = first + i as u32;
+        let entry = map.get(&index, 0).map_err(|_| io::Error::from_raw_os_error(libc::EFAULT))?;
+        values[i * cpu_count..][..cpu_count].copy_from_slice(&entry);
+        *key = index;
+    }
+    Ok(keys.len())
+}
+
+// GRCOV_STOP_COVERAGE
+#[cfg(test)]
+mod test {
+    use aya::{maps::PerCpuValues, util::nr_cpus};
+    use aya_metrics_mocks::PerCpuArray;
+
+    use super::*;
+
+    #[test]
+    fn test_read() -> Result<(), anyhow::Error> {
+        let cpu_count = nr_cpus().map_err(|(_, err)| err)?;
+        let mut map = PerCpuArray::new(8, 0u64);
+        for index in 0..8 {
+            map.set(index, PerCpuValues::try_from(vec![index as u64 * 10; cpu_count])?, 0)?;
+        }
+
+        // Only the entries between the lowest and highest indices are read
+        let mut reader = BatchReader::new([5, 2, 3], cpu_count);
+        assert_eq!(reader.get(2), None);
+        reader.read(&map)?;
+        assert_eq!(reader.get(1), None);
+        assert_eq!(reader.get(2), Some(&vec![20; cpu_count][..]));
+        assert_eq!(reader.get(4), Some(&vec![40; cpu_count][..]));
+        assert_eq!(reader.get(5), Some(&vec![50; cpu_count][..]));
+        assert_eq!(reader.get(6), None);
+
+        // The buffers are reused by later reads
+        map.set(2, PerCpuValues::try_from(vec![21; cpu_count])?, 0)?;
+        reader.read(&map)?;
+        assert_eq!(reader.get(2), Some(&vec![21; cpu_count][..]));
+
+        // Reading each entry gives the same values
+        reader.batch = false;
+        map.set(5, PerCpuValues::try_from(vec![51; cpu_count])?, 0)?;
+        reader.read(&map)?;
+        assert_eq!(reader.get(2), Some(&vec![21; cpu_count][..]));
+        assert_eq!(reader.get(5), Some(&vec![51; cpu_count][..]));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_nothing() -> Result<(), anyhow::Error> {
+        let mut reader = BatchReader::new([], 1);
+        reader.read(&PerCpuArray::new(0, 0u64))?;
+        assert_eq!(reader.get(0), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_falls_back() -> Result<(), anyhow::Error> {
+        let cpu_count = nr_cpus().map_err(|(_, err)| err)?;
+        let mut map = PerCpuArray::new(4, 0u64);
+        map.set(1, PerCpuValues::try_from(vec![10; cpu_count])?, 0)?;
+
+        // Kernels without batch operations fall back to a lookup per entry from then on
+        let mut reader = BatchReader::new([1, 2], cpu_count);
+        reader.read_with(&map, |_, _, _, _| Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP)))?;
+        assert!(!reader.batch);
+        assert_eq!(reader.get(1), Some(&vec![10; cpu_count][..]));
+        reader.read_with(&map, |_, _, _, _| panic!("Batches should not be tried again"))?;
+
+        // Any other error is returned
+        let mut reader = BatchReader::new([1, 2], cpu_count);
+        let result = reader.read_with(&map, |_, _, _, _| Err(io::Error::from_raw_os_error(libc::EPERM)));
+        assert!(matches!(
+            result,
+            Err(MapError::SyscallError(SyscallError {
+                call: "bpf_map_lookup_batch",
+                ..
+            }))
+        ));
+        assert!(reader.batch);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_unsupported() {
+        assert!(is_unsupported(&io::Error::from_raw_os_error(libc::EINVAL)));
+        assert!(is_unsupported(&io::Error::from_raw_os_error(libc::EOPNOTSUPP)));
+        assert!(is_unsupported(&io::Error::from_raw_os_error(ENOTSUPP)));
+        assert!(!is_unsupported(&io::Error::from_raw_os_error(libc::EPERM)));
+    }
+
+    #[test]
+    fn test_lookup_batch_attr() {
+        let mut out_batch = 0u32;
+        let mut keys = [0u32; 3];
+        let mut values = [0u64; 6];
+
+        // Batches from the first key have no key to continue after
+        assert_eq!(in_batch(0), None);
+        let attr = lookup_batch_attr(7, None, &mut out_batch, &mut keys, &mut values);
+        // SAFETY: The batch attributes were written.
+        let batch = unsafe { attr.batch };
+        assert_eq!(batch.in_batch, 0);
+        assert_eq!(batch.out_batch, &mut out_batch as *mut u32 as u64);
+        assert_eq!(batch.keys, keys.as_ptr() as u64);
+        assert_eq!(batch.values, values.as_ptr() as u64);
+        assert_eq!(batch.count, 3);
+        assert_eq!(batch.map_fd, 7);
+
+        // Any other batch continues after the key before its first key
+        let in_batch = in_batch(5);
+        assert_eq!(in_batch, Some(4));
+        let attr = lookup_batch_attr(7, in_batch.as_ref(), &mut out_batch, &mut keys, &mut values);
+        // SAFETY: The batch attributes were written.
+        let batch = unsafe { attr.batch };
+        assert_eq!(batch.in_batch, in_batch.as_ref().map_or(0, |key| key as *const u32 as u64));
+    }
+}
@@ -0,0 +1,159 @@
+//! Collects [`CompoundCounter`]s.
+
+use aya::maps::MapError;
+use aya_metrics_common::{kind, CompoundCounter, CompoundCounterFields, CompoundCounterValue, MeterKind};
+
+use crate::{counter::CounterState, take_map, Collector, Ebpf, Metric, PerCpuArray};
+
+/// The state of each field of a compound counter, which is emitted as a counter of its own.
+pub struct CompoundCounterState {
+    fields: Vec<CounterState>,
+    /// The value of a field for each CPU, reused for every field.
+    values: Vec<u64>,
+}
+
+impl<M: CompoundCounter> Collector<M> for kind::CompoundCounter {
+    type Map = PerCpuArray<CompoundCounterValue>;
+    type Options = ();
+    type State = CompoundCounterState;
+
+    fn take_map(bpf: &mut Ebpf) -> Result<PerCpuArray<CompoundCounterValue>, MapError> {
+        take_map(bpf, MeterKind::CompoundCounter.map_name())
+    }
+
+    fn max_entries(map: &PerCpuArray<CompoundCounterValue>) -> Option<u32> {
+        Some(map.len())
+    }
+
+    fn register(metric: &Metric<M, kind::CompoundCounter>, cpus: &[u32], cpu_count: usize) -> CompoundCounterState {
+        #[allow(clippy::let_unit_value)]
+        let () = CompoundCounterFields::<M>::FIT;
+
+        // Each field is named by the compound counter followed by the field, and has the unit of the metric unless it
+        // has one of its own
+        let name = metric.meter.name();
+        let fields = M::FIELDS
+            .iter()
+            .enumerate()
+            .map(|(i, field)| {
+                CounterState::register(
+                    format!("{name}_{field}").into(),
+                    metric.meter.field_unit(i).unwrap_or(metric.unit),
+                    metric.meter.description(),
+                    &metric.dimensions,
+                    cpus,
+                    cpu_count,
+                )
+            })
+            .collect();
+
+        CompoundCounterState {
+            fields,
+            values: vec![0; cpu_count],
+        }
+    }
+
+    fn collect(
+        metric: &Metric<M, kind::CompoundCounter>,
+        state: &mut CompoundCounterState,
+        map: &mut PerCpuArray<CompoundCounterValue>,
+        cpus: &[u32],
+    ) -> Result<(), MapError> {
+        // Get the values of every field per CPU with a single lookup
+        let values = map.get(&metric.meter.index(), 0)?;
+
+        for (i, field) in state.fields.iter_mut().enumerate() {
+            for (value, cpu_value) in state.values.iter_mut().zip(values.iter()) {
+                *value = cpu_value.fields[i];
+            }
+            field.emit(&state.values, cpus);
+        }
+
+        Ok(())
+    }
+}
+
+// GRCOV_STOP_COVERAGE
+#[cfg(test)]
+mod test {
+    use aya::{
+        maps::PerCpuValues,
+        util::{nr_cpus, online_cpus},
+    };
+    use aya_metrics_mocks::PerCpuArray;
+    use metrics::{Key, SharedString, Unit};
+    use tokio::time::{self, Duration};
+
+    use super::*;
+    use crate::{mocks::metrics::MockRecorder, Dimension, EbpfMetrics};
+
+    #[derive(Copy, Clone, Debug)]
+    enum MockCompoundCounter {
+        Xdp,
+    }
+
+    impl CompoundCounter for MockCompoundCounter {
+        const FIELDS: &'static [&'static str] = &["packets", "bytes"];
+
+        fn name(self) -> SharedString {
+            match self {
+                MockCompoundCounter::Xdp => "xdp".into(),
+            }
+        }
+
+        fn index(&self) -> u32 {
+            match self {
+                MockCompoundCounter::Xdp => 0,
+            }
+        }
+
+        fn field_unit(self, field: usize) -> Option<Unit> {
+            (Self::FIELDS[field] == "bytes").then_some(Unit::Bytes)
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_emit_metrics_reports_each_field() -> Result<(), anyhow::Error> {
+        let recorder = MockRecorder::new();
+        let _guard = metrics::set_default_local_recorder(&recorder);
+
+        let mut per_cpu_array = PerCpuArray::new(1, CompoundCounterValue::default());
+
+        tokio::spawn(EbpfMetrics::emit_metrics(
+            per_cpu_array.clone(),
+            vec![Metric::<_, kind::CompoundCounter>::new(
+                MockCompoundCounter::Xdp,
+                Unit::Count,
+                vec![Dimension::By(vec![])],
+            )],
+            Duration::from_secs(60),
+        ));
+
+        let get_counter = |name: &'static str| recorder.get_counter(&Key::from_name(name));
+
+        // Give the task a chance to run
+        tokio::task::yield_now().await;
+        // Validate each field is registered as a counter of its own (time=0s)
+        assert_eq!(get_counter("xdp_packets"), Some(0));
+        assert_eq!(get_counter("xdp_bytes"), Some(0));
+        assert_eq!(get_counter("xdp"), None);
+        // Validate each field has its own unit, or the unit of the metric (time=0s)
+        assert_eq!(recorder.get_unit("xdp_packets"), Some(Unit::Count));
+        assert_eq!(recorder.get_unit("xdp_bytes"), Some(Unit::Bytes));
+
+        // Every CPU sees 2 packets of 64 bytes
+        let value = CompoundCounterValue { fields: [2, 128, 0, 0] };
+        per_cpu_array.set(0, PerCpuValues::try_from(vec![value; nr_cpus().map_err(|(_, err)| err)?])?, 0)?;
+        // Time travel 60 seconds forward!
+        time::advance(Duration::from_secs(60)).await;
+        // Give the task a chance to run
+        tokio::task::yield_now().await;
+
+        // Validate the fields are summed across CPUs (time=60s)
+        let online = online_cpus().map_err(|(_, err)| err)?.len() as u64;
+        assert_eq!(get_counter("xdp_packets"), Some(2 * online));
+        assert_eq!(get_counter("xdp_bytes"), Some(128 * online));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,345 @@
+//! Collects [`Counter`](aya_metrics_common::Counter)s.
+
+use std::{os::fd::AsFd, path::Path};
+
+use aya::{maps::MapError, util::nr_cpus};
+use aya_metrics_common::{kind, Counter, CounterStorage, LAYOUT_SECTION_PREFIX};
+
+use metrics::{SharedString, Unit};
+use tokio::time::Duration;
+
+use crate::{
+    batch::BatchReader, mmap::MmapCounters, open::Source, take_map, Array, Collector, Dimensions, Ebpf, EbpfMetrics,
+    Error, Handles, Map, MapData, Metric, PerCpuArray,
+};
+
+/// Options of a counter [`Metric`].
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct CounterOptions {
+    /// Whether the first values read are the starting point of the counter, rather than an increment.
+    baseline: bool,
+}
+
+impl<M: Counter> Metric<M> {
+    /// Take the first values read as the starting point of the counter, rather than emitting them as an increment.
+    ///
+    /// A counter map which outlives this process, such as one opened with [`EbpfMetrics::from_pin`], holds every
+    /// increment since the eBPF program was loaded. Without a baseline its whole total is emitted on the first period.
+    pub fn with_baseline(mut self) -> Self {
+        self.options.baseline = true;
+        self
+    }
+}
+
+impl<M: Counter> EbpfMetrics<M> {
+    /// Create [`EbpfMetrics<M>`] from a counter map borrowed from its owner, for specific metrics.
+    ///
+    /// Unlike [`EbpfMetrics::new`], the map is not taken from the [`Ebpf`], so it may still be used by anything else
+    /// holding it, such as with `bpf.map(M::MAP_NAME)`. The map must have the type and size of the map named by
+    /// [`Counter::MAP_NAME`], and its layout is not verified.
+    ///
+    /// Only counters are opened from a map borrowed or pinned, the maps of other kinds of meters are always taken from
+    /// the [`Ebpf`] with [`EbpfMetrics::new`].
+    pub fn from_map(map: &Map, metrics: Vec<Metric<M>>, period: Duration) -> Result<EbpfMetrics<M>, Error> {
+        EbpfMetrics::with_map(CounterArray::open::<M>(Source::Map(map))?, metrics, period)
+    }
+
+    /// Create [`EbpfMetrics<M>`] from the data of a counter map borrowed from its owner, see [`EbpfMetrics::from_map`].
+    pub fn from_map_data(map: &MapData, metrics: Vec<Metric<M>>, period: Duration) -> Result<EbpfMetrics<M>, Error> {
+        EbpfMetrics::with_map(CounterArray::open::<M>(Source::MapData(map))?, metrics, period)
+    }
+
+    /// Create [`EbpfMetrics<M>`] from a file descriptor referring to a counter map, see [`EbpfMetrics::from_map`].
+    ///
+    /// The file descriptor is duplicated, so it may be closed by its owner at any time.
+    pub fn from_fd<F: AsFd>(fd: F, metrics: Vec<Metric<M>>, period: Duration) -> Result<EbpfMetrics<M>, Error> {
+        EbpfMetrics::with_map(CounterArray::open::<M>(Source::Fd(fd.as_fd()))?, metrics, period)
+    }
+
+    /// Create [`EbpfMetrics<M>`] from a counter map pinned to bpffs at `path`, see [`EbpfMetrics::from_map`].
+    pub fn from_pin<P: AsRef<Path>>(
+        path: P,
+        metrics: Vec<Metric<M>>,
+        period: Duration,
+    ) -> Result<EbpfMetrics<M>, Error> {
+        let source = Source::Pin {
+            path: path.as_ref(),
+            read_only: false,
+        };
+        EbpfMetrics::with_map(CounterArray::open::<M>(source)?, metrics, period)
+    }
+
+    /// Create [`EbpfMetrics<M>`] from a counter map pinned to bpffs at `path`, which is opened read only.
+    ///
+    /// This is permitted for pins which are not writable by this process, see [`EbpfMetrics::from_pin`].
+    ///
+    /// Memory mapped counters opened read only may not be mapped into memory, as the kernel refuses to share the
+    /// memory of a map opened without write access. They are then looked up each period instead, with a syscall for
+    /// every counter and CPU, which is logged as a warning. Use [`EbpfMetrics::from_pin`] to read them from memory.
+    pub fn from_pin_read_only<P: AsRef<Path>>(
+        path: P,
+        metrics: Vec<Metric<M>>,
+        period: Duration,
+    ) -> Result<EbpfMetrics<M>, Error> {
+        let source = Source::Pin {
+            path: path.as_ref(),
+            read_only: true,
+        };
+        EbpfMetrics::with_map(CounterArray::open::<M>(source)?, metrics, period)
+    }
+}
+
+/// The BPF array holding counters, along with the buffers reused to read it each period.
+pub struct CounterArray {
+    storage: Storage,
+}
+
+/// The BPF array holding counters, for each [`CounterStorage`].
+enum Storage {
+    PerCpuArray {
+        map: PerCpuArray<u64>,
+        /// Reads the counters of every metric at once, created on the first period.
+        reader: Option<BatchReader>,
+    },
+    Mmap(MmapCounters),
+}
+
+impl CounterArray {
+    /// Map an array created with `BPF_F_MMAPABLE` into memory, holding counters in strides of `max_entries`.
+    pub(crate) fn mmap(map: Array<u64>, max_entries: u32) -> Result<Self, MapError> {
+        let cpu_count = nr_cpus().map_err(|(_, err)| MapError::IoError(err))?;
+        Ok(CounterArray {
+            storage: Storage::Mmap(MmapCounters::new(map, max_entries, cpu_count)?),
+        })
+    }
+
+    /// Open a counter map which is not owned by an [`Ebpf`], with the storage of `M`.
+    fn open<M: Counter>(source: Source<'_>) -> Result<Self, MapError> {
+        match M::STORAGE {
+            CounterStorage::PerCpuArray => source.open::<PerCpuArray<u64>>().map(CounterArray::from),
+            CounterStorage::Mmap => CounterArray::mmap(source.open()?, M::MAX_ENTRIES),
+        }
+    }
+
+    /// The number of entries of the map which may hold a counter.
+    pub(crate) fn len(&self) -> u32 {
+        match &self.storage {
+            Storage::PerCpuArray { map, .. } => map.len(),
+            Storage::Mmap(counters) => counters.len(),
+        }
+    }
+}
+
+impl From<PerCpuArray<u64>> for CounterArray {
+    fn from(map: PerCpuArray<u64>) -> Self {
+        CounterArray {
+            storage: Storage::PerCpuArray { map, reader: None },
+        }
+    }
+}
+
+/// Counter handles along with the values of the previous period.
+pub struct CounterState {
+    handles: Handles<metrics::Counter>,
+    /// The previous value of the counter for each CPU, used to calculate the delta for the next period.
+    prev_values: Vec<u64>,
+    /// Whether the next values are the starting point of the counter, rather than an increment.
+    baseline: bool,
+}
+
+impl CounterState {
+    /// Create the state of a counter from its registered handles.
+    pub(crate) fn new(handles: Handles<metrics::Counter>, cpu_count: usize) -> Self {
+        CounterState {
+            handles,
+            prev_values: vec![0u64; cpu_count],
+            baseline: false,
+        }
+    }
+
+    /// Take the first values emitted as the starting point of the counter, see [`Metric::with_baseline`].
+    pub(crate) fn with_baseline(mut self, baseline: bool) -> Self {
+        self.baseline = baseline;
+        self
+    }
+
+    /// Describe a counter and register a handle for each of its dimensions.
+    pub(crate) fn register(
+        name: SharedString,
+        unit: Unit,
+        description: String,
+        dimensions: &Dimensions,
+        cpus: &[u32],
+        cpu_count: usize,
+    ) -> Self {
+        metrics::describe_counter!(name.clone(), unit, description);
+
+        let handles = Handles::register(dimensions, cpus, cpu_count, metrics::Counter::noop(), |labels| {
+            metrics::counter!(name.clone(), labels)
+        });
+
+        CounterState::new(handles, cpu_count)
+    }
+
+    /// Whether the value of any CPU is less than in the previous period, as the counter was removed and re-inserted.
+    pub(crate) fn has_reset(&self, values: &[u64], cpus: &[u32]) -> bool {
+        cpus.iter().any(|cpu_id| {
+            let cpu_id = *cpu_id as usize;
+            values.get(cpu_id).is_some_and(|value| *value < self.prev_values[cpu_id])
+        })
+    }
+
+    /// Start counting again from zero.
+    pub(crate) fn reset(&mut self) {
+        self.prev_values.fill(0);
+    }
+
+    /// Emit the delta between the values read from the BPF map and the values of the previous period.
+    ///
+    /// Returns the delta summed across CPUs.
+    pub(crate) fn emit(&mut self, values: &[u64], cpus: &[u32]) -> u64 {
+        // Counts from before the baseline were emitted by whoever read the map before
+        if self.baseline {
+            self.baseline = false;
+            for cpu_id in cpus {
+                let cpu_id = *cpu_id as usize;
+                if let Some(value) = values.get(cpu_id) {
+                    self.prev_values[cpu_id] = *value;
+                }
+            }
+            return 0;
+        }
+
+        // Keep a sum across CPUs
+        let mut delta_sum = 0;
+
+        // Iterate over each CPU
+        for cpu_id in cpus {
+            let cpu_id = *cpu_id as usize;
+            // Get the latest value for this CPU
+            if let Some(value) = values.get::<usize>(cpu_id) {
+                let value = *value;
+                // A value less than in the previous period was counted from zero again, such as by a new eBPF
+                // program pinning the map, so all of it is emitted
+                let delta = value.checked_sub(self.prev_values[cpu_id]).unwrap_or(value);
+
+                // Update the sum across CPUs
+                delta_sum += delta;
+                // Store the state for the next period
+                self.prev_values[cpu_id] = value;
+
+                // Emit metric by cpu number with any additional labels
+                for handles in &self.handles.by_cpu {
+                    handles[cpu_id].increment(delta);
+                }
+            } // GRCOV_IGNORE_LINE (apparently there is a hidden else block!)
+        }
+
+        // Emit metric with any additional labels
+        for handle in &self.handles.by {
+            handle.increment(delta_sum);
+        }
+
+        delta_sum
+    }
+}
+
+impl<M: Counter> Collector<M> for kind::Counter {
+    type Map = CounterArray;
+    type Options = CounterOptions;
+    type State = CounterState;
+
+    fn take_map(bpf: &mut Ebpf) -> Result<CounterArray, MapError> {
+        match M::STORAGE {
+            CounterStorage::PerCpuArray => take_map::<PerCpuArray<u64>>(bpf, M::MAP_NAME).map(CounterArray::from),
+            CounterStorage::Mmap => CounterArray::mmap(take_map(bpf, M::MAP_NAME)?, M::MAX_ENTRIES),
+        }
+    }
+
+    fn verify_layout(bpf: &mut Ebpf) -> Result<(), Error> {
+        // Counters without a layout, and eBPF programs which do not embed one, are not checked
+        if M::LAYOUT == 0 {
+            return Ok(());
+        }
+        let layout: Array<u64> = match take_map(bpf, &format!("{LAYOUT_SECTION_PREFIX}{}", M::MAP_NAME)) {
+            Ok(layout) => layout,
+            Err(MapError::InvalidName { .. }) => return Ok(()),
+            Err(err) => return Err(Error::MapError(err)),
+        };
+
+        let found = layout.get(&0, 0)?;
+        if found != 0 && found != M::LAYOUT {
+            return Err(Error::LayoutMismatch {
+                map: M::MAP_NAME.to_string(),
+                expected: M::LAYOUT,
+                found,
+            });
+        }
+        Ok(())
+    }
+
+    fn max_entries(map: &CounterArray) -> Option<u32> {
+        Some(map.len())
+    }
+
+    fn register(metric: &Metric<M>, cpus: &[u32], cpu_count: usize) -> CounterState {
+        CounterState::register(
+            metric.meter.name(),
+            metric.unit,
+            metric.meter.description(),
+            &metric.dimensions,
+            cpus,
+            cpu_count,
+        )
+        .with_baseline(metric.options.baseline)
+    }
+
+    fn collect(
+        metric: &Metric<M>,
+        state: &mut CounterState,
+        map: &mut CounterArray,
+        cpus: &[u32],
+    ) -> Result<(), MapError> {
+        // Get values per CPU
+        match &mut map.storage {
+            Storage::PerCpuArray { map, .. } => {
+                let values = map.get(&metric.meter.index(), 0)?;
+                state.emit(&values, cpus);
+            }
+            Storage::Mmap(counters) => {
+                if let Some(values) = counters.read(metric.meter.index()) {
+                    state.emit(values, cpus);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn collect_all(
+        metrics: &[Metric<M>],
+        states: &mut [CounterState],
+        map: &mut CounterArray,
+        cpus: &[u32],
+    ) -> Result<(), MapError> {
+        let Storage::PerCpuArray { map, reader } = &mut map.storage else {
+            // Counters in memory are read without a syscall, so there is nothing to batch
+            for (metric, state) in metrics.iter().zip(states) {
+                Self::collect(metric, state, map, cpus)?;
+            }
+            return Ok(());
+        };
+
+        // Read the counters of every metric with a single lookup, the metrics are the same each period
+        let cpu_count = states.first().map_or(0, |state| state.prev_values.len());
+        let reader = reader
+            .get_or_insert_with(|| BatchReader::new(metrics.iter().map(|metric| metric.meter.index()), cpu_count));
+        reader.read(map)?;
+
+        for (metric, state) in metrics.iter().zip(states) {
+            if let Some(values) = reader.get(metric.meter.index()) {
+                state.emit(values, cpus);
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,178 @@
+//! Collects [`DistinctCount`]s.
+
+use aya::maps::MapError;
+use aya_metrics_common::{kind, DistinctCount, HyperLogLogValue, MeterKind};
+
+use crate::{reset, take_map, Collector, Ebpf, Handles, Metric, PerCpuArray};
+
+/// Distinct count handles.
+///
+/// Distinct counts are emitted as gauges, as their value is estimated afresh each period.
+pub struct DistinctCountState {
+    handles: Handles<metrics::Gauge>,
+    cpu_count: usize,
+}
+
+impl<M: DistinctCount> Collector<M> for kind::DistinctCount {
+    type Map = PerCpuArray<HyperLogLogValue>;
+    type Options = ();
+    type State = DistinctCountState;
+
+    fn take_map(bpf: &mut Ebpf) -> Result<PerCpuArray<HyperLogLogValue>, MapError> {
+        take_map(bpf, MeterKind::DistinctCount.map_name())
+    }
+
+    fn max_entries(map: &PerCpuArray<HyperLogLogValue>) -> Option<u32> {
+        Some(map.len())
+    }
+
+    fn register(metric: &Metric<M, kind::DistinctCount>, cpus: &[u32], cpu_count: usize) -> DistinctCountState {
+        let name = metric.meter.name();
+        metrics::describe_gauge!(name.clone(), metric.unit, metric.meter.description());
+
+        let handles = Handles::register(&metric.dimensions, cpus, cpu_count, metrics::Gauge::noop(), |labels| {
+            metrics::gauge!(name.clone(), labels)
+        });
+
+        DistinctCountState { handles, cpu_count }
+    }
+
+    fn collect(
+        metric: &Metric<M, kind::DistinctCount>,
+        state: &mut DistinctCountState,
+        map: &mut PerCpuArray<HyperLogLogValue>,
+        cpus: &[u32],
+    ) -> Result<(), MapError> {
+        // Get registers per CPU
+        let index = metric.meter.index();
+        let values = map.get(&index, 0)?;
+
+        // Reset the registers for the next period. Values recorded between the read and the reset are lost.
+        reset(map, index, HyperLogLogValue::EMPTY, state.cpu_count)?;
+
+        // Merge the registers across CPUs, as the same value may have been recorded on several CPUs
+        let mut merged = HyperLogLogValue::EMPTY;
+
+        // Iterate over each CPU
+        for cpu_id in cpus {
+            let cpu_id = *cpu_id as usize;
+            if let Some(value) = values.get(cpu_id) {
+                merged.merge(value);
+
+                // Emit metric by cpu number with any additional labels
+                if !state.handles.by_cpu.is_empty() {
+                    let estimate = value.estimate().round();
+                    for handles in &state.handles.by_cpu {
+                        handles[cpu_id].set(estimate);
+                    }
+                }
+            }
+        }
+
+        // Emit metric with any additional labels
+        let estimate = merged.estimate().round();
+        for handle in &state.handles.by {
+            handle.set(estimate);
+        }
+
+        Ok(())
+    }
+}
+
+// GRCOV_STOP_COVERAGE
+#[cfg(test)]
+mod test {
+
+    use aya::{
+        maps::PerCpuValues,
+        util::{nr_cpus, online_cpus},
+    };
+    use aya_metrics_common::hll_hash;
+    use aya_metrics_mocks::PerCpuArray;
+    use metrics::{Key, Label, SharedString, Unit};
+    use tokio::time::{self, Duration};
+
+    use super::*;
+    use crate::{mocks::metrics::MockRecorder, Dimension, EbpfMetrics, METRIC_LABEL_CPU};
+
+    #[derive(Copy, Clone, Debug)]
+    enum MockDistinctCount {
+        UniqueSources,
+    }
+
+    impl DistinctCount for MockDistinctCount {
+        fn name(self) -> SharedString {
+            match self {
+                MockDistinctCount::UniqueSources => "unique_sources".into(),
+            }
+        }
+
+        fn index(&self) -> u32 {
+            match self {
+                MockDistinctCount::UniqueSources => 0,
+            }
+        }
+    }
+
+    fn get_gauge(recorder: &MockRecorder, labels: Vec<Label>) -> f64 {
+        recorder
+            .get_gauge(&Key::from_parts(MockDistinctCount::UniqueSources.name(), labels))
+            .expect("Unique sources gauge should be registered")
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_emit_metrics_merges_cpus() -> Result<(), anyhow::Error> {
+        let recorder = MockRecorder::new();
+        let _guard = metrics::set_default_local_recorder(&recorder);
+
+        let mut per_cpu_array = PerCpuArray::new(1, HyperLogLogValue::EMPTY);
+
+        tokio::spawn(EbpfMetrics::emit_metrics(
+            per_cpu_array.clone(),
+            vec![Metric::<_, kind::DistinctCount>::new(
+                MockDistinctCount::UniqueSources,
+                Unit::Count,
+                vec![Dimension::By(vec![]), Dimension::ByCpu(vec![])],
+            )],
+            Duration::from_secs(60),
+        ));
+
+        // Give the task a chance to run
+        tokio::task::yield_now().await;
+        // Validate the initial registration (time=0s)
+        assert_eq!(get_gauge(&recorder, vec![]), 0.0);
+
+        // Every online CPU sees the same 10 sources, and the first online CPU sees 10 more
+        let cpus = online_cpus().map_err(|(_, err)| err)?;
+        let mut values = vec![HyperLogLogValue::EMPTY; nr_cpus().map_err(|(_, err)| err)?];
+        for cpu_id in &cpus {
+            for source in 0..10u32 {
+                values[*cpu_id as usize].record(hll_hash(&source));
+            }
+        }
+        for source in 10..20u32 {
+            values[cpus[0] as usize].record(hll_hash(&source));
+        }
+        per_cpu_array.set(0, PerCpuValues::try_from(values)?, 0)?;
+        // Time travel 60 seconds forward!
+        time::advance(Duration::from_secs(60)).await;
+        // Give the task a chance to run
+        tokio::task::yield_now().await;
+
+        // Validate the merged and per CPU estimates (time=60s)
+        assert_eq!(get_gauge(&recorder, vec![]), 20.0);
+        let labels = vec![Label::new(METRIC_LABEL_CPU, cpus[0].to_string())];
+        assert_eq!(get_gauge(&recorder, labels), 20.0);
+        for cpu_id in &cpus[1..] {
+            let labels = vec![Label::new(METRIC_LABEL_CPU, cpu_id.to_string())];
+            assert_eq!(get_gauge(&recorder, labels), 10.0);
+        }
+
+        // Validate the registers were reset, so nothing is counted in the next period (time=120s)
+        time::advance(Duration::from_secs(60)).await;
+        tokio::task::yield_now().await;
+        assert_eq!(get_gauge(&recorder, vec![]), 0.0);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,236 @@
+//! Collects [`Gauge`]s.
+
+use aya::maps::{MapError, PerCpuValues};
+use aya_metrics_common::{kind, Gauge, GaugeValue, MeterKind};
+
+use crate::{take_map, Collector, Ebpf, Handles, Metric, PerCpuArray};
+
+/// The policy used to merge the values a gauge holds on each CPU into a single value.
+///
+/// Values set by eBPF are written to the slot of the CPU the program ran on. This is last-writer-wins per CPU, so a
+/// policy is needed to decide what the gauge is across all CPUs.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum GaugeMerge {
+    /// Use the value which was written most recently on any CPU.
+    #[default]
+    Latest,
+    /// Use the sum of the values on all possible CPUs, as a CPU which went offline still holds its contribution.
+    Sum,
+    /// Use the largest value written on any CPU.
+    Max,
+}
+
+impl GaugeMerge {
+    /// Merge the values of the given CPUs, or of all possible CPUs for [`GaugeMerge::Sum`].
+    fn merge(&self, values: &PerCpuValues<GaugeValue>, cpus: &[u32]) -> f64 {
+        let online = || cpus.iter().filter_map(|cpu_id| values.get(*cpu_id as usize));
+        match self {
+            GaugeMerge::Latest => online()
+                .filter(|value| value.timestamp > 0)
+                .max_by_key(|value| value.timestamp)
+                .map_or(0.0, |value| value.value as f64),
+            GaugeMerge::Sum => values.iter().map(|value| value.value as f64).sum(),
+            GaugeMerge::Max => online()
+                .filter(|value| value.timestamp > 0)
+                .map(|value| value.value)
+                .max()
+                .map_or(0.0, |value| value as f64),
+        }
+    }
+}
+
+/// Gauge handles.
+pub struct GaugeState {
+    handles: Handles<metrics::Gauge>,
+}
+
+impl<M: Gauge> Metric<M, kind::Gauge> {
+    /// Set the policy used to merge the values of the gauge across CPUs.
+    ///
+    /// Defaults to [`GaugeMerge::Latest`].
+    pub fn with_merge(mut self, merge: GaugeMerge) -> Self {
+        self.options = merge;
+        self
+    }
+}
+
+impl<M: Gauge> Collector<M> for kind::Gauge {
+    type Map = PerCpuArray<GaugeValue>;
+    type Options = GaugeMerge;
+    type State = GaugeState;
+
+    fn take_map(bpf: &mut Ebpf) -> Result<PerCpuArray<GaugeValue>, MapError> {
+        take_map(bpf, MeterKind::Gauge.map_name())
+    }
+
+    fn max_entries(map: &PerCpuArray<GaugeValue>) -> Option<u32> {
+        Some(map.len())
+    }
+
+    fn register(metric: &Metric<M, kind::Gauge>, cpus: &[u32], cpu_count: usize) -> GaugeState {
+        let name = metric.meter.name();
+        metrics::describe_gauge!(name.clone(), metric.unit, metric.meter.description());
+
+        let handles = Handles::register(&metric.dimensions, cpus, cpu_count, metrics::Gauge::noop(), |labels| {
+            metrics::gauge!(name.clone(), labels)
+        });
+
+        GaugeState { handles }
+    }
+
+    fn collect(
+        metric: &Metric<M, kind::Gauge>,
+        state: &mut GaugeState,
+        map: &mut PerCpuArray<GaugeValue>,
+        cpus: &[u32],
+    ) -> Result<(), MapError> {
+        // Get values per CPU
+        let values = map.get(&metric.meter.index(), 0)?;
+
+        // Emit metric by cpu number with any additional labels
+        for cpu_id in cpus {
+            let cpu_id = *cpu_id as usize;
+            if let Some(value) = values.get(cpu_id) {
+                for cpu_handles in &state.handles.by_cpu {
+                    cpu_handles[cpu_id].set(value.value as f64);
+                }
+            }
+        }
+
+        // Emit metric merged across CPUs with any additional labels
+        let merged = metric.options.merge(&values, cpus);
+        for handle in &state.handles.by {
+            handle.set(merged);
+        }
+
+        Ok(())
+    }
+}
+
+// GRCOV_STOP_COVERAGE
+#[cfg(test)]
+mod test {
+
+    use aya::util::{nr_cpus, online_cpus};
+    use aya_metrics_mocks::PerCpuArray;
+    use metrics::{Key, Label, SharedString, Unit};
+    use tokio::time::{self, Duration};
+
+    use super::*;
+    use crate::{mocks::metrics::MockRecorder, Dimension, EbpfMetrics, METRIC_LABEL_CPU};
+
+    #[derive(Copy, Clone, Debug)]
+    enum MockGauge {
+        QueueDepth,
+    }
+
+    impl Gauge for MockGauge {
+        fn name(self) -> SharedString {
+            match self {
+                MockGauge::QueueDepth => "queue_depth".into(),
+            }
+        }
+
+        fn index(&self) -> u32 {
+            match self {
+                MockGauge::QueueDepth => 0,
+            }
+        }
+    }
+
+    fn get_queue_depth_metric(merge: GaugeMerge) -> Metric<MockGauge, kind::Gauge> {
+        Metric::new(MockGauge::QueueDepth, Unit::Count, vec![Dimension::By(vec![]), Dimension::ByCpu(vec![])])
+            .with_merge(merge)
+    }
+
+    /// Values where the first online CPU was written last but holds the smallest value.
+    fn get_values() -> Result<Vec<GaugeValue>, anyhow::Error> {
+        let mut values = vec![GaugeValue::default(); nr_cpus().map_err(|(_, err)| err)?];
+        let cpus = online_cpus().map_err(|(_, err)| err)?;
+        for (i, cpu_id) in cpus.iter().enumerate() {
+            values[*cpu_id as usize] = GaugeValue {
+                value: 10 * (i as i64 + 1),
+                timestamp: (cpus.len() - i) as u64,
+            };
+        }
+        Ok(values)
+    }
+
+    fn get_gauge(recorder: &MockRecorder, labels: Vec<Label>) -> f64 {
+        recorder
+            .get_gauge(&Key::from_parts(MockGauge::QueueDepth.name(), labels))
+            .expect("Queue depth gauge should be registered")
+    }
+
+    #[test]
+    fn test_merge() -> Result<(), anyhow::Error> {
+        let values = PerCpuValues::try_from(get_values()?)?;
+        let cpus = online_cpus().map_err(|(_, err)| err)?;
+        let n = cpus.len() as f64;
+
+        assert_eq!(GaugeMerge::Latest.merge(&values, &cpus), 10.0);
+        assert_eq!(GaugeMerge::Sum.merge(&values, &cpus), 10.0 * n * (n + 1.0) / 2.0);
+        assert_eq!(GaugeMerge::Max.merge(&values, &cpus), 10.0 * n);
+
+        // CPUs which went offline are still summed, as they hold their contribution
+        let online = [cpus[0]];
+        let n = nr_cpus().map_err(|(_, err)| err)? as f64;
+        let values = PerCpuValues::try_from(vec![GaugeValue { value: 2, timestamp: 1 }; n as usize])?;
+        assert_eq!(GaugeMerge::Sum.merge(&values, &online), 2.0 * n);
+        assert_eq!(GaugeMerge::Max.merge(&values, &online), 2.0);
+
+        // Never written values are ignored by Latest and Max
+        let values = PerCpuValues::try_from(vec![GaugeValue::default(); nr_cpus().map_err(|(_, err)| err)?])?;
+        assert_eq!(GaugeMerge::Latest.merge(&values, &cpus), 0.0);
+        assert_eq!(GaugeMerge::Max.merge(&values, &cpus), 0.0);
+
+        // Never written values are ignored by Max, so they do not hide negative values written on some of the CPUs
+        let mut values = vec![GaugeValue::default(); nr_cpus().map_err(|(_, err)| err)?];
+        for (i, cpu_id) in cpus.iter().take(cpus.len().div_ceil(2)).enumerate() {
+            values[*cpu_id as usize] = GaugeValue {
+                value: -10 * (i as i64 + 1),
+                timestamp: i as u64 + 1,
+            };
+        }
+        let values = PerCpuValues::try_from(values)?;
+        assert_eq!(GaugeMerge::Max.merge(&values, &cpus), -10.0);
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_emit_metrics_sets_gauges() -> Result<(), anyhow::Error> {
+        let recorder = MockRecorder::new();
+        let _guard = metrics::set_default_local_recorder(&recorder);
+
+        let mut per_cpu_array = PerCpuArray::new(1, GaugeValue::default());
+
+        tokio::spawn(EbpfMetrics::emit_metrics(
+            per_cpu_array.clone(),
+            vec![get_queue_depth_metric(GaugeMerge::Max)],
+            Duration::from_secs(60),
+        ));
+
+        // Give the task a chance to run
+        tokio::task::yield_now().await;
+        // Validate the initial registration (time=0s)
+        assert_eq!(get_gauge(&recorder, vec![]), 0.0);
+
+        // Update the gauges
+        per_cpu_array.set(0, PerCpuValues::try_from(get_values()?)?, 0)?;
+        // Time travel 60 seconds forward!
+        time::advance(Duration::from_secs(60)).await;
+        // Give the task a chance to run
+        tokio::task::yield_now().await;
+
+        // Validate the merged and per CPU values (time=60s)
+        let cpus = online_cpus().map_err(|(_, err)| err)?;
+        assert_eq!(get_gauge(&recorder, vec![]), 10.0 * cpus.len() as f64);
+        for (i, cpu_id) in cpus.iter().enumerate() {
+            let labels = vec![Label::new(METRIC_LABEL_CPU, cpu_id.to_string())];
+            assert_eq!(get_gauge(&recorder, labels), 10.0 * (i as f64 + 1.0));
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,444 @@
+//! Collects [`HeavyHitter`]s.
+
+use std::{
+    collections::HashMap,
+    fmt::{self, Debug},
+    slice,
+    sync::Arc,
+};
+
+use aya::{
+    maps::{MapError, PerCpuValues},
+    Pod,
+};
+use aya_metrics_common::{
+    kind, CountMinSketch, HeavyHitter, MeterKey, MeterKind, BPF_KEYED_KEY_SIZE, HEAVY_HITTER_CANDIDATES_MAP_NAME,
+};
+use metrics::Label;
+use tokio::time::{Duration, Instant};
+
+use crate::{
+    keyed::{default_labels, KeyLabels, KeyedSeries, SeriesLimits},
+    reset, take_map, Collector, Ebpf, Metric, PerCpuArray, PerCpuHashMap,
+};
+
+/// The number of keys reported each period when not set.
+const DEFAULT_TOP: usize = 10;
+
+/// A [`Metric`] for a [`HeavyHitter`], emitted as a counter for each of the top keys in each period.
+pub type HeavyHitterMetric<M> = Metric<M, kind::HeavyHitter>;
+
+/// Options of a [`HeavyHitterMetric`].
+#[derive(Clone)]
+pub struct HeavyHitterOptions {
+    labels: KeyLabels,
+    top: usize,
+    limits: SeriesLimits,
+}
+
+impl Default for HeavyHitterOptions {
+    fn default() -> Self {
+        HeavyHitterOptions {
+            labels: default_labels(),
+            top: DEFAULT_TOP,
+            limits: SeriesLimits::default(),
+        }
+    }
+}
+
+impl Debug for HeavyHitterOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HeavyHitterOptions")
+            .field("top", &self.top)
+            .field("max_series", &self.limits.max_series)
+            .field("ttl", &self.limits.ttl)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<M: HeavyHitter> Metric<M, kind::HeavyHitter> {
+    /// Set the function converting each key into labels.
+    ///
+    /// The key must have the same type as the key given to `heavy_hitter` in BPF. The labels are added to the labels
+    /// of each dimension. Defaults to a `key` label holding the key as hexadecimal.
+    ///
+    /// Fails to compile if `K` is larger than [`BPF_KEYED_KEY_SIZE`].
+    pub fn with_labels<K: Pod>(mut self, labels: impl Fn(K) -> Vec<Label> + Send + Sync + 'static) -> Self {
+        self.options.labels = Arc::new(move |key| labels(key.key::<K>()));
+        self
+    }
+
+    /// Set the number of keys with the largest estimated counts which are reported each period.
+    ///
+    /// Each key reported is counted in a series of its own, which is kept until it expires, see
+    /// [`Metric::with_ttl`]. Defaults to 10.
+    pub fn with_top(mut self, top: usize) -> Self {
+        self.options.top = top;
+        self
+    }
+
+    /// Set the cardinality budget, the maximum number of keys registered as their own series.
+    ///
+    /// Once the budget is exhausted any new key reported is counted in a single overflow series, where the value of
+    /// each label of the key is `other`, and in the [`METRIC_KEYED_DROPPED_KEYS`](crate::METRIC_KEYED_DROPPED_KEYS)
+    /// self-metric. Defaults to no budget.
+    pub fn with_max_series(mut self, max_series: usize) -> Self {
+        self.options.limits.max_series = Some(max_series);
+        self
+    }
+
+    /// Set the time to live, after which a key which has not been among the top keys is expired.
+    ///
+    /// An expired key is no longer emitted, which also frees its place in the cardinality budget. The key is counted
+    /// in a new series if it is reported again. Defaults to never expiring.
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.options.limits.ttl = Some(ttl);
+        self
+    }
+
+    /// Set a hook which is called with the key of each metric of an expired series, see [`Metric::with_ttl`].
+    ///
+    /// The [metrics] crate has no way to unregister a metric, so this allows the application to remove the series from
+    /// its recorder or exporter.
+    pub fn on_expire(mut self, on_expire: impl Fn(&metrics::Key) + Send + Sync + 'static) -> Self {
+        self.options.limits.on_expire = Some(Arc::new(on_expire));
+        self
+    }
+}
+
+/// The BPF maps holding the count-min sketches and the candidate keys of heavy hitters.
+pub struct HeavyHitterMaps {
+    sketches: PerCpuArray<CountMinSketch>,
+    candidates: PerCpuHashMap<MeterKey, u64>,
+}
+
+/// Series registered for the keys which were reported.
+pub struct HeavyHitterState {
+    /// The series of each key, bounded by the cardinality budget.
+    series: KeyedSeries,
+    /// The start of the period in which each key registered as its own series was last reported.
+    last_top: HashMap<[u8; BPF_KEYED_KEY_SIZE], Instant>,
+    cpu_count: usize,
+}
+
+impl<M: HeavyHitter> Collector<M> for kind::HeavyHitter {
+    type Map = HeavyHitterMaps;
+    type Options = HeavyHitterOptions;
+    type State = HeavyHitterState;
+
+    fn take_map(bpf: &mut Ebpf) -> Result<HeavyHitterMaps, MapError> {
+        Ok(HeavyHitterMaps {
+            sketches: take_map(bpf, MeterKind::HeavyHitter.map_name())?,
+            candidates: take_map(bpf, HEAVY_HITTER_CANDIDATES_MAP_NAME)?,
+        })
+    }
+
+    fn max_entries(maps: &HeavyHitterMaps) -> Option<u32> {
+        Some(maps.sketches.len())
+    }
+
+    fn register(metric: &Metric<M, kind::HeavyHitter>, _cpus: &[u32], cpu_count: usize) -> HeavyHitterState {
+        metrics::describe_counter!(metric.meter.name(), metric.unit, metric.meter.description());
+
+        // Series are registered as their keys are reported
+        HeavyHitterState {
+            series: KeyedSeries::new(metric, cpu_count),
+            last_top: HashMap::new(),
+            cpu_count,
+        }
+    }
+
+    fn collect(
+        metric: &Metric<M, kind::HeavyHitter>,
+        state: &mut HeavyHitterState,
+        maps: &mut HeavyHitterMaps,
+        cpus: &[u32],
+    ) -> Result<(), MapError> {
+        Self::collect_all(slice::from_ref(metric), slice::from_mut(state), maps, cpus)
+    }
+
+    fn collect_all(
+        metrics: &[Metric<M, kind::HeavyHitter>],
+        states: &mut [HeavyHitterState],
+        maps: &mut HeavyHitterMaps,
+        cpus: &[u32],
+    ) -> Result<(), MapError> {
+        // Sum the estimated count of each candidate across CPUs, the map is shared by all heavy hitters
+        let mut candidates: HashMap<u32, Vec<Candidate>> = HashMap::new();
+        for entry in maps.candidates.iter() {
+            let (key, values) = entry?;
+            let total: u64 = cpus.iter().filter_map(|cpu_id| values.get(*cpu_id as usize)).sum();
+            candidates.entry(key.index).or_default().push((key, values, total));
+        }
+
+        for (metric, state) in metrics.iter().zip(states) {
+            let candidates = candidates.remove(&metric.meter.index()).unwrap_or_default();
+            collect_candidates(metric, state, candidates, maps, cpus)?;
+        }
+        Ok(())
+    }
+}
+
+/// A candidate key along with its estimated count on each CPU and across CPUs.
+type Candidate = (MeterKey, PerCpuValues<u64>, u64);
+
+/// Emit the top candidates of a metric for a single period, resetting its sketch and candidates.
+fn collect_candidates<M: HeavyHitter>(
+    metric: &Metric<M, kind::HeavyHitter>,
+    state: &mut HeavyHitterState,
+    mut candidates: Vec<Candidate>,
+    maps: &mut HeavyHitterMaps,
+    cpus: &[u32],
+) -> Result<(), MapError> {
+    let index = metric.meter.index();
+
+    // Reset the sketches and candidates for the next period. Values counted in the meantime are lost.
+    reset(&mut maps.sketches, index, CountMinSketch::EMPTY, state.cpu_count)?;
+    for (key, _, _) in &candidates {
+        // The key may have been evicted in the meantime
+        if let Err(err) = maps.candidates.remove(key) {
+            if maps.candidates.get(key, 0).is_ok() {
+                return Err(err);
+            }
+        }
+    }
+
+    // Emit the keys with the largest counts, breaking ties by key so the same keys are reported each time
+    candidates.sort_unstable_by(|(a, _, a_total), (b, _, b_total)| b_total.cmp(a_total).then(a.key.cmp(&b.key)));
+    candidates.truncate(metric.options.top);
+
+    // Keys which have not been reported for their time to live expire before new keys take their place in the
+    // cardinality budget
+    let now = Instant::now();
+    for (key, _, _) in &candidates {
+        if let Some(last_top) = state.last_top.get_mut(&key.key) {
+            *last_top = now;
+        }
+    }
+    if let Some(ttl) = metric.options.limits.ttl {
+        state.last_top.retain(|key, last_top| {
+            let expired = now.duration_since(*last_top) >= ttl;
+            if expired {
+                state.series.expire(&metric.options.limits, key);
+            }
+            !expired
+        });
+    }
+
+    for (key, values, total) in candidates {
+        let handles = state
+            .series
+            .handles(metric, &metric.options.labels, &metric.options.limits, &key, cpus);
+        if state.series.contains(&key.key) {
+            state.last_top.entry(key.key).or_insert(now);
+        }
+
+        // Emit metric by cpu number with any additional labels
+        for cpu_id in cpus {
+            let cpu_id = *cpu_id as usize;
+            if let Some(value) = values.get(cpu_id) {
+                for cpu_handles in &handles.by_cpu {
+                    cpu_handles[cpu_id].increment(*value);
+                }
+            }
+        }
+
+        // Emit metric with any additional labels
+        for handle in &handles.by {
+            handle.increment(total);
+        }
+    }
+
+    Ok(())
+}
+
+// GRCOV_STOP_COVERAGE
+#[cfg(test)]
+mod test {
+
+    use aya::{
+        maps::PerCpuValues,
+        util::{nr_cpus, online_cpus},
+    };
+    use aya_metrics_mocks::{PerCpuArray, PerCpuHashMap};
+    use metrics::{Key, SharedString, Unit};
+    use tokio::time::{self, Duration};
+
+    use super::*;
+    use crate::{
+        keyed::{METRIC_LABEL_METRIC, METRIC_LABEL_REASON, REASON_OVERFLOW},
+        mocks::metrics::MockRecorder,
+        Dimension, EbpfMetrics, METRIC_KEYED_DROPPED_KEYS,
+    };
+
+    const METRIC_LABEL_FLOW: &str = "flow";
+
+    #[derive(Copy, Clone, Debug)]
+    enum MockHeavyHitter {
+        BytesByFlow,
+        Other,
+    }
+
+    impl HeavyHitter for MockHeavyHitter {
+        fn name(self) -> SharedString {
+            match self {
+                MockHeavyHitter::BytesByFlow => "bytes_by_flow".into(),
+                MockHeavyHitter::Other => "other".into(),
+            }
+        }
+
+        fn index(&self) -> u32 {
+            match self {
+                MockHeavyHitter::BytesByFlow => 0,
+                MockHeavyHitter::Other => 1,
+            }
+        }
+    }
+
+    fn get_counter(recorder: &MockRecorder, flow: u32) -> Option<u64> {
+        let labels = vec![Label::new(METRIC_LABEL_FLOW, flow.to_string())];
+        recorder.get_counter(&Key::from_parts(MockHeavyHitter::BytesByFlow.name(), labels))
+    }
+
+    /// Set the estimated count of a flow on the first online CPU.
+    fn set(
+        map: &mut PerCpuHashMap<MeterKey, u64>,
+        meter: MockHeavyHitter,
+        flow: u32,
+        value: u64,
+    ) -> Result<(), anyhow::Error> {
+        let mut values = vec![0u64; nr_cpus().map_err(|(_, err)| err)?];
+        values[online_cpus().map_err(|(_, err)| err)?[0] as usize] = value;
+        map.insert(MeterKey::new(meter.index(), &flow), PerCpuValues::try_from(values)?, 0)?;
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_emit_metrics_reports_top_keys() -> Result<(), anyhow::Error> {
+        let recorder = MockRecorder::new();
+        let _guard = metrics::set_default_local_recorder(&recorder);
+
+        let mut sketches = PerCpuArray::new(1, CountMinSketch::EMPTY);
+        let mut candidates = PerCpuHashMap::new();
+        let maps = HeavyHitterMaps {
+            sketches: sketches.clone(),
+            candidates: candidates.clone(),
+        };
+
+        // Count a flow in the sketch, which is reset each period
+        let mut sketch = CountMinSketch::EMPTY;
+        sketch.record(1, 100);
+        sketches.set(0, PerCpuValues::try_from(vec![sketch; nr_cpus().map_err(|(_, err)| err)?])?, 0)?;
+
+        set(&mut candidates, MockHeavyHitter::BytesByFlow, 1, 100)?;
+        set(&mut candidates, MockHeavyHitter::BytesByFlow, 2, 50)?;
+        set(&mut candidates, MockHeavyHitter::BytesByFlow, 3, 10)?;
+        set(&mut candidates, MockHeavyHitter::Other, 4, 1000)?;
+
+        tokio::spawn(EbpfMetrics::emit_metrics(
+            maps,
+            vec![HeavyHitterMetric::new(MockHeavyHitter::BytesByFlow, Unit::Bytes, vec![Dimension::By(vec![])])
+                .with_labels(|flow: u32| vec![Label::new(METRIC_LABEL_FLOW, flow.to_string())])
+                .with_top(2)],
+            Duration::from_secs(60),
+        ));
+
+        // Give the task a chance to run
+        tokio::task::yield_now().await;
+
+        // Validate only the top keys are reported (time=0s)
+        assert_eq!(get_counter(&recorder, 1), Some(100));
+        assert_eq!(get_counter(&recorder, 2), Some(50));
+        assert_eq!(get_counter(&recorder, 3), None);
+
+        // Validate the candidates and sketch of the heavy hitter were reset, but not those of other heavy hitters
+        assert_eq!(candidates.len(), 1);
+        assert_eq!(sketches.get(&0, 0)?[0], CountMinSketch::EMPTY);
+
+        // Count flows again
+        set(&mut candidates, MockHeavyHitter::BytesByFlow, 1, 1)?;
+        set(&mut candidates, MockHeavyHitter::BytesByFlow, 3, 500)?;
+        // Time travel 60 seconds forward!
+        time::advance(Duration::from_secs(60)).await;
+        // Give the task a chance to run
+        tokio::task::yield_now().await;
+
+        // Validate the counts of the period are added to the series of each reported key (time=60s)
+        assert_eq!(get_counter(&recorder, 1), Some(101));
+        assert_eq!(get_counter(&recorder, 2), Some(50));
+        assert_eq!(get_counter(&recorder, 3), Some(500));
+
+        Ok(())
+    }
+    #[tokio::test(start_paused = true)]
+    async fn test_emit_metrics_expires_keys() -> Result<(), anyhow::Error> {
+        let recorder = MockRecorder::new();
+        let _guard = metrics::set_default_local_recorder(&recorder);
+
+        let mut candidates = PerCpuHashMap::new();
+        let maps = HeavyHitterMaps {
+            sketches: PerCpuArray::new(1, CountMinSketch::EMPTY),
+            candidates: candidates.clone(),
+        };
+        set(&mut candidates, MockHeavyHitter::BytesByFlow, 1, 100)?;
+
+        let expired = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let on_expire = {
+            let expired = expired.clone();
+            move |key: &Key| expired.lock().unwrap().push(key.clone())
+        };
+        tokio::spawn(EbpfMetrics::emit_metrics(
+            maps,
+            vec![HeavyHitterMetric::new(MockHeavyHitter::BytesByFlow, Unit::Bytes, vec![Dimension::By(vec![])])
+                .with_labels(|flow: u32| vec![Label::new(METRIC_LABEL_FLOW, flow.to_string())])
+                .with_top(1)
+                .with_max_series(1)
+                .with_ttl(Duration::from_secs(120))
+                .on_expire(on_expire)],
+            Duration::from_secs(60),
+        ));
+
+        // Give the task a chance to run
+        tokio::task::yield_now().await;
+
+        // Report another flow while the first flow holds the only place in the budget
+        set(&mut candidates, MockHeavyHitter::BytesByFlow, 2, 50)?;
+        // Time travel 60 seconds forward!
+        time::advance(Duration::from_secs(60)).await;
+        // Give the task a chance to run
+        tokio::task::yield_now().await;
+
+        // Validate the new flow is counted in the overflow series (time=60s)
+        let get_other = || {
+            let labels = vec![Label::new(METRIC_LABEL_FLOW, "other")];
+            recorder.get_counter(&Key::from_parts(MockHeavyHitter::BytesByFlow.name(), labels))
+        };
+        let labels = vec![
+            Label::new(METRIC_LABEL_METRIC, MockHeavyHitter::BytesByFlow.name()),
+            Label::new(METRIC_LABEL_REASON, REASON_OVERFLOW),
+        ];
+        assert_eq!(get_counter(&recorder, 1), Some(100));
+        assert_eq!(get_counter(&recorder, 2), None);
+        assert_eq!(get_other(), Some(50));
+        assert_eq!(recorder.get_counter(&Key::from_parts(METRIC_KEYED_DROPPED_KEYS, labels)), Some(1));
+        assert!(expired.lock().unwrap().is_empty());
+
+        // Report a third flow, once the first flow has not been reported for its time to live
+        set(&mut candidates, MockHeavyHitter::BytesByFlow, 3, 10)?;
+        // Time travel 60 seconds forward!
+        time::advance(Duration::from_secs(60)).await;
+        // Give the task a chance to run
+        tokio::task::yield_now().await;
+
+        // Validate the first flow expired and the new flow is counted in its own series (time=120s)
+        {
+            let expired = expired.lock().unwrap();
+            let labels = vec![Label::new(METRIC_LABEL_FLOW, "1")];
+            assert_eq!(*expired, vec![Key::from_parts(MockHeavyHitter::BytesByFlow.name(), labels)]);
+        }
+        assert_eq!(get_counter(&recorder, 3), Some(10));
+        assert_eq!(get_other(), Some(50));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,543 @@
+//! Collects [`Histogram`]s.
+
+use aya::maps::MapError;
+use aya_metrics_common::{kind, Buckets, Histogram, HistogramValue, MeterKind, BPF_HISTOGRAM_BUCKETS};
+use metrics::{Label, Unit};
+
+use crate::{take_map, Collector, Dimensions, Ebpf, Handles, Metric, PerCpuArray};
+
+const METRIC_LABEL_LE: &str = "le";
+
+/// How the buckets of a histogram are emitted.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum HistogramMode {
+    /// Emit each value counted in a bucket during the period as a [`metrics::histogram!`] sample.
+    ///
+    /// The eBPF program only counts values per bucket, so samples are recorded with the upper bound of their bucket.
+    /// The last bucket has no upper bound, so values larger than the last bound are recorded with the last bound.
+    #[default]
+    Samples,
+    /// Emit a cumulative [`metrics::counter!`] for each bucket with an `le` label set to the upper bound of the bucket.
+    ///
+    /// This follows the Prometheus convention, where each counter counts all values less than or equal to the bound.
+    Buckets,
+}
+
+/// Options of a histogram [`Metric`].
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct HistogramOptions {
+    mode: HistogramMode,
+    nanoseconds: bool,
+}
+
+/// Histogram handles for the configured [`HistogramMode`].
+enum HistogramHandles {
+    /// Handles along with the number of nanoseconds per unit of the metric, which is one unless values are converted.
+    Samples(Buckets, u64, Handles<metrics::Histogram>),
+    /// Handles for each bucket.
+    Buckets(Vec<Handles<metrics::Counter>>),
+}
+
+/// Histogram handles along with the values of the previous period.
+pub struct HistogramState {
+    handles: HistogramHandles,
+    /// The previous value of the histogram for each CPU, used to calculate the delta for the next period.
+    prev_values: Vec<HistogramValue>,
+}
+
+impl<M: Histogram> Metric<M, kind::Histogram> {
+    /// Set how the buckets of the histogram are emitted.
+    ///
+    /// Defaults to [`HistogramMode::Samples`].
+    pub fn with_mode(mut self, mode: HistogramMode) -> Self {
+        self.options.mode = mode;
+        self
+    }
+
+    /// Convert the values of the histogram from nanoseconds into the unit of the metric.
+    ///
+    /// Values recorded by timers are in nanoseconds, so the metric may be emitted with [`Unit::Seconds`],
+    /// [`Unit::Milliseconds`] or [`Unit::Microseconds`]. Values are not converted for any other unit.
+    pub fn with_nanoseconds(mut self) -> Self {
+        self.options.nanoseconds = true;
+        self
+    }
+
+    /// Create a [`Metric`] for a histogram which timers record into, emitted in seconds.
+    pub fn timer(meter: M, dimensions: Dimensions) -> Self {
+        Metric::new(meter, Unit::Seconds, dimensions).with_nanoseconds()
+    }
+}
+
+/// The number of nanoseconds per unit, or one if the unit is not a unit of time.
+fn nanoseconds_per(unit: Unit) -> u64 {
+    match unit {
+        Unit::Seconds => 1_000_000_000,
+        Unit::Milliseconds => 1_000_000,
+        Unit::Microseconds => 1_000,
+        _ => 1,
+    }
+}
+
+impl HistogramHandles {
+    /// Emit the values counted in each bucket during a period.
+    fn emit(&self, delta: &HistogramValue, by_cpu: Option<usize>) {
+        match self {
+            HistogramHandles::Samples(buckets, per_unit, handles) => {
+                // Without any bounds there is no value to record
+                let Some(last_bounded) = buckets.num_buckets().checked_sub(2) else {
+                    return;
+                };
+                for (bucket, count) in delta.buckets.iter().enumerate().filter(|(_, count)| **count > 0) {
+                    let value = buckets.upper_bound(bucket.min(last_bounded)) as f64 / *per_unit as f64;
+                    for_each(handles, by_cpu, |handle| handle.record_many(value, *count as usize));
+                }
+            }
+            HistogramHandles::Buckets(buckets) => {
+                let mut cumulative = 0;
+                for (handles, count) in buckets.iter().zip(delta.buckets) {
+                    cumulative += count;
+                    for_each(handles, by_cpu, |handle| handle.increment(cumulative));
+                }
+            }
+        }
+    }
+}
+
+/// Call `f` with the handles for a specific CPU, or with the handles without a CPU when no CPU is given.
+fn for_each<H>(handles: &Handles<H>, by_cpu: Option<usize>, f: impl FnMut(&H)) {
+    match by_cpu {
+        Some(cpu_id) => handles.by_cpu.iter().map(|handles| &handles[cpu_id]).for_each(f),
+        None => handles.by.iter().for_each(f),
+    }
+}
+
+/// The label of the bucket counting values up to and including the upper bound of a bucket.
+///
+/// The bound is divided by `per_unit` to convert it into the unit of the metric.
+fn le_label(buckets: &Buckets, bucket: usize, per_unit: u64) -> Label {
+    if bucket + 1 == buckets.num_buckets() {
+        Label::new(METRIC_LABEL_LE, "+Inf")
+    } else if per_unit == 1 {
+        Label::new(METRIC_LABEL_LE, buckets.upper_bound(bucket).to_string())
+    } else {
+        Label::new(METRIC_LABEL_LE, (buckets.upper_bound(bucket) as f64 / per_unit as f64).to_string())
+    }
+}
+
+impl<M: Histogram> Collector<M> for kind::Histogram {
+    type Map = PerCpuArray<HistogramValue>;
+    type Options = HistogramOptions;
+    type State = HistogramState;
+
+    fn take_map(bpf: &mut Ebpf) -> Result<PerCpuArray<HistogramValue>, MapError> {
+        take_map(bpf, MeterKind::Histogram.map_name())
+    }
+
+    fn max_entries(map: &PerCpuArray<HistogramValue>) -> Option<u32> {
+        Some(map.len())
+    }
+
+    fn reserved_labels(metric: &Metric<M, kind::Histogram>) -> &'static [&'static str] {
+        match metric.options.mode {
+            HistogramMode::Samples => &[],
+            HistogramMode::Buckets => &[METRIC_LABEL_LE],
+        }
+    }
+
+    fn register(metric: &Metric<M, kind::Histogram>, cpus: &[u32], cpu_count: usize) -> HistogramState {
+        let buckets = metric.meter.buckets();
+        let per_unit = if metric.options.nanoseconds {
+            nanoseconds_per(metric.unit)
+        } else {
+            1
+        };
+        let name = metric.meter.name();
+        let handles = match metric.options.mode {
+            HistogramMode::Samples => {
+                metrics::describe_histogram!(name.clone(), metric.unit, metric.meter.description());
+
+                HistogramHandles::Samples(
+                    buckets,
+                    per_unit,
+                    Handles::register(&metric.dimensions, cpus, cpu_count, metrics::Histogram::noop(), |labels| {
+                        metrics::histogram!(name.clone(), labels)
+                    }),
+                )
+            }
+            HistogramMode::Buckets => {
+                metrics::describe_counter!(name.clone(), metric.unit, metric.meter.description());
+
+                HistogramHandles::Buckets(
+                    (0..buckets.num_buckets())
+                        .map(|bucket| {
+                            Handles::register(
+                                &metric.dimensions,
+                                cpus,
+                                cpu_count,
+                                metrics::Counter::noop(),
+                                |mut labels| {
+                                    labels.push(le_label(&buckets, bucket, per_unit));
+                                    metrics::counter!(name.clone(), labels)
+                                },
+                            )
+                        })
+                        .collect(),
+                )
+            }
+        };
+
+        HistogramState {
+            handles,
+            prev_values: vec![HistogramValue::EMPTY; cpu_count],
+        }
+    }
+
+    fn collect(
+        metric: &Metric<M, kind::Histogram>,
+        state: &mut HistogramState,
+        map: &mut PerCpuArray<HistogramValue>,
+        cpus: &[u32],
+    ) -> Result<(), MapError> {
+        // Get values per CPU
+        let values = map.get(&metric.meter.index(), 0)?;
+
+        // Keep a sum across CPUs
+        let mut delta_sum = HistogramValue::EMPTY;
+
+        // Iterate over each CPU
+        for cpu_id in cpus {
+            let cpu_id = *cpu_id as usize;
+            // Get the latest value for this CPU
+            if let Some(value) = values.get::<usize>(cpu_id) {
+                let prev_value = &mut state.prev_values[cpu_id];
+                let mut delta = HistogramValue::EMPTY;
+                for bucket in 0..BPF_HISTOGRAM_BUCKETS {
+                    // A bucket less than in the previous period was counted from zero again, such as by a new eBPF
+                    // program pinning the map, so all of it is emitted
+                    delta.buckets[bucket] = value.buckets[bucket]
+                        .checked_sub(prev_value.buckets[bucket])
+                        .unwrap_or(value.buckets[bucket]);
+                    // Update the sum across CPUs
+                    delta_sum.buckets[bucket] += delta.buckets[bucket];
+                }
+                // Store the state for the next period
+                *prev_value = *value;
+
+                // Emit metric by cpu number with any additional labels
+                state.handles.emit(&delta, Some(cpu_id));
+            }
+        }
+
+        // Emit metric with any additional labels
+        state.handles.emit(&delta_sum, None);
+
+        Ok(())
+    }
+}
+
+// GRCOV_STOP_COVERAGE
+#[cfg(test)]
+mod test {
+
+    use aya::{
+        maps::PerCpuValues,
+        util::{nr_cpus, online_cpus},
+    };
+    use aya_metrics_mocks::{Ebpf, PerCpuArray};
+    use metrics::{Key, SharedString, Unit};
+    use tokio::time::{self, Duration};
+
+    use super::*;
+    use crate::{mocks::metrics::MockRecorder, Dimension, EbpfMetrics, Error, METRIC_LABEL_CPU};
+
+    const RTT_BOUNDS: &[u64] = &[1_000_000, 5_000_000, 10_000_000];
+
+    #[derive(Copy, Clone, Debug)]
+    enum MockHistogram {
+        PacketSize,
+        Rtt,
+    }
+
+    impl Histogram for MockHistogram {
+        fn name(self) -> SharedString {
+            match self {
+                MockHistogram::PacketSize => "packet_size".into(),
+                MockHistogram::Rtt => "rtt".into(),
+            }
+        }
+
+        fn index(&self) -> u32 {
+            match self {
+                MockHistogram::PacketSize => 0,
+                MockHistogram::Rtt => 1,
+            }
+        }
+
+        fn buckets(&self) -> Buckets {
+            match self {
+                MockHistogram::PacketSize => Buckets::Log2,
+                MockHistogram::Rtt => Buckets::Explicit(RTT_BOUNDS),
+            }
+        }
+    }
+
+    fn get_metric(histogram: MockHistogram, mode: HistogramMode) -> Metric<MockHistogram, kind::Histogram> {
+        Metric::new(histogram, Unit::Count, vec![Dimension::By(vec![]), Dimension::ByCpu(vec![])]).with_mode(mode)
+    }
+
+    /// Values where each CPU counted one value in the `first` bucket and two values in the `second` bucket.
+    fn get_values(first: usize, second: usize) -> Result<PerCpuValues<HistogramValue>, anyhow::Error> {
+        let mut value = HistogramValue::EMPTY;
+        value.buckets[first] = 1;
+        value.buckets[second] = 2;
+        Ok(PerCpuValues::try_from(vec![value; nr_cpus().map_err(|(_, err)| err)?])?)
+    }
+
+    /// Spawn a task emitting the metric and update its values after the first period.
+    async fn emit_values(
+        metric: Metric<MockHistogram, kind::Histogram>,
+        values: PerCpuValues<HistogramValue>,
+    ) -> Result<(), anyhow::Error> {
+        let index = Histogram::index(&metric.meter);
+        let mut per_cpu_array = PerCpuArray::new(index as usize + 1, HistogramValue::EMPTY);
+
+        tokio::spawn(EbpfMetrics::emit_metrics(per_cpu_array.clone(), vec![metric], Duration::from_secs(60)));
+
+        // Give the task a chance to run
+        tokio::task::yield_now().await;
+
+        // Update the histograms
+        per_cpu_array.set(index, values, 0)?;
+        // Time travel 60 seconds forward!
+        time::advance(Duration::from_secs(60)).await;
+        // Give the task a chance to run
+        tokio::task::yield_now().await;
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_emit_metrics_records_samples() -> Result<(), anyhow::Error> {
+        let recorder = MockRecorder::new();
+        let _guard = metrics::set_default_local_recorder(&recorder);
+
+        // One value of 1 and two values of 1500
+        emit_values(get_metric(MockHistogram::PacketSize, HistogramMode::Samples), get_values(1, 11)?).await?;
+
+        let cpus = online_cpus().map_err(|(_, err)| err)?;
+        let mut expected = vec![1.0; cpus.len()];
+        expected.extend(vec![2047.0; 2 * cpus.len()]);
+        let actual = recorder
+            .get_histogram(&Key::from_parts(MockHistogram::PacketSize.name(), vec![]))
+            .expect("Packet size histogram should be registered with no labels");
+        assert_eq!(actual, expected);
+
+        for cpu_id in cpus {
+            let labels = vec![Label::new(METRIC_LABEL_CPU, cpu_id.to_string())];
+            let actual = recorder
+                .get_histogram(&Key::from_parts(MockHistogram::PacketSize.name(), labels))
+                .expect("Packet size histogram should be registered with cpu label");
+            assert_eq!(actual, vec![1.0, 2047.0, 2047.0]);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_emit_metrics_records_samples_over_last_bound() -> Result<(), anyhow::Error> {
+        let recorder = MockRecorder::new();
+        let _guard = metrics::set_default_local_recorder(&recorder);
+
+        // One value between 1ms and 5ms and two values over 10ms
+        emit_values(get_metric(MockHistogram::Rtt, HistogramMode::Samples), get_values(1, 3)?).await?;
+
+        // Values over the last bound are recorded with the last bound
+        let cpus = online_cpus().map_err(|(_, err)| err)?;
+        let mut expected = vec![5_000_000.0; cpus.len()];
+        expected.extend(vec![10_000_000.0; 2 * cpus.len()]);
+        let actual = recorder
+            .get_histogram(&Key::from_parts(MockHistogram::Rtt.name(), vec![]))
+            .expect("Rtt histogram should be registered with no labels");
+        assert_eq!(actual, expected);
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_emit_metrics_increments_buckets() -> Result<(), anyhow::Error> {
+        let recorder = MockRecorder::new();
+        let _guard = metrics::set_default_local_recorder(&recorder);
+
+        // One value of 1 and two values of 1500
+        emit_values(get_metric(MockHistogram::PacketSize, HistogramMode::Buckets), get_values(1, 11)?).await?;
+
+        let cpus = online_cpus().map_err(|(_, err)| err)?.len() as u64;
+        let get_bucket = |le: &str| {
+            recorder
+                .get_counter(&Key::from_parts(
+                    MockHistogram::PacketSize.name(),
+                    vec![Label::new(METRIC_LABEL_LE, le.to_string())],
+                ))
+                .expect("Packet size bucket should be registered with le label")
+        };
+        assert_eq!(get_bucket("0"), 0);
+        assert_eq!(get_bucket("1"), cpus);
+        assert_eq!(get_bucket("1023"), cpus);
+        assert_eq!(get_bucket("2047"), 3 * cpus);
+        assert_eq!(get_bucket("+Inf"), 3 * cpus);
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_emit_metrics_buckets_after_reset() -> Result<(), anyhow::Error> {
+        let recorder = MockRecorder::new();
+        let _guard = metrics::set_default_local_recorder(&recorder);
+
+        let metric = get_metric(MockHistogram::PacketSize, HistogramMode::Buckets);
+        let mut per_cpu_array = PerCpuArray::new(1, HistogramValue::EMPTY);
+        tokio::spawn(EbpfMetrics::emit_metrics(per_cpu_array.clone(), vec![metric], Duration::from_secs(60)));
+
+        // Give the task a chance to run
+        tokio::task::yield_now().await;
+
+        // One value of 1 and two values of 1500
+        per_cpu_array.set(0, get_values(1, 11)?, 0)?;
+        // Time travel 60 seconds forward!
+        time::advance(Duration::from_secs(60)).await;
+        // Give the task a chance to run
+        tokio::task::yield_now().await;
+
+        // Count from zero again, such as after a new eBPF program pinned the map, then a single value of 1500
+        let mut value = HistogramValue::EMPTY;
+        value.buckets[11] = 1;
+        per_cpu_array.set(0, PerCpuValues::try_from(vec![value; nr_cpus().map_err(|(_, err)| err)?])?, 0)?;
+        // Time travel 60 seconds forward!
+        time::advance(Duration::from_secs(60)).await;
+        // Give the task a chance to run
+        tokio::task::yield_now().await;
+
+        // The lower buckets are emitted in full rather than underflowing
+        let cpus = online_cpus().map_err(|(_, err)| err)?.len() as u64;
+        let get_bucket = |le: &str| {
+            recorder
+                .get_counter(&Key::from_parts(
+                    MockHistogram::PacketSize.name(),
+                    vec![Label::new(METRIC_LABEL_LE, le.to_string())],
+                ))
+                .expect("Packet size bucket should be registered with le label")
+        };
+        assert_eq!(get_bucket("1"), cpus);
+        assert_eq!(get_bucket("2047"), 4 * cpus);
+        assert_eq!(get_bucket("+Inf"), 4 * cpus);
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_emit_metrics_explicit_buckets() -> Result<(), anyhow::Error> {
+        let recorder = MockRecorder::new();
+        let _guard = metrics::set_default_local_recorder(&recorder);
+
+        // One value between 1ms and 5ms and two values over 10ms
+        emit_values(get_metric(MockHistogram::Rtt, HistogramMode::Buckets), get_values(1, 3)?).await?;
+
+        let cpus = online_cpus().map_err(|(_, err)| err)?.len() as u64;
+        let get_bucket = |le: &str| {
+            recorder.get_counter(&Key::from_parts(
+                MockHistogram::Rtt.name(),
+                vec![Label::new(METRIC_LABEL_LE, le.to_string())],
+            ))
+        };
+        assert_eq!(get_bucket("1000000"), Some(0));
+        assert_eq!(get_bucket("5000000"), Some(cpus));
+        assert_eq!(get_bucket("10000000"), Some(cpus));
+        assert_eq!(get_bucket("+Inf"), Some(3 * cpus));
+        // Only the declared bounds are registered
+        assert_eq!(get_bucket("1"), None);
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_emit_metrics_timer_in_seconds() -> Result<(), anyhow::Error> {
+        let recorder = MockRecorder::new();
+        let _guard = metrics::set_default_local_recorder(&recorder);
+
+        // One value between 1ms and 5ms and two values over 10ms
+        let metric = Metric::timer(MockHistogram::Rtt, vec![Dimension::By(vec![])]);
+        emit_values(metric, get_values(1, 3)?).await?;
+
+        let cpus = online_cpus().map_err(|(_, err)| err)?;
+        let mut expected = vec![0.005; cpus.len()];
+        expected.extend(vec![0.01; 2 * cpus.len()]);
+        let actual = recorder
+            .get_histogram(&Key::from_parts(MockHistogram::Rtt.name(), vec![]))
+            .expect("Rtt histogram should be registered with no labels");
+        assert_eq!(actual, expected);
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_emit_metrics_timer_over_last_bound() -> Result<(), anyhow::Error> {
+        let recorder = MockRecorder::new();
+        let _guard = metrics::set_default_local_recorder(&recorder);
+
+        // Every duration is over 10ms
+        let metric =
+            Metric::new(MockHistogram::Rtt, Unit::Milliseconds, vec![Dimension::ByCpu(vec![])]).with_nanoseconds();
+        emit_values(metric, get_values(3, 3)?).await?;
+
+        // Durations over the last bound are recorded with the last bound, in milliseconds
+        for cpu_id in online_cpus().map_err(|(_, err)| err)? {
+            let labels = vec![Label::new(METRIC_LABEL_CPU, cpu_id.to_string())];
+            let actual = recorder
+                .get_histogram(&Key::from_parts(MockHistogram::Rtt.name(), labels))
+                .expect("Rtt histogram should be registered with cpu label");
+            assert_eq!(actual, vec![10.0, 10.0]);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_new_reserves_le_label() -> Result<(), anyhow::Error> {
+        let le = || vec![Dimension::By(vec![Label::new(METRIC_LABEL_LE, "1")])];
+        let mut bpf = Ebpf::load(&[])?;
+        let mut new = |mode| {
+            let metric = Metric::new(MockHistogram::Rtt, Unit::Count, le()).with_mode(mode);
+            EbpfMetrics::new(&mut bpf, vec![metric], Duration::from_secs(60))
+        };
+
+        // The label is only added to buckets
+        new(HistogramMode::Samples)?;
+        assert!(matches!(
+            new(HistogramMode::Buckets),
+            Err(Error::ReservedLabel { metric, label }) if metric == "rtt" && label == METRIC_LABEL_LE
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_le_label() {
+        assert_eq!(le_label(&Buckets::Log2, 0, 1), Label::new(METRIC_LABEL_LE, "0"));
+        assert_eq!(le_label(&Buckets::Log2, 11, 1), Label::new(METRIC_LABEL_LE, "2047"));
+        assert_eq!(le_label(&Buckets::Log2, BPF_HISTOGRAM_BUCKETS - 1, 1), Label::new(METRIC_LABEL_LE, "+Inf"));
+
+        let buckets = Buckets::Explicit(RTT_BOUNDS);
+        assert_eq!(le_label(&buckets, 0, 1), Label::new(METRIC_LABEL_LE, "1000000"));
+        assert_eq!(le_label(&buckets, 2, 1), Label::new(METRIC_LABEL_LE, "10000000"));
+        assert_eq!(le_label(&buckets, 3, 1), Label::new(METRIC_LABEL_LE, "+Inf"));
+
+        // Bounds in nanoseconds are converted into the unit of the metric
+        let per_unit = nanoseconds_per(Unit::Seconds);
+        assert_eq!(le_label(&buckets, 0, per_unit), Label::new(METRIC_LABEL_LE, "0.001"));
+        assert_eq!(le_label(&buckets, 2, per_unit), Label::new(METRIC_LABEL_LE, "0.01"));
+        assert_eq!(le_label(&buckets, 3, per_unit), Label::new(METRIC_LABEL_LE, "+Inf"));
+        assert_eq!(nanoseconds_per(Unit::Milliseconds), 1_000_000);
+        assert_eq!(nanoseconds_per(Unit::Count), 1);
+    }
+}
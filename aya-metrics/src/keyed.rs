@@ -0,0 +1,877 @@
+//! Collects [`KeyedCounter`]s.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::{self, Debug},
+    slice,
+    sync::Arc,
+};
+
+use aya::{
+    maps::{MapError, PerCpuValues},
+    Pod,
+};
+use aya_metrics_common::{
+    kind, KeyedCounter, MeterKey, MeterKind, BPF_KEYED_KEY_SIZE, KEYED_COUNTERS_DROPPED_MAP_NAME,
+};
+use metrics::Label;
+use tokio::time::{Duration, Instant};
+
+use crate::{
+    counter::CounterState, take_map, AdditionalLabels, Collector, Dimension, Ebpf, Handles, Metric, PerCpuArray,
+    PerCpuHashMap,
+};
+
+/// The label of a series when no function converting keys into labels is set.
+const METRIC_LABEL_KEY: &str = "key";
+
+/// The value of each label of the overflow series.
+const METRIC_LABEL_VALUE_OTHER: &str = "other";
+
+/// The self-metric counting keys which are not counted in their own series.
+pub const METRIC_KEYED_DROPPED_KEYS: &str = "aya_metrics_keyed_dropped_keys";
+
+/// The label of [`METRIC_KEYED_DROPPED_KEYS`] holding the name of the keyed counter.
+pub(crate) const METRIC_LABEL_METRIC: &str = "metric";
+
+/// The label of [`METRIC_KEYED_DROPPED_KEYS`] holding the reason the key was dropped.
+pub(crate) const METRIC_LABEL_REASON: &str = "reason";
+
+/// The key was counted in the overflow series as the cardinality budget was exhausted.
+pub(crate) const REASON_OVERFLOW: &str = "overflow";
+
+/// The key was evicted from the BPF map, so any increments since the previous period were lost.
+const REASON_EVICTED: &str = "evicted";
+
+/// The key could not be inserted into the BPF map as it was full, so its increment was lost.
+///
+/// BPF counts each dropped increment, as it does not know whether the same key was dropped before.
+const REASON_FULL: &str = "full";
+
+/// A [`Metric`] for a [`KeyedCounter`], emitted as a counter for each key seen in BPF.
+pub type KeyedMetric<M> = Metric<M, kind::KeyedCounter>;
+
+/// Converts the bytes of a key into the labels of its series.
+pub(crate) type KeyLabels = Arc<dyn Fn(&MeterKey) -> AdditionalLabels + Send + Sync>;
+
+/// Called with the key of each metric of an expired series.
+pub(crate) type OnExpire = Arc<dyn Fn(&metrics::Key) + Send + Sync>;
+
+/// The cardinality budget and expiry of the series registered for keys.
+#[derive(Clone, Default)]
+pub(crate) struct SeriesLimits {
+    pub(crate) max_series: Option<usize>,
+    pub(crate) ttl: Option<Duration>,
+    pub(crate) on_expire: Option<OnExpire>,
+}
+
+/// Options of a [`KeyedMetric`].
+#[derive(Clone)]
+pub struct KeyedOptions {
+    labels: KeyLabels,
+    limits: SeriesLimits,
+}
+
+impl Default for KeyedOptions {
+    fn default() -> Self {
+        KeyedOptions {
+            labels: default_labels(),
+            limits: SeriesLimits::default(),
+        }
+    }
+}
+
+impl Debug for KeyedOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyedOptions")
+            .field("max_series", &self.limits.max_series)
+            .field("ttl", &self.limits.ttl)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Labels a series with a `key` label holding its key as hexadecimal.
+pub(crate) fn default_labels() -> KeyLabels {
+    Arc::new(|key| vec![Label::new(METRIC_LABEL_KEY, hex_label(&key.key))])
+}
+
+/// The key as hexadecimal, without the zeroes padding it to [`BPF_KEYED_KEY_SIZE`].
+fn hex_label(key: &[u8; BPF_KEYED_KEY_SIZE]) -> String {
+    let len = key.iter().rposition(|byte| *byte != 0).map_or(1, |position| position + 1);
+    key[..len].iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+impl<M: KeyedCounter> Metric<M, kind::KeyedCounter> {
+    /// Set the function converting the key of each series into labels.
+    ///
+    /// The key must have the same type as the key given to `keyed_counter` in BPF. The labels are added to the
+    /// labels of each dimension. Defaults to a `key` label holding the key as hexadecimal.
+    ///
+    /// Fails to compile if `K` is larger than [`BPF_KEYED_KEY_SIZE`].
+    pub fn with_labels<K: Pod>(mut self, labels: impl Fn(K) -> Vec<Label> + Send + Sync + 'static) -> Self {
+        self.options.labels = Arc::new(move |key| labels(key.key::<K>()));
+        self
+    }
+
+    /// Set the cardinality budget, the maximum number of keys registered as their own series.
+    ///
+    /// Once the budget is exhausted any new key is counted in a single overflow series, where the value of each label
+    /// of the key is `other`, and in the [`METRIC_KEYED_DROPPED_KEYS`] self-metric. A key evicted from the BPF map
+    /// frees its place in the budget. Defaults to no budget.
+    pub fn with_max_series(mut self, max_series: usize) -> Self {
+        self.options.limits.max_series = Some(max_series);
+        self
+    }
+
+    /// Set the idle time to live, after which a key whose value has not changed is expired.
+    ///
+    /// An expired key is deleted from the BPF map and its series is no longer emitted, which also frees its place in
+    /// the cardinality budget. The key is counted in a new series if it appears again. Defaults to never expiring.
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.options.limits.ttl = Some(ttl);
+        self
+    }
+
+    /// Set a hook which is called with the key of each metric of an expired series, see [`Metric::with_ttl`].
+    ///
+    /// The series of a key also expires when the key is evicted from the BPF map.
+    ///
+    /// The [metrics] crate has no way to unregister a metric, so this allows the application to remove the series from
+    /// its recorder or exporter.
+    pub fn on_expire(mut self, on_expire: impl Fn(&metrics::Key) + Send + Sync + 'static) -> Self {
+        self.options.limits.on_expire = Some(Arc::new(on_expire));
+        self
+    }
+}
+
+/// A series registered for a key.
+pub(crate) struct Series {
+    pub(crate) handles: Handles<metrics::Counter>,
+    /// The key of each registered metric, given to the expiry hook.
+    keys: Vec<metrics::Key>,
+}
+
+/// The counter state of a key in the BPF map.
+struct KeyState {
+    counter: CounterState,
+    /// The start of the period in which the value of the key last changed.
+    last_change: Instant,
+}
+
+/// The BPF maps holding the keys of keyed counters and counting their dropped increments.
+pub struct KeyedCounterMaps {
+    counters: PerCpuHashMap<MeterKey, u64>,
+    dropped: PerCpuArray<u64>,
+}
+
+/// Registered series along with the counter state for each key in the BPF map.
+pub struct KeyedCounterState {
+    /// The series of each key, bounded by the cardinality budget.
+    series: KeyedSeries,
+    /// The state of each key in the BPF map, bounded by the size of the map.
+    keys: HashMap<[u8; BPF_KEYED_KEY_SIZE], KeyState>,
+    /// Counts keys evicted from the BPF map.
+    evicted: metrics::Counter,
+    /// Counts increments dropped in BPF as the map was full.
+    full: metrics::Counter,
+    /// The number of increments dropped in BPF across all CPUs in the previous period.
+    prev_dropped: u64,
+    cpu_count: usize,
+}
+
+impl KeyedCounterState {
+    /// Stop emitting the series of a key, calling the expiry hook for each of its metrics.
+    fn expire<M: KeyedCounter>(&mut self, metric: &Metric<M, kind::KeyedCounter>, key: &[u8; BPF_KEYED_KEY_SIZE]) {
+        self.keys.remove(key);
+        self.series.expire(&metric.options.limits, key);
+    }
+}
+
+/// The series registered for keys, each as its own series until the cardinality budget is exhausted.
+pub(crate) struct KeyedSeries {
+    /// Each key registered as its own series, bounded by the cardinality budget.
+    registered: HashMap<[u8; BPF_KEYED_KEY_SIZE], Series>,
+    /// Handles of the overflow series, registered once the cardinality budget is exhausted.
+    overflow: Option<Handles<metrics::Counter>>,
+    /// Counts keys counted in the overflow series.
+    overflowed: metrics::Counter,
+    cpu_count: usize,
+}
+
+impl KeyedSeries {
+    /// Create the series of the keys of a metric, without registering any of them.
+    pub(crate) fn new<M: aya_metrics_common::Meter<K>, K: Collector<M>>(
+        metric: &Metric<M, K>,
+        cpu_count: usize,
+    ) -> Self {
+        KeyedSeries {
+            registered: HashMap::new(),
+            overflow: None,
+            overflowed: dropped_keys(metric, REASON_OVERFLOW),
+            cpu_count,
+        }
+    }
+
+    /// The handles of the series a key is counted in, registering the series if needed.
+    pub(crate) fn handles<M: aya_metrics_common::Meter<K>, K: Collector<M>>(
+        &mut self,
+        metric: &Metric<M, K>,
+        labels: &KeyLabels,
+        limits: &SeriesLimits,
+        key: &MeterKey,
+        cpus: &[u32],
+    ) -> Handles<metrics::Counter> {
+        if let Some(series) = self.registered.get(&key.key) {
+            return series.handles.clone();
+        }
+
+        let key_labels = labels(key);
+        if limits.max_series.is_none_or(|max_series| self.registered.len() < max_series) {
+            let series = register_series(metric, key_labels, cpus, self.cpu_count);
+            let handles = series.handles.clone();
+            self.registered.insert(key.key, series);
+            return handles;
+        }
+
+        self.overflowed.increment(1);
+        self.overflow
+            .get_or_insert_with(|| {
+                let other_labels = key_labels
+                    .iter()
+                    .map(|label| Label::new(label.key().to_string(), METRIC_LABEL_VALUE_OTHER))
+                    .collect();
+                register_series(metric, other_labels, cpus, self.cpu_count).handles
+            })
+            .clone()
+    }
+
+    /// Whether a key is registered as its own series.
+    pub(crate) fn contains(&self, key: &[u8; BPF_KEYED_KEY_SIZE]) -> bool {
+        self.registered.contains_key(key)
+    }
+
+    /// Stop emitting the series of a key, calling the expiry hook for each of its metrics.
+    pub(crate) fn expire(&mut self, limits: &SeriesLimits, key: &[u8; BPF_KEYED_KEY_SIZE]) {
+        // Keys counted in the overflow series were never registered
+        if let Some(series) = self.registered.remove(key) {
+            if let Some(on_expire) = &limits.on_expire {
+                series.keys.iter().for_each(|key| on_expire(key));
+            }
+        }
+    }
+}
+
+/// The [`METRIC_KEYED_DROPPED_KEYS`] self-metric of a metric, for keys dropped for the given reason.
+pub(crate) fn dropped_keys<M: aya_metrics_common::Meter<K>, K: Collector<M>>(
+    metric: &Metric<M, K>,
+    reason: &'static str,
+) -> metrics::Counter {
+    metrics::describe_counter!(
+        METRIC_KEYED_DROPPED_KEYS,
+        metrics::Unit::Count,
+        "Keys of keyed counters and heavy hitters which were not counted in their own series."
+    );
+    metrics::counter!(
+        METRIC_KEYED_DROPPED_KEYS,
+        METRIC_LABEL_METRIC => metric.meter.name(),
+        METRIC_LABEL_REASON => reason
+    )
+}
+
+/// Register a series, adding the labels of its key to each dimension.
+pub(crate) fn register_series<M: aya_metrics_common::Meter<K>, K: Collector<M>>(
+    metric: &Metric<M, K>,
+    key_labels: AdditionalLabels,
+    cpus: &[u32],
+    cpu_count: usize,
+) -> Series {
+    let dimensions = metric
+        .dimensions
+        .iter()
+        .map(|dimension| match dimension {
+            Dimension::By(labels) => Dimension::By([labels.clone(), key_labels.clone()].concat()),
+            Dimension::ByCpu(labels) => Dimension::ByCpu([labels.clone(), key_labels.clone()].concat()),
+        })
+        .collect();
+
+    let name = metric.meter.name();
+    let mut keys = Vec::new();
+    let handles = Handles::register(&dimensions, cpus, cpu_count, metrics::Counter::noop(), |labels| {
+        keys.push(metrics::Key::from_parts(name.clone(), labels.clone()));
+        metrics::counter!(name.clone(), labels)
+    });
+
+    Series { handles, keys }
+}
+
+impl<M: KeyedCounter> Collector<M> for kind::KeyedCounter {
+    type Map = KeyedCounterMaps;
+    type Options = KeyedOptions;
+    type State = KeyedCounterState;
+
+    fn take_map(bpf: &mut Ebpf) -> Result<KeyedCounterMaps, MapError> {
+        Ok(KeyedCounterMaps {
+            counters: take_map(bpf, MeterKind::KeyedCounter.map_name())?,
+            dropped: take_map(bpf, KEYED_COUNTERS_DROPPED_MAP_NAME)?,
+        })
+    }
+
+    fn max_entries(maps: &KeyedCounterMaps) -> Option<u32> {
+        Some(maps.dropped.len())
+    }
+
+    fn register(metric: &Metric<M, kind::KeyedCounter>, _cpus: &[u32], cpu_count: usize) -> KeyedCounterState {
+        metrics::describe_counter!(metric.meter.name(), metric.unit, metric.meter.description());
+
+        // Series are registered as their keys appear in the BPF map
+        KeyedCounterState {
+            series: KeyedSeries::new(metric, cpu_count),
+            keys: HashMap::new(),
+            evicted: dropped_keys(metric, REASON_EVICTED),
+            full: dropped_keys(metric, REASON_FULL),
+            prev_dropped: 0,
+            cpu_count,
+        }
+    }
+
+    fn collect(
+        metric: &Metric<M, kind::KeyedCounter>,
+        state: &mut KeyedCounterState,
+        maps: &mut KeyedCounterMaps,
+        cpus: &[u32],
+    ) -> Result<(), MapError> {
+        Self::collect_all(slice::from_ref(metric), slice::from_mut(state), maps, cpus)
+    }
+
+    fn collect_all(
+        metrics: &[Metric<M, kind::KeyedCounter>],
+        states: &mut [KeyedCounterState],
+        maps: &mut KeyedCounterMaps,
+        cpus: &[u32],
+    ) -> Result<(), MapError> {
+        // Iterate over every key once, the map is shared by all keyed counters
+        let mut entries: HashMap<u32, Vec<(MeterKey, PerCpuValues<u64>)>> = HashMap::new();
+        for entry in maps.counters.iter() {
+            let (key, values) = entry?;
+            entries.entry(key.index).or_default().push((key, values));
+        }
+
+        for (metric, state) in metrics.iter().zip(states) {
+            // Sum across all possible CPUs, as a CPU which went offline still holds its dropped increments
+            let dropped = maps.dropped.get(&metric.meter.index(), 0)?;
+            let dropped = dropped.iter().fold(0u64, |sum, value| sum.wrapping_add(*value));
+            // Fewer increments than in the previous period were dropped since the map was reset, such as by a new eBPF
+            // program, so all of them are counted
+            state.full.increment(dropped.checked_sub(state.prev_dropped).unwrap_or(dropped));
+            state.prev_dropped = dropped;
+
+            let entries = entries.remove(&metric.meter.index()).unwrap_or_default();
+            collect_entries(metric, state, entries, &mut maps.counters, cpus)?;
+        }
+        Ok(())
+    }
+}
+
+/// Emit the entries of the BPF map holding the keys of a metric for a single period.
+fn collect_entries<M: KeyedCounter>(
+    metric: &Metric<M, kind::KeyedCounter>,
+    state: &mut KeyedCounterState,
+    entries: Vec<(MeterKey, PerCpuValues<u64>)>,
+    map: &mut PerCpuHashMap<MeterKey, u64>,
+    cpus: &[u32],
+) -> Result<(), MapError> {
+    let now = Instant::now();
+    let mut idle = Vec::new();
+
+    // Keys which are no longer in the map were evicted, so their series expire before new keys take their place in the
+    // cardinality budget
+    let seen: HashSet<_> = entries.iter().map(|(key, _)| key.key).collect();
+    let evicted: Vec<_> = state.keys.keys().filter(|key| !seen.contains(*key)).copied().collect();
+    state.evicted.increment(evicted.len() as u64);
+    for key in evicted {
+        state.expire(metric, &key);
+    }
+
+    for (key, values) in entries {
+        let mut changed = false;
+        match state.keys.get_mut(&key.key) {
+            // The key was evicted and inserted again since the previous period
+            Some(key_state) if key_state.counter.has_reset(&values, cpus) => {
+                state.evicted.increment(1);
+                key_state.counter.reset();
+                changed = true;
+            }
+            Some(_) => {}
+            None => {
+                let handles = state
+                    .series
+                    .handles(metric, &metric.options.labels, &metric.options.limits, &key, cpus);
+                let key_state = KeyState {
+                    counter: CounterState::new(handles, state.cpu_count),
+                    last_change: now,
+                };
+                state.keys.insert(key.key, key_state);
+            }
+        }
+
+        if let Some(key_state) = state.keys.get_mut(&key.key) {
+            if key_state.counter.emit(&values, cpus) > 0 || changed {
+                key_state.last_change = now;
+            }
+            if metric
+                .options
+                .limits
+                .ttl
+                .is_some_and(|ttl| now.duration_since(key_state.last_change) >= ttl)
+            {
+                idle.push(key);
+            }
+        }
+    }
+
+    // Delete idle keys from the map, increments between reading and deleting a key are lost
+    for key in idle {
+        if map.remove(&key).is_err() && map.get(&key, 0).is_ok() {
+            // Keep the series and try again in the next period
+            continue;
+        }
+        state.expire(metric, &key.key);
+    }
+
+    Ok(())
+}
+
+// GRCOV_STOP_COVERAGE
+#[cfg(test)]
+mod test {
+    use std::{net::Ipv4Addr, sync::Arc};
+
+    use aya::{
+        maps::PerCpuValues,
+        util::{nr_cpus, online_cpus},
+    };
+    use aya_metrics_mocks::{PerCpuArray, PerCpuHashMap};
+    use metrics::{Key, SharedString, Unit};
+    use tokio::time::{self, Duration};
+
+    use super::*;
+    use crate::{mocks::metrics::MockRecorder, EbpfMetrics, METRIC_LABEL_CPU};
+
+    const METRIC_LABEL_SOURCE: &str = "source";
+
+    #[derive(Copy, Clone, Debug)]
+    enum MockKeyedCounter {
+        PacketsBySource,
+        Other,
+    }
+
+    impl KeyedCounter for MockKeyedCounter {
+        fn name(self) -> SharedString {
+            match self {
+                MockKeyedCounter::PacketsBySource => "packets_by_source".into(),
+                MockKeyedCounter::Other => "other".into(),
+            }
+        }
+
+        fn index(&self) -> u32 {
+            match self {
+                MockKeyedCounter::PacketsBySource => 0,
+                MockKeyedCounter::Other => 1,
+            }
+        }
+    }
+
+    fn get_packets_metric() -> KeyedMetric<MockKeyedCounter> {
+        KeyedMetric::new(
+            MockKeyedCounter::PacketsBySource,
+            Unit::Count,
+            vec![Dimension::By(vec![]), Dimension::ByCpu(vec![])],
+        )
+        .with_labels(|address: [u8; 4]| vec![Label::new(METRIC_LABEL_SOURCE, Ipv4Addr::from(address).to_string())])
+    }
+
+    fn get_counter(recorder: &MockRecorder, labels: Vec<Label>) -> Option<u64> {
+        recorder.get_counter(&Key::from_parts(MockKeyedCounter::PacketsBySource.name(), labels))
+    }
+
+    /// The maps of keyed counters sharing the keys of `map`, without any dropped increments.
+    fn get_maps(map: &PerCpuHashMap<MeterKey, u64>) -> KeyedCounterMaps {
+        KeyedCounterMaps {
+            counters: map.clone(),
+            dropped: PerCpuArray::new(2, 0),
+        }
+    }
+
+    fn set(
+        map: &mut PerCpuHashMap<MeterKey, u64>,
+        counter: MockKeyedCounter,
+        address: [u8; 4],
+        value: u64,
+    ) -> Result<(), anyhow::Error> {
+        let key = MeterKey::new(counter.index(), &address);
+        map.insert(key, PerCpuValues::try_from(vec![value; nr_cpus().map_err(|(_, err)| err)?])?, 0)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_hex_label() {
+        assert_eq!(hex_label(&MeterKey::new(0, &[0x0au8, 0, 0, 0xff]).key), "0a0000ff");
+        assert_eq!(hex_label(&MeterKey::new(0, &0u32).key), "00");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_emit_metrics_registers_keys() -> Result<(), anyhow::Error> {
+        let recorder = MockRecorder::new();
+        let _guard = metrics::set_default_local_recorder(&recorder);
+
+        let mut map = PerCpuHashMap::new();
+        set(&mut map, MockKeyedCounter::PacketsBySource, [10, 0, 0, 1], 1)?;
+        set(&mut map, MockKeyedCounter::Other, [10, 0, 0, 3], 1)?;
+
+        tokio::spawn(EbpfMetrics::emit_metrics(get_maps(&map), vec![get_packets_metric()], Duration::from_secs(60)));
+
+        // Give the task a chance to run
+        tokio::task::yield_now().await;
+
+        // Validate keys present at start up are registered (time=0s)
+        let cpus = online_cpus().map_err(|(_, err)| err)?;
+        let source = |address: &str| Label::new(METRIC_LABEL_SOURCE, address.to_string());
+        assert_eq!(get_counter(&recorder, vec![source("10.0.0.1")]), Some(cpus.len() as u64));
+        for cpu_id in &cpus {
+            let labels = vec![Label::new(METRIC_LABEL_CPU, cpu_id.to_string()), source("10.0.0.1")];
+            assert_eq!(get_counter(&recorder, labels), Some(1));
+        }
+        // Keys of other keyed counters are not registered
+        assert_eq!(get_counter(&recorder, vec![source("10.0.0.3")]), None);
+
+        // Increment an existing key and insert a new key
+        set(&mut map, MockKeyedCounter::PacketsBySource, [10, 0, 0, 1], 5)?;
+        set(&mut map, MockKeyedCounter::PacketsBySource, [10, 0, 0, 2], 2)?;
+        // Time travel 60 seconds forward!
+        time::advance(Duration::from_secs(60)).await;
+        // Give the task a chance to run
+        tokio::task::yield_now().await;
+
+        // Validate both series (time=60s)
+        assert_eq!(get_counter(&recorder, vec![source("10.0.0.1")]), Some(5 * cpus.len() as u64));
+        assert_eq!(get_counter(&recorder, vec![source("10.0.0.2")]), Some(2 * cpus.len() as u64));
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_emit_metrics_shares_map() -> Result<(), anyhow::Error> {
+        let recorder = MockRecorder::new();
+        let _guard = metrics::set_default_local_recorder(&recorder);
+
+        let mut map = PerCpuHashMap::new();
+        set(&mut map, MockKeyedCounter::PacketsBySource, [10, 0, 0, 1], 1)?;
+        set(&mut map, MockKeyedCounter::Other, [10, 0, 0, 3], 2)?;
+
+        let other = KeyedMetric::new(MockKeyedCounter::Other, Unit::Count, vec![Dimension::By(vec![])]);
+        let metrics = vec![get_packets_metric(), other];
+        tokio::spawn(EbpfMetrics::emit_metrics(get_maps(&map), metrics, Duration::from_secs(60)));
+
+        // Give the task a chance to run
+        tokio::task::yield_now().await;
+
+        // Validate each metric only registers its own keys from the same read of the map (time=0s)
+        let cpus = online_cpus().map_err(|(_, err)| err)?.len() as u64;
+        let source = |address: &str| Label::new(METRIC_LABEL_SOURCE, address.to_string());
+        assert_eq!(get_counter(&recorder, vec![source("10.0.0.1")]), Some(cpus));
+        let other = Key::from_parts(MockKeyedCounter::Other.name(), vec![Label::new(METRIC_LABEL_KEY, "0a000003")]);
+        assert_eq!(recorder.get_counter(&other), Some(2 * cpus));
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_emit_metrics_default_labels() -> Result<(), anyhow::Error> {
+        let recorder = MockRecorder::new();
+        let _guard = metrics::set_default_local_recorder(&recorder);
+
+        let mut map = PerCpuHashMap::new();
+        set(&mut map, MockKeyedCounter::PacketsBySource, [10, 0, 0, 1], 1)?;
+
+        tokio::spawn(EbpfMetrics::emit_metrics(
+            get_maps(&map),
+            vec![KeyedMetric::new(MockKeyedCounter::PacketsBySource, Unit::Count, vec![Dimension::By(vec![])])],
+            Duration::from_secs(60),
+        ));
+
+        // Give the task a chance to run
+        tokio::task::yield_now().await;
+
+        let labels = vec![Label::new(METRIC_LABEL_KEY, "0a000001")];
+        let cpus = online_cpus().map_err(|(_, err)| err)?;
+        assert_eq!(get_counter(&recorder, labels), Some(cpus.len() as u64));
+
+        Ok(())
+    }
+
+    fn get_dropped(recorder: &MockRecorder, reason: &str) -> Option<u64> {
+        let labels = vec![
+            Label::new(METRIC_LABEL_METRIC, MockKeyedCounter::PacketsBySource.name()),
+            Label::new(METRIC_LABEL_REASON, reason.to_string()),
+        ];
+        recorder.get_counter(&Key::from_parts(METRIC_KEYED_DROPPED_KEYS, labels))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_emit_metrics_overflow() -> Result<(), anyhow::Error> {
+        let recorder = MockRecorder::new();
+        let _guard = metrics::set_default_local_recorder(&recorder);
+
+        let mut map = PerCpuHashMap::new();
+        set(&mut map, MockKeyedCounter::PacketsBySource, [10, 0, 0, 1], 1)?;
+
+        tokio::spawn(EbpfMetrics::emit_metrics(
+            get_maps(&map),
+            vec![get_packets_metric().with_max_series(1)],
+            Duration::from_secs(60),
+        ));
+
+        // Give the task a chance to run
+        tokio::task::yield_now().await;
+
+        // Insert new keys once the budget is exhausted
+        set(&mut map, MockKeyedCounter::PacketsBySource, [10, 0, 0, 2], 2)?;
+        set(&mut map, MockKeyedCounter::PacketsBySource, [10, 0, 0, 3], 3)?;
+        // Time travel 60 seconds forward!
+        time::advance(Duration::from_secs(60)).await;
+        // Give the task a chance to run
+        tokio::task::yield_now().await;
+
+        // Validate new keys are counted in the overflow series (time=60s)
+        let cpus = online_cpus().map_err(|(_, err)| err)?;
+        let source = |address: &str| Label::new(METRIC_LABEL_SOURCE, address.to_string());
+        assert_eq!(get_counter(&recorder, vec![source("10.0.0.1")]), Some(cpus.len() as u64));
+        assert_eq!(get_counter(&recorder, vec![source("10.0.0.2")]), None);
+        assert_eq!(get_counter(&recorder, vec![source("10.0.0.3")]), None);
+        assert_eq!(get_counter(&recorder, vec![source("other")]), Some(5 * cpus.len() as u64));
+        assert_eq!(get_dropped(&recorder, REASON_OVERFLOW), Some(2));
+        assert_eq!(get_dropped(&recorder, REASON_EVICTED), Some(0));
+        assert_eq!(get_dropped(&recorder, REASON_FULL), Some(0));
+
+        // Increment a key in the overflow series
+        set(&mut map, MockKeyedCounter::PacketsBySource, [10, 0, 0, 3], 4)?;
+        // Time travel 60 seconds forward!
+        time::advance(Duration::from_secs(60)).await;
+        // Give the task a chance to run
+        tokio::task::yield_now().await;
+
+        // Validate keys are only dropped once (time=120s)
+        assert_eq!(get_counter(&recorder, vec![source("other")]), Some(6 * cpus.len() as u64));
+        assert_eq!(get_dropped(&recorder, REASON_OVERFLOW), Some(2));
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_emit_metrics_evicted() -> Result<(), anyhow::Error> {
+        let recorder = MockRecorder::new();
+        let _guard = metrics::set_default_local_recorder(&recorder);
+
+        let mut map = PerCpuHashMap::new();
+        set(&mut map, MockKeyedCounter::PacketsBySource, [10, 0, 0, 1], 5)?;
+
+        tokio::spawn(EbpfMetrics::emit_metrics(get_maps(&map), vec![get_packets_metric()], Duration::from_secs(60)));
+
+        // Give the task a chance to run
+        tokio::task::yield_now().await;
+
+        // Evict the key
+        map.remove(&MeterKey::new(MockKeyedCounter::PacketsBySource.index(), &[10u8, 0, 0, 1]))?;
+        // Time travel 60 seconds forward!
+        time::advance(Duration::from_secs(60)).await;
+        // Give the task a chance to run
+        tokio::task::yield_now().await;
+
+        // Validate the eviction is counted (time=60s)
+        let cpus = online_cpus().map_err(|(_, err)| err)?;
+        let labels = vec![Label::new(METRIC_LABEL_SOURCE, "10.0.0.1")];
+        assert_eq!(get_counter(&recorder, labels.clone()), Some(5 * cpus.len() as u64));
+        assert_eq!(get_dropped(&recorder, REASON_EVICTED), Some(1));
+
+        // Insert the key again, which registers its series again
+        set(&mut map, MockKeyedCounter::PacketsBySource, [10, 0, 0, 1], 2)?;
+        // Time travel 60 seconds forward!
+        time::advance(Duration::from_secs(60)).await;
+        // Give the task a chance to run
+        tokio::task::yield_now().await;
+
+        // Validate the new value is added (time=120s)
+        assert_eq!(get_counter(&recorder, labels.clone()), Some(7 * cpus.len() as u64));
+        assert_eq!(get_dropped(&recorder, REASON_EVICTED), Some(1));
+
+        // Evict and insert the key again within a single period
+        set(&mut map, MockKeyedCounter::PacketsBySource, [10, 0, 0, 1], 1)?;
+        // Time travel 60 seconds forward!
+        time::advance(Duration::from_secs(60)).await;
+        // Give the task a chance to run
+        tokio::task::yield_now().await;
+
+        // Validate the value is counted from zero (time=180s)
+        assert_eq!(get_counter(&recorder, labels), Some(8 * cpus.len() as u64));
+        assert_eq!(get_dropped(&recorder, REASON_EVICTED), Some(2));
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_emit_metrics_expires_evicted_keys() -> Result<(), anyhow::Error> {
+        let recorder = MockRecorder::new();
+        let _guard = metrics::set_default_local_recorder(&recorder);
+
+        let mut map = PerCpuHashMap::new();
+        set(&mut map, MockKeyedCounter::PacketsBySource, [10, 0, 0, 1], 1)?;
+
+        let expired = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let on_expire = {
+            let expired = expired.clone();
+            move |key: &Key| expired.lock().unwrap().push(key.clone())
+        };
+        tokio::spawn(EbpfMetrics::emit_metrics(
+            get_maps(&map),
+            vec![get_packets_metric().with_max_series(1).on_expire(on_expire)],
+            Duration::from_secs(60),
+        ));
+
+        // Give the task a chance to run
+        tokio::task::yield_now().await;
+
+        // Evict the key, which holds the only place in the budget, and insert a new key
+        map.remove(&MeterKey::new(MockKeyedCounter::PacketsBySource.index(), &[10u8, 0, 0, 1]))?;
+        set(&mut map, MockKeyedCounter::PacketsBySource, [10, 0, 0, 2], 2)?;
+        // Time travel 60 seconds forward!
+        time::advance(Duration::from_secs(60)).await;
+        // Give the task a chance to run
+        tokio::task::yield_now().await;
+
+        // Validate the series of the evicted key expired (time=60s)
+        let cpus = online_cpus().map_err(|(_, err)| err)?;
+        let source = |address: &str| Label::new(METRIC_LABEL_SOURCE, address.to_string());
+        {
+            let expired = expired.lock().unwrap();
+            assert_eq!(expired.len(), 1 + cpus.len());
+            let key = Key::from_parts(MockKeyedCounter::PacketsBySource.name(), vec![source("10.0.0.1")]);
+            assert!(expired.contains(&key));
+        }
+        assert_eq!(get_dropped(&recorder, REASON_EVICTED), Some(1));
+
+        // Validate the new key is counted in its own series, as the budget was freed
+        assert_eq!(get_counter(&recorder, vec![source("10.0.0.2")]), Some(2 * cpus.len() as u64));
+        assert_eq!(get_counter(&recorder, vec![source("other")]), None);
+        assert_eq!(get_dropped(&recorder, REASON_OVERFLOW), Some(0));
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_emit_metrics_expires_idle_keys() -> Result<(), anyhow::Error> {
+        let recorder = MockRecorder::new();
+        let _guard = metrics::set_default_local_recorder(&recorder);
+
+        let mut map = PerCpuHashMap::new();
+        set(&mut map, MockKeyedCounter::PacketsBySource, [10, 0, 0, 1], 1)?;
+        set(&mut map, MockKeyedCounter::PacketsBySource, [10, 0, 0, 2], 1)?;
+
+        let expired = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let on_expire = {
+            let expired = expired.clone();
+            move |key: &Key| expired.lock().unwrap().push(key.clone())
+        };
+        tokio::spawn(EbpfMetrics::emit_metrics(
+            get_maps(&map),
+            vec![get_packets_metric().with_ttl(Duration::from_secs(120)).on_expire(on_expire)],
+            Duration::from_secs(60),
+        ));
+
+        // Give the task a chance to run
+        tokio::task::yield_now().await;
+
+        // Only the second key changes
+        for value in [2, 3] {
+            set(&mut map, MockKeyedCounter::PacketsBySource, [10, 0, 0, 2], value)?;
+            // Time travel 60 seconds forward!
+            time::advance(Duration::from_secs(60)).await;
+            // Give the task a chance to run
+            tokio::task::yield_now().await;
+        }
+
+        // Validate the idle key was deleted and its series expired (time=120s)
+        let cpus = online_cpus().map_err(|(_, err)| err)?;
+        let source = |address: &str| Label::new(METRIC_LABEL_SOURCE, address.to_string());
+        assert_eq!(map.len(), 1);
+        let expected = [
+            vec![source("10.0.0.1")],
+            vec![Label::new(METRIC_LABEL_CPU, cpus[0].to_string()), source("10.0.0.1")],
+        ];
+        {
+            let expired = expired.lock().unwrap();
+            assert_eq!(expired.len(), 1 + cpus.len());
+            for labels in expected {
+                assert!(expired.contains(&Key::from_parts(MockKeyedCounter::PacketsBySource.name(), labels)));
+            }
+        }
+
+        // Insert the expired key again
+        set(&mut map, MockKeyedCounter::PacketsBySource, [10, 0, 0, 1], 4)?;
+        // Time travel 60 seconds forward!
+        time::advance(Duration::from_secs(60)).await;
+        // Give the task a chance to run
+        tokio::task::yield_now().await;
+
+        // Validate the key is counted from zero and the deletion was not counted as an eviction (time=180s)
+        assert_eq!(get_counter(&recorder, vec![source("10.0.0.1")]), Some(5 * cpus.len() as u64));
+        assert_eq!(get_counter(&recorder, vec![source("10.0.0.2")]), Some(3 * cpus.len() as u64));
+        assert_eq!(get_dropped(&recorder, REASON_EVICTED), Some(0));
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_emit_metrics_full() -> Result<(), anyhow::Error> {
+        let recorder = MockRecorder::new();
+        let _guard = metrics::set_default_local_recorder(&recorder);
+
+        let maps = get_maps(&PerCpuHashMap::new());
+        let mut dropped = maps.dropped.clone();
+        tokio::spawn(EbpfMetrics::emit_metrics(maps, vec![get_packets_metric()], Duration::from_secs(60)));
+
+        // Give the task a chance to run
+        tokio::task::yield_now().await;
+        assert_eq!(get_dropped(&recorder, REASON_FULL), Some(0));
+
+        // Drop increments on every possible CPU, and on the other keyed counter
+        let cpu_count = nr_cpus().map_err(|(_, err)| err)?;
+        for (index, value) in [(0, 3), (1, 5)] {
+            dropped.set(index, PerCpuValues::try_from(vec![value; cpu_count])?, 0)?;
+        }
+        // Time travel 60 seconds forward!
+        time::advance(Duration::from_secs(60)).await;
+        // Give the task a chance to run
+        tokio::task::yield_now().await;
+
+        // Validate only the increments dropped since the previous period are counted (time=60s)
+        assert_eq!(get_dropped(&recorder, REASON_FULL), Some(3 * cpu_count as u64));
+
+        // Time travel 60 seconds forward!
+        time::advance(Duration::from_secs(60)).await;
+        // Give the task a chance to run
+        tokio::task::yield_now().await;
+        // Validate nothing is counted when no increments were dropped (time=120s)
+        assert_eq!(get_dropped(&recorder, REASON_FULL), Some(3 * cpu_count as u64));
+
+        // Count from zero again, such as after a new eBPF program pinned the map
+        dropped.set(0, PerCpuValues::try_from(vec![1; cpu_count])?, 0)?;
+        // Time travel 60 seconds forward!
+        time::advance(Duration::from_secs(60)).await;
+        // Give the task a chance to run
+        tokio::task::yield_now().await;
+        // Validate the lower values are counted in full rather than underflowing (time=180s)
+        assert_eq!(get_dropped(&recorder, REASON_FULL), Some(4 * cpu_count as u64));
+
+        Ok(())
+    }
+}
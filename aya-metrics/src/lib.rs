@@ -2,8 +2,9 @@
 //!
 //! This is a generalized user space implementation to collect custom metrics from an eBPF program.
 //!
-//! The module provides the [EbpfMetrics] type, which reads counters created in eBPF and emits them using the [metrics]
-//! crate. Any implementation of the [metrics::recorder::Recorder] trait can be used once it is set as the global recorder.
+//! The module provides the [EbpfMetrics] type, which reads meters created in eBPF, of any of the kinds of
+//! [`MeterKind`](aya_metrics_common::MeterKind), and emits them using the [metrics] crate. Any implementation of the
+//! [metrics::recorder::Recorder] trait can be used once it is set as the global recorder.
 //!
 //! # Example:
 //!
@@ -13,6 +14,7 @@
 //! # use std::time::Duration;
 //! # use aya_metrics::{EbpfMetrics, Dimension, Metric};
 //! # use metrics::{Label, Unit};
+//! use aya_metrics_common::SharedString;
 //!
 //! #[derive(Copy, Clone)]
 //! enum MyCounter {
@@ -21,10 +23,10 @@
 //! }
 //!
 //! impl aya_metrics_common::Counter for MyCounter {
-//!     fn name(self) -> String {
+//!     fn name(self) -> SharedString {
 //!         match self {
-//!             MyCounter::Packets => "packets_counter".to_string(),
-//!             MyCounter::Bytes => "bytes_counter".to_string(),
+//!             MyCounter::Packets => "packets_counter".into(),
+//!             MyCounter::Bytes => "bytes_counter".into(),
 //!         }
 //!     }
 //!
@@ -65,181 +67,379 @@
 //! counter(MyCounter::Packets, 1);
 //! ```
 //!
-use std::{io, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Debug,
+    io,
+};
 
 #[cfg(not(feature = "mocks"))]
-use aya::Ebpf;
 use aya::{
-    maps::MapError,
+    maps::{Map, MapData},
+    Ebpf,
+};
+use aya::{
+    maps::{MapError, PerCpuValues},
+    sys::SyscallError,
     util::{nr_cpus, online_cpus},
+    Pod,
 };
-use aya_metrics_common::Meter;
+use aya_metrics_common::{kind, IntoEnumIterator, Meter};
 #[cfg(feature = "mocks")]
-use aya_metrics_mocks::{Ebpf, PerCpuArray};
-use futures::{lock::Mutex, stream::FuturesUnordered, StreamExt};
-use metrics::{Counter, Label, Unit};
+use aya_metrics_mocks::{Array, Ebpf, Map, MapData, PerCpuArray, PerCpuHashMap};
+use metrics::{Label, SharedString, Unit};
 use thiserror::Error;
 use tokio::time::{self, Duration};
 
+mod batch;
+mod compound_counter;
+mod counter;
+mod distinct_count;
+mod gauge;
+mod heavy_hitter;
+mod histogram;
+mod keyed;
+mod mmap;
+mod open;
+mod up_down_counter;
+mod watermark;
+
+pub use gauge::GaugeMerge;
+pub use heavy_hitter::HeavyHitterMetric;
+pub use histogram::HistogramMode;
+pub use keyed::{KeyedMetric, METRIC_KEYED_DROPPED_KEYS};
+
+#[cfg(not(feature = "mocks"))]
+type Array<V> = aya::maps::Array<aya::maps::MapData, V>;
 #[cfg(not(feature = "mocks"))]
 type PerCpuArray<V> = aya::maps::PerCpuArray<aya::maps::MapData, V>;
+#[cfg(not(feature = "mocks"))]
+type PerCpuHashMap<K, V> = aya::maps::PerCpuHashMap<aya::maps::MapData, K, V>;
 
 type AdditionalLabels = Vec<Label>;
 
 const METRIC_LABEL_CPU: &str = "cpu";
 
-/// Defines the dimension of a particular [`Metric`].
-#[derive(Clone, Debug)]
-pub enum Dimension {
-    /// Dimension with additional labels.
-    By(AdditionalLabels),
-    /// Dimension with cpu and additional labels.
-    ByCpu(AdditionalLabels),
-}
+pub use aya_metrics_common::Dimension;
 
 type Dimensions = Vec<Dimension>;
 
+/// Collects a kind of [`Meter`] from its BPF map and emits it using the [metrics] crate.
+///
+/// This is implemented for each marker in [`aya_metrics_common::kind`].
+pub trait Collector<M: Meter<Self>>: Sized {
+    /// The BPF maps holding meters of this kind.
+    type Map;
+
+    /// Options which are specific to this kind and can be set per [`Metric`].
+    type Options: Clone + Debug + Default;
+
+    /// The registered metric handles along with any state kept between periods.
+    #[doc(hidden)]
+    type State;
+
+    /// Take ownership of the BPF maps holding meters of this kind.
+    #[doc(hidden)]
+    fn take_map(bpf: &mut Ebpf) -> Result<Self::Map, MapError>;
+
+    /// Ensure the meters were laid out in the BPF maps in the same way as in the eBPF program.
+    ///
+    /// This is only checked when the eBPF program embeds its layout.
+    #[doc(hidden)]
+    fn verify_layout(_bpf: &mut Ebpf) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// The number of entries of the BPF map, which the index of every meter must be less than.
+    ///
+    /// This is `None` when meters are not stored at their index, such as in a hash map.
+    #[doc(hidden)]
+    fn max_entries(_map: &Self::Map) -> Option<u32> {
+        None
+    }
+
+    /// The labels added to every dimension of a metric, which the dimensions must not set themselves.
+    ///
+    /// The `cpu` label of [`Dimension::ByCpu`] is reserved for every kind.
+    #[doc(hidden)]
+    fn reserved_labels(_metric: &Metric<M, Self>) -> &'static [&'static str] {
+        &[]
+    }
+
+    /// Describe and register a metric for each of its dimensions.
+    #[doc(hidden)]
+    fn register(metric: &Metric<M, Self>, cpus: &[u32], cpu_count: usize) -> Self::State;
+
+    /// Read the values of a metric from the BPF map and emit them for a single period.
+    #[doc(hidden)]
+    fn collect(
+        metric: &Metric<M, Self>,
+        state: &mut Self::State,
+        map: &mut Self::Map,
+        cpus: &[u32],
+    ) -> Result<(), MapError>;
+
+    /// Read the values of every metric from the BPF map and emit them for a single period.
+    ///
+    /// Kinds whose meters share the entries of a map, such as the keys of a hash map, read the map once for all of
+    /// them rather than once per metric.
+    #[doc(hidden)]
+    fn collect_all(
+        metrics: &[Metric<M, Self>],
+        states: &mut [Self::State],
+        map: &mut Self::Map,
+        cpus: &[u32],
+    ) -> Result<(), MapError> {
+        for (metric, state) in metrics.iter().zip(states) {
+            Self::collect(metric, state, map, cpus)?;
+        }
+        Ok(())
+    }
+}
+
 /// Defines a metric that [`EbpfMetrics`] can report on.
 #[derive(Debug)]
-pub struct Metric<M: Meter> {
+pub struct Metric<M: Meter<K>, K: Collector<M> = kind::Counter> {
     /// The meter to take values from.
     meter: M,
     /// The unit with which to emit the metric.
     unit: Unit,
     /// The dimensions with which to emit the metric.
     dimensions: Dimensions,
+    /// Options specific to the kind of meter.
+    options: K::Options,
 }
 
-impl<M: Meter> Metric<M> {
+impl<M: Meter<K>, K: Collector<M>> Metric<M, K> {
     /// Create a new [`Metric`]
     pub fn new(meter: M, unit: Unit, dimensions: Dimensions) -> Self {
         Metric {
             meter,
             unit,
             dimensions,
+            options: K::Options::default(),
         }
     }
-}
 
-/// Emits custom metrics generated by an eBPF program using the [metrics] crate.
-pub struct EbpfMetrics<M: Meter> {
-    counters: PerCpuArray<u64>,
-    metrics: Vec<Metric<M>>,
-    period: Duration,
-}
-
-impl<M: Meter> EbpfMetrics<M> {
-    /// Create [`EbpfMetrics<M>`] from [`Ebpf`] for specific metrics.
+    /// Create a new [`Metric`] with the unit and default dimensions declared by the meter.
     ///
-    /// When `EbpfMetrics<M>::run()` is invoked metrics will be periodically emitted with the given recorder.
-    pub fn new(bpf: &mut Ebpf, metrics: Vec<Metric<M>>, period: Duration) -> Result<EbpfMetrics<M>, Error> {
-        // Take ownership of the BPF counters map
-        let counters = bpf
-            .take_map(M::kind().map_name())
-            .ok_or(aya::maps::MapError::InvalidName {
-                name: M::kind().map_name().to_string(),
-            })
-            .and_then(PerCpuArray::try_from)
-            .map_err(Error::MapError)?;
-
-        Ok(EbpfMetrics {
-            counters,
-            metrics,
-            period,
-        })
+    /// The unit defaults to [`Unit::Count`] when the meter declares none, see [`Meter::unit`] and
+    /// [`Meter::default_dimensions`]. Either may still be overridden with [`Metric::with_unit`] and
+    /// [`Metric::with_dimensions`].
+    pub fn from_meter(meter: M) -> Self {
+        Metric::new(meter, meter.unit().unwrap_or(Unit::Count), meter.default_dimensions())
     }
 
-    /// Periodically emit metrics
-    pub async fn run(self) -> Result<(), Error> {
-        // Share the counters amongst the futures.
-        let counters = Arc::new(Mutex::new(self.counters));
-
-        // Create a future for each metric.
-        let mut futures = FuturesUnordered::new();
-        for metric in self.metrics {
-            futures.push(EbpfMetrics::emit_metrics(counters.clone(), metric, self.period))
-        }
+    /// Set the unit with which to emit the metric.
+    pub fn with_unit(mut self, unit: Unit) -> Self {
+        self.unit = unit;
+        self
+    }
 
-        // Gracefully terminate if any future unexpectedly terminates and propagate any errors.
-        // Remaining futures will be cancelled when dropped.
-        futures.select_next_some().await
+    /// Set the dimensions with which to emit the metric.
+    pub fn with_dimensions(mut self, dimensions: Dimensions) -> Self {
+        self.dimensions = dimensions;
+        self
     }
+}
 
-    async fn emit_metrics(
-        bpf_counters: Arc<Mutex<PerCpuArray<u64>>>,
-        metric: Metric<M>,
-        period: Duration,
-    ) -> Result<(), Error> {
-        metrics::describe_counter!(metric.meter.name(), metric.unit.clone(), metric.meter.description());
+impl<M: Meter<K> + IntoEnumIterator, K: Collector<M>> Metric<M, K> {
+    /// Create a [`Metric`] for every meter of an enumeration, with the same unit and dimensions.
+    ///
+    /// Meters added to the enumeration are reported without changing user space code. The enumeration must implement
+    /// [`IntoEnumIterator`], such as by deriving `strum_macros::EnumIter`.
+    pub fn all(unit: Unit, dimensions: Dimensions) -> Vec<Self> {
+        M::iter().map(|meter| Metric::new(meter, unit, dimensions.clone())).collect()
+    }
+}
 
-        let mut interval = time::interval(period);
-        let cpu_count = nr_cpus().map_err(|(_, err)| Error::InvalidPossibleCpu(err))?;
-        let cpus = online_cpus().map_err(|(_, err)| Error::InvalidOnlineCpu(err))?;
+/// Metric handles registered for each [`Dimension`] of a [`Metric`].
+#[derive(Clone)]
+struct Handles<H> {
+    /// A handle for each [`Dimension::By`].
+    by: Vec<H>,
+    /// Handles indexed by CPU for each [`Dimension::ByCpu`].
+    by_cpu: Vec<Vec<H>>,
+}
 
-        // Pre-register all counters and store their handles for better performance
-        let mut counter_handles = Vec::new();
-        let mut counter_handles_by_cpu = Vec::new();
-        for dimension in &metric.dimensions {
+impl<H: Clone> Handles<H> {
+    /// Register a handle for each dimension, and for each online CPU where the dimension is by CPU.
+    ///
+    /// CPUs which are not online are given the `noop` handle.
+    fn register(
+        dimensions: &Dimensions,
+        cpus: &[u32],
+        cpu_count: usize,
+        noop: H,
+        mut register: impl FnMut(AdditionalLabels) -> H,
+    ) -> Self {
+        let mut by = Vec::new();
+        let mut by_cpu = Vec::new();
+        for dimension in dimensions {
             match dimension {
                 Dimension::By(labels) => {
-                    let handle = metrics::counter!(metric.meter.name(), labels.clone());
-                    counter_handles.push(handle);
+                    by.push(register(labels.clone()));
                 }
                 Dimension::ByCpu(labels) => {
-                    let mut handles = vec![Counter::noop(); cpu_count];
-                    for cpu_id in &cpus {
+                    let mut handles = vec![noop.clone(); cpu_count];
+                    for cpu_id in cpus {
                         let cpu_label = Label::new(METRIC_LABEL_CPU, cpu_id.to_string());
                         let mut labels = labels.clone();
                         labels.push(cpu_label);
-                        let handle = metrics::counter!(metric.meter.name(), labels.clone());
-                        handles[*cpu_id as usize] = handle;
+                        handles[*cpu_id as usize] = register(labels);
                     }
-                    counter_handles_by_cpu.push(handles);
+                    by_cpu.push(handles);
                 }
             }
         }
+        Handles { by, by_cpu }
+    }
+}
 
-        // Store the previous state of the counters to calculate the delta for the next period
-        let mut prev_values = vec![0u64; cpu_count];
+/// Take ownership of a BPF map by name.
+fn take_map<T: TryFrom<Map, Error = MapError>>(bpf: &mut Ebpf, name: &str) -> Result<T, MapError> {
+    bpf.take_map(name)
+        .ok_or(MapError::InvalidName { name: name.to_string() })
+        .and_then(T::try_from)
+}
 
-        loop {
-            interval.tick().await;
+/// Reset the value of every CPU at an index of a BPF per CPU array, for meters which are reset each period.
+fn reset<V: Pod>(map: &mut PerCpuArray<V>, index: u32, value: V, cpu_count: usize) -> Result<(), MapError> {
+    let values = PerCpuValues::try_from(vec![value; cpu_count]).map_err(|io_error| SyscallError {
+        call: "bpf_map_update_elem",
+        io_error,
+    })?;
+    map.set(index, values, 0)
+}
+
+/// Emits custom metrics generated by an eBPF program using the [metrics] crate.
+pub struct EbpfMetrics<M: Meter<K>, K: Collector<M> = kind::Counter> {
+    map: K::Map,
+    metrics: Vec<Metric<M, K>>,
+    period: Duration,
+}
+
+impl<M: Meter<K>, K: Collector<M>> EbpfMetrics<M, K> {
+    /// Create [`EbpfMetrics<M>`] from [`Ebpf`] for specific metrics.
+    ///
+    /// Takes the BPF map holding `M`, which for counters is the map named by [`Counter::MAP_NAME`]. When
+    /// `EbpfMetrics<M>::run()` is invoked metrics will be periodically emitted with the given recorder.
+    ///
+    /// [`Counter::MAP_NAME`]: aya_metrics_common::Counter::MAP_NAME
+    pub fn new(bpf: &mut Ebpf, metrics: Vec<Metric<M, K>>, period: Duration) -> Result<EbpfMetrics<M, K>, Error> {
+        // Ensure the eBPF program was built with the same meters, so they are not reported under the wrong names
+        K::verify_layout(bpf)?;
+
+        // Take ownership of the BPF map holding the meters
+        let map = K::take_map(bpf).map_err(Error::MapError)?;
+
+        EbpfMetrics::with_map(map, metrics, period)
+    }
+
+    /// Create [`EbpfMetrics<M>`] from the BPF map holding `M`, however it was opened.
+    fn with_map(map: K::Map, metrics: Vec<Metric<M, K>>, period: Duration) -> Result<EbpfMetrics<M, K>, Error> {
+        EbpfMetrics::validate(&metrics, K::max_entries(&map))?;
 
-            // Get counter values per CPU
-            let counter_values = {
-                let guard = bpf_counters.lock().await;
-                guard.get(&metric.meter.index(), 0).map_err(Error::MapError)?
-            };
-
-            // Keep a sum across CPUs
-            let mut delta_sum = 0;
-
-            // Iterate over each CPU
-            for cpu_id in &cpus {
-                let cpu_id = *cpu_id as usize;
-                // Get the latest value for this CPU
-                if let Some(value) = counter_values.get::<usize>(cpu_id) {
-                    let value = *value;
-                    let prev_value = prev_values[cpu_id];
-                    let delta = value - prev_value;
-
-                    // Update the sum across CPUs
-                    delta_sum += delta;
-                    // Store the state for the next period
-                    prev_values[cpu_id] = value;
-
-                    // Emit metric by cpu number with any additional labels
-                    for handles in &mut counter_handles_by_cpu {
-                        handles[cpu_id].increment(delta);
+        Ok(EbpfMetrics { map, metrics, period })
+    }
+
+    /// Ensure the metrics are reported without colliding with each other, so misconfigurations fail at startup.
+    fn validate(metrics: &[Metric<M, K>], max_entries: Option<u32>) -> Result<(), Error> {
+        let mut names: HashMap<SharedString, u32> = HashMap::new();
+
+        for metric in metrics {
+            let name = metric.meter.name();
+            let index = metric.meter.index();
+
+            // Ensure every meter fits in the map, as it may be resized when loading the eBPF program
+            if let Some(max_entries) = max_entries.filter(|max_entries| index >= *max_entries) {
+                return Err(Error::IndexOutOfBounds {
+                    metric: name.to_string(),
+                    index,
+                    max_entries,
+                });
+            }
+
+            // Meters reported twice would be emitted, and reset for some kinds, twice each period
+            if let Some(other_index) = names.insert(name.clone(), index) {
+                return Err(if other_index == index {
+                    Error::DuplicateMetric {
+                        metric: name.to_string(),
+                        index,
+                    }
+                } else {
+                    Error::DuplicateMetricName {
+                        metric: name.to_string(),
+                        index,
+                        other_index,
                     }
-                } // GRCOV_IGNORE_LINE (apparently there is a hidden else block!)
+                });
             }
 
-            // Emit metric with any additional labels
-            for handle in &counter_handles {
-                handle.increment(delta_sum);
+            let mut dimensions = HashSet::new();
+            for dimension in &metric.dimensions {
+                let (labels, by_cpu) = match dimension {
+                    Dimension::By(labels) => (labels, false),
+                    Dimension::ByCpu(labels) => (labels, true),
+                };
+
+                let by_cpu_label = by_cpu.then_some(METRIC_LABEL_CPU);
+                let mut reserved = by_cpu_label.iter().chain(K::reserved_labels(metric)).copied();
+                if let Some(label) = reserved.find(|key| labels.iter().any(|label| label.key() == *key)) {
+                    return Err(Error::ReservedLabel {
+                        metric: name.to_string(),
+                        label: label.to_string(),
+                    });
+                }
+
+                // The order of labels does not change the series
+                let mut sorted = labels.clone();
+                sorted.sort_unstable();
+                if !dimensions.insert((by_cpu, sorted)) {
+                    return Err(Error::DuplicateDimension {
+                        metric: name.to_string(),
+                        dimension: dimension.clone(),
+                    });
+                }
             }
         }
+
+        Ok(())
+    }
+
+    /// Create [`EbpfMetrics<M>`] from [`Ebpf`] for every meter of an enumeration, see [`Metric::all`].
+    pub fn for_all(
+        bpf: &mut Ebpf,
+        unit: Unit,
+        dimensions: Dimensions,
+        period: Duration,
+    ) -> Result<EbpfMetrics<M, K>, Error>
+    where
+        M: IntoEnumIterator,
+    {
+        EbpfMetrics::new(bpf, Metric::all(unit, dimensions), period)
+    }
+
+    /// Periodically emit metrics
+    ///
+    /// Every metric is collected in the same loop, so the map is read once per period for all of them.
+    pub async fn run(self) -> Result<(), Error> {
+        EbpfMetrics::emit_metrics(self.map, self.metrics, self.period).await
+    }
+
+    async fn emit_metrics(mut bpf_map: K::Map, metrics: Vec<Metric<M, K>>, period: Duration) -> Result<(), Error> {
+        let mut interval = time::interval(period);
+        let cpu_count = nr_cpus().map_err(|(_, err)| Error::InvalidPossibleCpu(err))?;
+        let cpus = online_cpus().map_err(|(_, err)| Error::InvalidOnlineCpu(err))?;
+
+        // Pre-register all metrics and store their handles for better performance
+        let mut states: Vec<_> = metrics.iter().map(|metric| K::register(metric, &cpus, cpu_count)).collect();
+
+        loop {
+            interval.tick().await;
+
+            K::collect_all(&metrics, &mut states, &mut bpf_map, &cpus).map_err(Error::MapError)?;
+        }
     }
 }
 
@@ -250,6 +450,66 @@ pub enum Error {
     #[error("error opening metric array")]
     MapError(#[from] MapError),
 
+    /// The index of a meter is not less than the number of entries of its BPF map
+    #[error("index {index} of metric {metric} is out of bounds of a map with {max_entries} entries")]
+    IndexOutOfBounds {
+        /// The name of the metric
+        metric: String,
+        /// The index of the meter
+        index: u32,
+        /// The number of entries of the map
+        max_entries: u32,
+    },
+
+    /// The same meter is reported by more than one metric
+    #[error("metric {metric} with index {index} is defined more than once")]
+    DuplicateMetric {
+        /// The name of the metric
+        metric: String,
+        /// The index of the meter
+        index: u32,
+    },
+
+    /// Different meters are reported under the same name
+    #[error("metric {metric} is defined for both index {other_index} and index {index}")]
+    DuplicateMetricName {
+        /// The name of the metric
+        metric: String,
+        /// The index of the meter defined last
+        index: u32,
+        /// The index of the meter defined first
+        other_index: u32,
+    },
+
+    /// A dimension sets a label which is added when emitting the metric, such as `cpu` for [`Dimension::ByCpu`]
+    #[error("label {label} of metric {metric} is reserved")]
+    ReservedLabel {
+        /// The name of the metric
+        metric: String,
+        /// The key of the label
+        label: String,
+    },
+
+    /// A dimension is given more than once for a metric, which would count its values twice
+    #[error("metric {metric} has dimension {dimension:?} more than once")]
+    DuplicateDimension {
+        /// The name of the metric
+        metric: String,
+        /// The repeated dimension
+        dimension: Dimension,
+    },
+
+    /// The layout of the meters embedded in the eBPF program differs from the layout of the user space meters
+    #[error("layout {found:#018x} of map {map} does not match layout {expected:#018x} of the user space meters")]
+    LayoutMismatch {
+        /// The name of the map holding the meters
+        map: String,
+        /// The layout of the user space meters
+        expected: u64,
+        /// The layout embedded in the eBPF program
+        found: u64,
+    },
+
     /// Errors occuring while listing possible CPUs
     #[error("invalid /sys/devices/system/cpu/possible format")]
     InvalidPossibleCpu(#[source] io::Error),
@@ -268,8 +528,9 @@ mod mocks;
 mod test {
     use super::*;
     use aya::maps::PerCpuValues;
+    use aya_metrics_common::MeterKind;
     use metrics::Unit;
-    use metrics::{Key, Label};
+    use metrics::{Key, Label, SharedString};
 
     use mocks::metrics::MockRecorder;
 
@@ -284,9 +545,9 @@ mod test {
     }
 
     impl aya_metrics_common::Counter for MockCounter {
-        fn name(self) -> String {
+        fn name(self) -> SharedString {
             match self {
-                MockCounter::Packets => "packets".to_string(),
+                MockCounter::Packets => "packets".into(),
             }
         }
 
@@ -317,7 +578,7 @@ mod test {
         let recorder = MockRecorder::new();
         let _guard = metrics::set_default_local_recorder(&recorder);
 
-        let metrics = EbpfMetrics::new(&mut Ebpf {}, vec![get_packets_metric()], Duration::from_secs(60))?;
+        let metrics = EbpfMetrics::new(&mut Ebpf::load(&[])?, vec![get_packets_metric()], Duration::from_secs(60))?;
         tokio::spawn(async move { metrics.run().await });
 
         // Give the task a chance to run
@@ -329,11 +590,359 @@ mod test {
         Ok(())
     }
 
+    #[derive(Copy, Clone, Debug)]
+    enum MockLargeCounter {
+        Last = 149,
+    }
+
+    impl aya_metrics_common::Counter for MockLargeCounter {
+        const MAX_ENTRIES: u32 = MockLargeCounter::Last as u32 + 1;
+
+        fn name(self) -> SharedString {
+            match self {
+                MockLargeCounter::Last => "last".into(),
+            }
+        }
+
+        fn index(&self) -> u32 {
+            *self as u32
+        }
+    }
+
+    #[test]
+    fn test_new_verifies_max_entries() -> Result<(), anyhow::Error> {
+        let metrics = || vec![Metric::new(MockLargeCounter::Last, Unit::Count, vec![Dimension::By(vec![])])];
+
+        // The map is too small unless it is resized when loading
+        let result = EbpfMetrics::new(&mut Ebpf::load(&[])?, metrics(), Duration::from_secs(60));
+        assert!(matches!(
+            result,
+            Err(Error::IndexOutOfBounds { metric, index: 149, max_entries: 64 }) if metric == "last"
+        ));
+
+        let mut bpf = aya_metrics_mocks::EbpfLoader::new()
+            .set_max_entries(
+                MeterKind::Counter.map_name(),
+                <MockLargeCounter as aya_metrics_common::Counter>::MAX_ENTRIES,
+            )
+            .load(&[])?;
+        let metrics = EbpfMetrics::new(&mut bpf, metrics(), Duration::from_secs(60))?;
+        assert_eq!(metrics.map.len(), 150);
+
+        Ok(())
+    }
+
+    #[derive(Copy, Clone, Debug)]
+    enum MockRenamedCounter {
+        First,
+        Second,
+    }
+
+    impl aya_metrics_common::Counter for MockRenamedCounter {
+        fn name(self) -> SharedString {
+            "renamed".into()
+        }
+
+        fn index(&self) -> u32 {
+            *self as u32
+        }
+    }
+
+    #[test]
+    fn test_new_validates_metrics() -> Result<(), anyhow::Error> {
+        use MockDerivedCounter::{Bytes, Packets};
+
+        let mut loader = aya_metrics_mocks::EbpfLoader::new();
+        loader.set_max_entries("DERIVED_COUNTERS", 4);
+        let mut bpf = loader.load(&[])?;
+        let mut new = |metrics| EbpfMetrics::new(&mut bpf, metrics, Duration::from_secs(60));
+        let host = || Label::new("hostname", "test.hostname");
+        let region = || Label::new("region", "test.region");
+
+        // The same meter is reported twice
+        let metrics = vec![Metric::from_meter(Packets), Metric::from_meter(Bytes), Metric::from_meter(Packets)];
+        assert!(matches!(
+            new(metrics),
+            Err(Error::DuplicateMetric { metric, index: 0 }) if metric == "packets"
+        ));
+
+        // Different meters are reported under the same name
+        let metrics =
+            vec![Metric::from_meter(MockRenamedCounter::First), Metric::from_meter(MockRenamedCounter::Second)];
+        let result = EbpfMetrics::new(&mut Ebpf::load(&[])?, metrics, Duration::from_secs(60));
+        assert!(matches!(
+            result,
+            Err(Error::DuplicateMetricName { metric, index: 1, other_index: 0 }) if metric == "renamed"
+        ));
+
+        // The cpu label is only reserved by dimensions by CPU
+        let cpu = || Label::new(METRIC_LABEL_CPU, "0");
+        new(vec![Metric::from_meter(Packets).with_dimensions(vec![Dimension::By(vec![cpu()])])])?;
+        let metrics = vec![Metric::from_meter(Bytes).with_dimensions(vec![Dimension::ByCpu(vec![host(), cpu()])])];
+        assert!(matches!(
+            new(metrics),
+            Err(Error::ReservedLabel { metric, label }) if metric == "bytes_received" && label == METRIC_LABEL_CPU
+        ));
+
+        // Dimensions with the same labels in any order are the same series, unless only one is by CPU
+        let metrics = vec![Metric::from_meter(Packets).with_dimensions(vec![
+            Dimension::By(vec![host(), region()]),
+            Dimension::ByCpu(vec![region(), host()]),
+            Dimension::By(vec![region(), host()]),
+        ])];
+        assert!(matches!(
+            new(metrics),
+            Err(Error::DuplicateDimension { metric, dimension })
+                if metric == "packets" && dimension == Dimension::By(vec![region(), host()])
+        ));
+
+        Ok(())
+    }
+
+    #[derive(Copy, Clone, Debug)]
+    enum MockLibraryCounter {
+        Last = 99,
+    }
+
+    impl aya_metrics_common::Counter for MockLibraryCounter {
+        const MAX_ENTRIES: u32 = MockLibraryCounter::Last as u32 + 1;
+        const MAP_NAME: &'static str = "LIBRARY_COUNTERS";
+
+        fn name(self) -> SharedString {
+            match self {
+                MockLibraryCounter::Last => "library_last".into(),
+            }
+        }
+
+        fn index(&self) -> u32 {
+            *self as u32
+        }
+    }
+
+    #[test]
+    fn test_new_takes_custom_named_map() -> Result<(), anyhow::Error> {
+        // Only the map named by the counters is resized, so taking the default map would fail
+        let mut bpf = aya_metrics_mocks::EbpfLoader::new()
+            .set_max_entries(
+                <MockLibraryCounter as aya_metrics_common::Counter>::MAP_NAME,
+                <MockLibraryCounter as aya_metrics_common::Counter>::MAX_ENTRIES,
+            )
+            .load(&[])?;
+        let metrics = vec![Metric::new(MockLibraryCounter::Last, Unit::Count, vec![Dimension::By(vec![])])];
+        let metrics = EbpfMetrics::new(&mut bpf, metrics, Duration::from_secs(60))?;
+        assert_eq!(metrics.map.len(), 100);
+
+        Ok(())
+    }
+
+    #[derive(Copy, Clone, Debug, aya_metrics_common::Counter, strum_macros::EnumIter)]
+    #[counter(map_name = "DERIVED_COUNTERS", max_entries = 4)]
+    enum MockDerivedCounter {
+        #[counter(description = "Packets received")]
+        Packets,
+        #[counter(name = "bytes_received", unit = "bytes")]
+        Bytes,
+    }
+
+    #[test]
+    fn test_derived_counter() -> Result<(), anyhow::Error> {
+        use aya_metrics_common::Counter as C;
+        use MockDerivedCounter::{Bytes, Packets};
+
+        assert_eq!(C::index(&Packets), 0);
+        assert_eq!(C::index(&Bytes), 1);
+        assert_eq!(C::name(Packets), SharedString::const_str("packets"));
+        assert_eq!(C::name(Bytes), SharedString::const_str("bytes_received"));
+        assert_eq!(C::description(Packets), "Packets received");
+        assert_eq!(C::description(Bytes), "");
+        assert_eq!(C::unit(Packets), None);
+        assert_eq!(C::unit(Bytes), Some(Unit::Bytes));
+
+        let mut bpf = aya_metrics_mocks::EbpfLoader::new()
+            .set_max_entries(<MockDerivedCounter as C>::MAP_NAME, <MockDerivedCounter as C>::MAX_ENTRIES)
+            .load(&[])?;
+        let metrics = vec![Metric::new(Bytes, Unit::Bytes, vec![Dimension::By(vec![])])];
+        let metrics = EbpfMetrics::new(&mut bpf, metrics, Duration::from_secs(60))?;
+        assert_eq!(metrics.map.len(), 4);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_new_verifies_layout() -> Result<(), anyhow::Error> {
+        use aya_metrics_common::{layout_hash, Counter as C};
+
+        let layout = <MockDerivedCounter as C>::LAYOUT;
+        assert_eq!(layout, layout_hash(MeterKind::Counter, &[(0, "packets"), (1, "bytes_received")]));
+        let section = format!("{}DERIVED_COUNTERS", aya_metrics_common::LAYOUT_SECTION_PREFIX);
+        // The embedded layout is taken along with the map, so each check loads the object again
+        let load = |embedded: Option<u64>| {
+            let mut loader = aya_metrics_mocks::EbpfLoader::new();
+            let bytes = embedded.map(u64::to_ne_bytes);
+            if let Some(bytes) = &bytes {
+                loader.set_section(&section, bytes);
+            }
+            loader.load(&[])
+        };
+        let new = |mut bpf: Ebpf| {
+            let metrics = vec![Metric::from_meter(MockDerivedCounter::Packets)];
+            EbpfMetrics::new(&mut bpf, metrics, Duration::from_secs(60))
+        };
+
+        // The same layout, or none at all, is accepted
+        new(load(Some(layout))?)?;
+        new(load(None)?)?;
+        new(load(Some(0))?)?;
+
+        // Another layout, such as with a renamed counter, is rejected
+        let other = layout_hash(MeterKind::Counter, &[(0, "packets"), (1, "bytes")]);
+        match new(load(Some(other))?) {
+            Err(Error::LayoutMismatch { map, expected, found }) => {
+                assert_eq!(map, "DERIVED_COUNTERS");
+                assert_eq!(expected, layout);
+                assert_eq!(found, other);
+            }
+            _ => panic!("Expected a layout mismatch"),
+        }
+
+        // Counters without a layout are not checked
+        let mut bpf = aya_metrics_mocks::EbpfLoader::new()
+            .set_section(".rodata.layout.COUNTERS", &other.to_ne_bytes())
+            .load(&[])?;
+        let metrics = vec![Metric::new(MockCounter::Packets, Unit::Count, vec![Dimension::By(vec![])])];
+        EbpfMetrics::new(&mut bpf, metrics, Duration::from_secs(60))?;
+
+        Ok(())
+    }
+
+    #[derive(Copy, Clone, Debug, aya_metrics_common::Counter, strum_macros::EnumIter)]
+    #[counter(map_name = "MMAP_COUNTERS", max_entries = 2, mmap)]
+    enum MockMmapCounter {
+        Packets,
+        Bytes,
+    }
+
+    #[test]
+    fn test_new_maps_mmap_counters() -> Result<(), anyhow::Error> {
+        let cpu_count = nr_cpus().map_err(|(_, err)| err)? as u32;
+        let load = |max_entries: u32| {
+            aya_metrics_mocks::EbpfLoader::new()
+                .set_max_entries("MMAP_COUNTERS", max_entries)
+                .load(&[])
+        };
+        let new = |mut bpf: Ebpf| {
+            let metrics = Metric::<MockMmapCounter>::all(Unit::Count, vec![Dimension::By(vec![])]);
+            EbpfMetrics::new(&mut bpf, metrics, Duration::from_secs(60))
+        };
+
+        // Every possible CPU needs a stride of a whole cache line
+        new(load(8 * cpu_count)?)?;
+        match new(load(8 * cpu_count - 1)?) {
+            Err(Error::MapError(MapError::OutOfBounds { index, max_entries })) => {
+                assert_eq!(index, 8 * cpu_count - 1);
+                assert_eq!(max_entries, 8 * cpu_count - 1);
+            }
+            _ => panic!("Expected the map to be too small"),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_from_borrowed_map() -> Result<(), anyhow::Error> {
+        let metrics = || vec![get_packets_metric()];
+        let map = Ebpf::load(&[])?
+            .take_map(MeterKind::Counter.map_name())
+            .expect("Counter map should exist");
+
+        // The map is only borrowed, so it may be used again
+        let borrowed = EbpfMetrics::from_map(&map, metrics(), Duration::from_secs(60))?;
+        assert_eq!(borrowed.map.len(), aya_metrics_mocks::DEFAULT_MAX_ENTRIES);
+        EbpfMetrics::from_map_data(&map, metrics(), Duration::from_secs(60))?;
+
+        // A file descriptor which does not refer to a map is rejected
+        let file = std::fs::File::open("/dev/null")?;
+        let result = EbpfMetrics::from_fd(&file, metrics(), Duration::from_secs(60));
+        assert!(matches!(result, Err(Error::MapError(MapError::SyscallError(_)))));
+
+        // A file descriptor referring to a counter map is opened
+        let fd = PerCpuArray::new(4, 0u64).fd()?;
+        let opened = EbpfMetrics::from_fd(&fd, metrics(), Duration::from_secs(60))?;
+        assert_eq!(opened.map.len(), 4);
+
+        // A file descriptor referring to another type of map is rejected
+        let fd = Array::<u64>::new(4).fd()?;
+        let result = EbpfMetrics::from_fd(&fd, metrics(), Duration::from_secs(60));
+        assert!(matches!(result, Err(Error::MapError(MapError::InvalidMapType { .. }))));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_from_pin() -> Result<(), anyhow::Error> {
+        let metrics = || vec![get_packets_metric()];
+        PerCpuArray::new(4, 0u64).pin("/sys/fs/bpf/test_from_pin/COUNTERS")?;
+
+        let pinned = EbpfMetrics::from_pin("/sys/fs/bpf/test_from_pin/COUNTERS", metrics(), Duration::from_secs(60))?;
+        assert_eq!(pinned.map.len(), 4);
+        EbpfMetrics::from_pin_read_only("/sys/fs/bpf/test_from_pin/COUNTERS", metrics(), Duration::from_secs(60))?;
+
+        // Nothing is pinned at another path
+        let result = EbpfMetrics::from_pin("/sys/fs/bpf/test_from_pin/OTHER", metrics(), Duration::from_secs(60));
+        assert!(matches!(result, Err(Error::MapError(MapError::SyscallError(_)))));
+
+        // Memory mapped counters are pinned as an array
+        let cpu_count = nr_cpus().map_err(|(_, err)| err)?;
+        Array::<u64>::new(8 * cpu_count).pin("/sys/fs/bpf/test_from_pin/MMAP_COUNTERS")?;
+        let metrics = Metric::<MockMmapCounter>::all(Unit::Count, vec![Dimension::By(vec![])]);
+        EbpfMetrics::from_pin("/sys/fs/bpf/test_from_pin/MMAP_COUNTERS", metrics, Duration::from_secs(60))?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_metric_from_meter() {
+        // The unit and dimensions declared by the counter are used
+        let metric: Metric<_> = Metric::from_meter(MockDerivedCounter::Bytes);
+        assert_eq!(metric.unit, Unit::Bytes);
+        assert_eq!(metric.dimensions, vec![Dimension::By(vec![])]);
+
+        // The unit defaults to a count
+        let metric: Metric<_> = Metric::from_meter(MockDerivedCounter::Packets);
+        assert_eq!(metric.unit, Unit::Count);
+
+        // Both may be overridden
+        let dimensions = vec![Dimension::ByCpu(vec![])];
+        let metric: Metric<_> = Metric::from_meter(MockDerivedCounter::Bytes)
+            .with_unit(Unit::Kibibytes)
+            .with_dimensions(dimensions.clone());
+        assert_eq!(metric.unit, Unit::Kibibytes);
+        assert_eq!(metric.dimensions, dimensions);
+    }
+
+    #[test]
+    fn test_for_all() -> Result<(), anyhow::Error> {
+        let dimensions = vec![Dimension::By(vec![])];
+        let metrics = EbpfMetrics::<MockDerivedCounter>::for_all(
+            &mut Ebpf::load(&[])?,
+            Unit::Count,
+            dimensions,
+            Duration::from_secs(60),
+        )?;
+
+        // Every variant is reported, in order
+        let meters: Vec<_> = metrics.metrics.iter().map(|metric| metric.meter.index()).collect();
+        assert_eq!(meters, [0, 1]);
+        assert!(metrics.metrics.iter().all(|metric| metric.unit == Unit::Count));
+
+        Ok(())
+    }
+
     #[tokio::test(start_paused = true)]
     async fn test_run_failure_when_empty_map() {
         let empty_per_cpu_array = PerCpuArray::new(0, 0u64);
         let metrics = EbpfMetrics {
-            counters: empty_per_cpu_array,
+            map: empty_per_cpu_array.into(),
             metrics: vec![get_packets_metric()],
             period: Duration::from_secs(60),
         };
@@ -353,8 +962,8 @@ mod test {
         let _guard = metrics::set_default_local_recorder(&recorder);
 
         tokio::spawn(EbpfMetrics::emit_metrics(
-            Arc::new(Mutex::new(PerCpuArray::new(1, 0u64))),
-            get_packets_metric(),
+            PerCpuArray::new(1, 0u64).into(),
+            vec![get_packets_metric()],
             Duration::from_secs(60),
         ));
 
@@ -367,6 +976,82 @@ mod test {
         Ok(())
     }
 
+    #[tokio::test(start_paused = true)]
+    async fn test_emit_metrics_collects_every_metric() -> Result<(), anyhow::Error> {
+        let recorder = MockRecorder::new();
+        let _guard = metrics::set_default_local_recorder(&recorder);
+
+        let mut per_cpu_array = PerCpuArray::new(4, 0u64);
+        let metrics = Metric::<MockDerivedCounter>::all(Unit::Count, vec![Dimension::By(vec![])]);
+        tokio::spawn(EbpfMetrics::emit_metrics(per_cpu_array.clone().into(), metrics, Duration::from_secs(60)));
+
+        let cpu_count = nr_cpus().map_err(|(_, err)| err)?;
+        let online = online_cpus().map_err(|(_, err)| err)?.len() as u64;
+        let get_counter = |counter: MockDerivedCounter| {
+            recorder.get_counter(&Key::from_parts(aya_metrics_common::Counter::name(counter), vec![]))
+        };
+
+        // Give the task a chance to run
+        tokio::task::yield_now().await;
+        // Validate every metric is registered by the same loop (time=0s)
+        assert_eq!(get_counter(MockDerivedCounter::Packets), Some(0));
+        assert_eq!(get_counter(MockDerivedCounter::Bytes), Some(0));
+
+        // Update both counters
+        per_cpu_array.set(0, PerCpuValues::try_from(vec![3u64; cpu_count])?, 0)?;
+        per_cpu_array.set(1, PerCpuValues::try_from(vec![7u64; cpu_count])?, 0)?;
+        // Time travel 60 seconds forward!
+        time::advance(Duration::from_secs(60)).await;
+        // Give the task a chance to run
+        tokio::task::yield_now().await;
+        // Validate both metrics are collected in the same tick (time=60s)
+        assert_eq!(get_counter(MockDerivedCounter::Packets), Some(3 * online));
+        assert_eq!(get_counter(MockDerivedCounter::Bytes), Some(7 * online));
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_emit_metrics_reads_mmap_counters() -> Result<(), anyhow::Error> {
+        let recorder = MockRecorder::new();
+        let _guard = metrics::set_default_local_recorder(&recorder);
+
+        let cpu_count = nr_cpus().map_err(|(_, err)| err)?;
+        let mut array = Array::new(8 * cpu_count);
+        let metrics = Metric::<MockMmapCounter>::all(Unit::Count, vec![Dimension::ByCpu(vec![])]);
+        tokio::spawn(EbpfMetrics::emit_metrics(
+            counter::CounterArray::mmap(array.clone(), 2)?,
+            metrics,
+            Duration::from_secs(60),
+        ));
+
+        let cpus = online_cpus().map_err(|(_, err)| err)?;
+        let get_counter = |counter: MockMmapCounter, cpu_id: u32| {
+            let labels = vec![Label::new(METRIC_LABEL_CPU, cpu_id.to_string())];
+            recorder.get_counter(&Key::from_parts(aya_metrics_common::Counter::name(counter), labels))
+        };
+
+        // Give the task a chance to run
+        tokio::task::yield_now().await;
+
+        // Update the counters in the stride of each CPU
+        for cpu_id in &cpus {
+            array.set(cpu_id * 8, 3 + *cpu_id as u64, 0)?;
+            array.set(cpu_id * 8 + 1, 7, 0)?;
+        }
+        // Time travel 60 seconds forward!
+        time::advance(Duration::from_secs(60)).await;
+        // Give the task a chance to run
+        tokio::task::yield_now().await;
+        // Validate each CPU is read from its own stride (time=60s)
+        for cpu_id in cpus {
+            assert_eq!(get_counter(MockMmapCounter::Packets, cpu_id), Some(3 + cpu_id as u64));
+            assert_eq!(get_counter(MockMmapCounter::Bytes, cpu_id), Some(7));
+        }
+
+        Ok(())
+    }
+
     #[tokio::test(start_paused = true)]
     async fn test_emit_metrics_increments_counters() -> Result<(), anyhow::Error> {
         let recorder = MockRecorder::new();
@@ -375,8 +1060,8 @@ mod test {
         let mut per_cpu_array = PerCpuArray::new(1, 0u64);
 
         tokio::spawn(EbpfMetrics::emit_metrics(
-            Arc::new(Mutex::new(per_cpu_array.clone())),
-            get_packets_metric(),
+            per_cpu_array.clone().into(),
+            vec![get_packets_metric()],
             Duration::from_secs(60),
         ));
 
@@ -406,6 +1091,115 @@ mod test {
         Ok(())
     }
 
+    #[tokio::test(start_paused = true)]
+    async fn test_emit_metrics_with_baseline() -> Result<(), anyhow::Error> {
+        let recorder = MockRecorder::new();
+        let _guard = metrics::set_default_local_recorder(&recorder);
+
+        // The counters were incremented before the metrics were emitted, such as by a previous process
+        let cpu_count = nr_cpus().map_err(|(_, err)| err)?;
+        let mut per_cpu_array = PerCpuArray::new(1, 0u64);
+        per_cpu_array.set(0, PerCpuValues::try_from(vec![1000u64; cpu_count])?, 0)?;
+
+        tokio::spawn(EbpfMetrics::emit_metrics(
+            per_cpu_array.clone().into(),
+            vec![get_packets_metric().with_baseline()],
+            Duration::from_secs(60),
+        ));
+
+        // Give the task a chance to run
+        tokio::task::yield_now().await;
+        // Validate the first values are not emitted (time=0s)
+        expect_counters(&recorder, 0)?;
+
+        // Update the counters
+        per_cpu_array.set(0, PerCpuValues::try_from(vec![1042u64; cpu_count])?, 0)?;
+        // Time travel 60 seconds forward!
+        time::advance(Duration::from_secs(60)).await;
+        // Give the task a chance to run
+        tokio::task::yield_now().await;
+        // Validate only the increment since the first values is emitted (time=60s)
+        expect_counters(&recorder, 42)?;
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_emit_metrics_from_pin_after_reset() -> Result<(), anyhow::Error> {
+        let recorder = MockRecorder::new();
+        let _guard = metrics::set_default_local_recorder(&recorder);
+
+        // The counters of the pinned map were incremented before the metrics were emitted
+        let cpu_count = nr_cpus().map_err(|(_, err)| err)?;
+        let mut per_cpu_array = PerCpuArray::new(1, 0u64);
+        per_cpu_array.set(0, PerCpuValues::try_from(vec![1000u64; cpu_count])?, 0)?;
+        per_cpu_array
+            .clone()
+            .pin("/sys/fs/bpf/test_emit_metrics_from_pin_after_reset/COUNTERS")?;
+
+        let pinned = EbpfMetrics::from_pin(
+            "/sys/fs/bpf/test_emit_metrics_from_pin_after_reset/COUNTERS",
+            vec![get_packets_metric().with_baseline()],
+            Duration::from_secs(60),
+        )?;
+        tokio::spawn(pinned.run());
+
+        // Give the task a chance to run
+        tokio::task::yield_now().await;
+        // Validate the first values are not emitted (time=0s)
+        expect_counters(&recorder, 0)?;
+
+        // Count from zero again, such as after a new eBPF program pinned the map
+        per_cpu_array.set(0, PerCpuValues::try_from(vec![5u64; cpu_count])?, 0)?;
+        // Time travel 60 seconds forward!
+        time::advance(Duration::from_secs(60)).await;
+        // Give the task a chance to run
+        tokio::task::yield_now().await;
+        // Validate the lower values are emitted in full (time=60s)
+        expect_counters(&recorder, 5)?;
+
+        // Update the counters
+        per_cpu_array.set(0, PerCpuValues::try_from(vec![12u64; cpu_count])?, 0)?;
+        // Time travel 60 seconds forward!
+        time::advance(Duration::from_secs(60)).await;
+        // Give the task a chance to run
+        tokio::task::yield_now().await;
+        // Validate the increment since the reset is emitted (time=120s)
+        expect_counters(&recorder, 12)?;
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_emit_metrics_from_fd() -> Result<(), anyhow::Error> {
+        let recorder = MockRecorder::new();
+        let _guard = metrics::set_default_local_recorder(&recorder);
+
+        // The map is still updated by its owner, which only lent a file descriptor referring to it
+        let cpu_count = nr_cpus().map_err(|(_, err)| err)?;
+        let mut per_cpu_array = PerCpuArray::new(1, 0u64);
+        let fd = per_cpu_array.fd()?;
+
+        let opened = EbpfMetrics::from_fd(&fd, vec![get_packets_metric()], Duration::from_secs(60))?;
+        tokio::spawn(opened.run());
+
+        // Give the task a chance to run
+        tokio::task::yield_now().await;
+        // Validate the initial values are emitted (time=0s)
+        expect_counters(&recorder, 0)?;
+
+        // Update the counters
+        per_cpu_array.set(0, PerCpuValues::try_from(vec![42u64; cpu_count])?, 0)?;
+        // Time travel 60 seconds forward!
+        time::advance(Duration::from_secs(60)).await;
+        // Give the task a chance to run
+        tokio::task::yield_now().await;
+        // Validate the counters updated by the owner are emitted (time=60s)
+        expect_counters(&recorder, 42)?;
+
+        Ok(())
+    }
+
     fn expect_counters(recorder: &MockRecorder, packets: u64) -> Result<(), anyhow::Error> {
         let actual = recorder
             .get_counter(&Key::from_parts(
@@ -0,0 +1,199 @@
+//! Reads counters from a BPF array mapped into memory.
+
+use aya::maps::MapError;
+use aya_metrics_common::mmap_counters_stride;
+
+use crate::Array;
+
+/// Counters of a BPF array created with `BPF_F_MMAPABLE`, which are read from memory without a syscall.
+///
+/// Each CPU increments its own stride of the array, see [`mmap_counters_stride`].
+pub(crate) struct MmapCounters {
+    /// The memory of the array, shared with the eBPF program.
+    memory: Memory,
+    /// The number of entries of each CPU which may hold a counter.
+    max_entries: u32,
+    /// The number of entries from the start of the stride of one CPU to the next.
+    stride: usize,
+    /// The value of every CPU for the counter read last, reused between reads.
+    values: Vec<u64>,
+}
+
+impl MmapCounters {
+    /// Map an array holding counters in strides of `max_entries`, for every possible CPU, into memory.
+    pub(crate) fn new(map: Array<u64>, max_entries: u32, cpu_count: usize) -> Result<Self, MapError> {
+        let stride = mmap_counters_stride(max_entries) as usize;
+        let len = stride * cpu_count;
+        // The counters of CPUs without a stride would never be incremented
+        if (map.len() as usize) < len {
+            return Err(MapError::OutOfBounds {
+                index: len as u32 - 1,
+                max_entries: map.len(),
+            });
+        }
+
+        Ok(MmapCounters {
+            memory: Memory::map(map, len)?,
+            max_entries,
+            stride,
+            values: vec![0; cpu_count],
+        })
+    }
+
+    /// The number of entries of each CPU which may hold a counter.
+    pub(crate) fn len(&self) -> u32 {
+        self.max_entries
+    }
+
+    /// Read the value of every CPU for a counter.
+    pub(crate) fn read(&mut self, index: u32) -> Option<&[u64]> {
+        if index >= self.max_entries {
+            return None;
+        }
+        for (cpu_id, value) in self.values.iter_mut().enumerate() {
+            *value = self.memory.load(cpu_id * self.stride + index as usize)?;
+        }
+        Some(&self.values)
+    }
+}
+
+/// The memory of a BPF array mapped read only with `mmap`, which is unmapped when dropped.
+///
+/// Arrays opened read only are mapped too. Only if the kernel refuses to share their memory, as `mmap` drops
+/// `VM_SHARED` for files opened without write access, are they looked up instead, which is logged as a warning as
+/// each entry of every CPU then takes a syscall.
+#[cfg(not(feature = "mocks"))]
+struct Memory {
+    /// The array, whose file descriptor keeps the map alive.
+    map: Array<u64>,
+    /// The start of the mapping, unless the array is looked up.
+    ptr: Option<*const std::sync::atomic::AtomicU64>,
+    /// The number of entries mapped.
+    len: usize,
+}
+
+// SAFETY: The memory is only read atomically, and is mapped for as long as the map is owned.
+#[cfg(not(feature = "mocks"))]
+unsafe impl Send for Memory {}
+
+#[cfg(not(feature = "mocks"))]
+impl Memory {
+    /// Map the first `len` entries of an array into memory.
+    fn map(map: Array<u64>, len: usize) -> Result<Self, MapError> {
+        use std::{
+            io, mem,
+            os::fd::{AsFd, AsRawFd},
+            ptr,
+        };
+
+        use aya::{maps::IterableMap, sys::SyscallError};
+
+        let fd = map.map().fd().as_fd().as_raw_fd();
+        // SAFETY: A new shared mapping is created, which does not alias any memory of this process.
+        let ptr = unsafe {
+            libc::mmap(ptr::null_mut(), len * mem::size_of::<u64>(), libc::PROT_READ, libc::MAP_SHARED, fd, 0)
+        };
+        if ptr == libc::MAP_FAILED {
+            let io_error = io::Error::last_os_error();
+            // SAFETY: Getting the flags of a file descriptor has no side effects.
+            let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+            if io_error.raw_os_error() == Some(libc::EINVAL) && flags >= 0 && flags & libc::O_ACCMODE == libc::O_RDONLY
+            {
+                log::warn!(
+                    "memory mapped counters opened read only could not be mapped, so they are looked up each period with \
+                     a syscall for every counter and CPU: open them writable to read them from memory"
+                );
+                return Ok(Memory { map, ptr: None, len });
+            }
+            return Err(MapError::SyscallError(SyscallError { call: "mmap", io_error }));
+        }
+
+        Ok(Memory {
+            map,
+            ptr: Some(ptr.cast()),
+            len,
+        })
+    }
+
+    /// Load the value of an entry.
+    fn load(&self, index: usize) -> Option<u64> {
+        use std::sync::atomic::{AtomicU64, Ordering};
+
+        let Some(ptr) = self.ptr else {
+            return self.map.get(&(index as u32), 0).ok();
+        };
+        // SAFETY: The mapping holds `len` aligned u64s, which the eBPF program only writes atomically.
+        let entries: &[AtomicU64] = unsafe { std::slice::from_raw_parts(ptr, self.len) };
+        // Each counter is independent, so there is no need to order loads with any other memory
+        entries.get(index).map(|entry| entry.load(Ordering::Relaxed))
+    }
+}
+
+#[cfg(not(feature = "mocks"))]
+impl Drop for Memory {
+    fn drop(&mut self) {
+        if let Some(ptr) = self.ptr {
+            // SAFETY: The memory was mapped with this length and is no longer referenced.
+            unsafe { libc::munmap(ptr.cast_mut().cast(), self.len * std::mem::size_of::<u64>()) };
+        }
+    }
+}
+
+/// The memory of a mock array, which is read through the array itself.
+#[cfg(feature = "mocks")]
+struct Memory {
+    map: Array<u64>,
+}
+
+#[cfg(feature = "mocks")]
+impl Memory {
+    /// Share a mock array with whoever else holds it.
+    fn map(map: Array<u64>, _len: usize) -> Result<Self, MapError> {
+        Ok(Memory { map })
+    }
+
+    /// Load the value of an entry.
+    fn load(&self, index: usize) -> Option<u64> {
+        self.map.get(&(index as u32), 0).ok()
+    }
+}
+
+// GRCOV_STOP_COVERAGE
+#[cfg(test)]
+mod test {
+    use aya_metrics_mocks::Array;
+
+    use super::*;
+
+    #[test]
+    fn test_read() -> Result<(), anyhow::Error> {
+        // Each CPU has a stride of 8 entries
+        let mut map = Array::new(16);
+        map.set(1, 3, 0)?;
+        map.set(9, 4, 0)?;
+
+        let mut counters = MmapCounters::new(map.clone(), 2, 2)?;
+        assert_eq!(counters.len(), 2);
+        assert_eq!(counters.read(0), Some(&[0, 0][..]));
+        assert_eq!(counters.read(1), Some(&[3, 4][..]));
+        assert_eq!(counters.read(2), None);
+
+        // Values are read from the memory shared with the eBPF program
+        map.set(9, 5, 0)?;
+        assert_eq!(counters.read(1), Some(&[3, 5][..]));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_too_few_cpus() {
+        let result = MmapCounters::new(Array::new(16), 2, 3);
+        assert!(matches!(
+            result,
+            Err(MapError::OutOfBounds {
+                index: 23,
+                max_entries: 16
+            })
+        ));
+    }
+}
@@ -1,5 +1,5 @@
 // GRCOV_STOP_COVERAGE
-use metrics::{Counter, Gauge, Histogram, Key, KeyName, Metadata, Recorder, SharedString, Unit};
+use metrics::{Counter, Gauge, Histogram, HistogramFn, Key, KeyName, Metadata, Recorder, SharedString, Unit};
 use std::collections::HashMap;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
@@ -7,6 +7,21 @@ use std::sync::{Arc, Mutex};
 #[derive(Default, Clone)]
 pub struct MockRecorder {
     counters: Arc<Mutex<HashMap<Key, Arc<AtomicU64>>>>,
+    gauges: Arc<Mutex<HashMap<Key, Arc<AtomicU64>>>>,
+    histograms: Arc<Mutex<HashMap<Key, Arc<MockHistogram>>>>,
+    units: Arc<Mutex<HashMap<KeyName, Unit>>>,
+}
+
+/// Keeps every recorded sample.
+#[derive(Default)]
+struct MockHistogram {
+    samples: Mutex<Vec<f64>>,
+}
+
+impl HistogramFn for MockHistogram {
+    fn record(&self, value: f64) {
+        self.samples.lock().unwrap().push(value);
+    }
 }
 
 impl MockRecorder {
@@ -22,6 +37,28 @@ impl MockRecorder {
             .cloned()
             .map(|v| v.load(Ordering::Relaxed))
     }
+
+    pub fn get_gauge(&self, key: &Key) -> Option<f64> {
+        self.gauges
+            .lock()
+            .unwrap()
+            .get(key)
+            .cloned()
+            .map(|v| f64::from_bits(v.load(Ordering::Relaxed)))
+    }
+
+    pub fn get_histogram(&self, key: &Key) -> Option<Vec<f64>> {
+        self.histograms
+            .lock()
+            .unwrap()
+            .get(key)
+            .cloned()
+            .map(|v| v.samples.lock().unwrap().clone())
+    }
+
+    pub fn get_unit(&self, name: &'static str) -> Option<Unit> {
+        self.units.lock().unwrap().get(&KeyName::from_const_str(name)).copied()
+    }
 }
 
 impl Recorder for MockRecorder {
@@ -31,15 +68,23 @@ impl Recorder for MockRecorder {
         Counter::from_arc(counter)
     }
 
-    fn register_gauge(&self, _key: &Key, _metadata: &Metadata<'_>) -> Gauge {
-        Gauge::noop()
+    fn register_gauge(&self, key: &Key, _metadata: &Metadata<'_>) -> Gauge {
+        let key = key.clone();
+        let gauge = self.gauges.lock().unwrap().entry(key).or_default().clone();
+        Gauge::from_arc(gauge)
     }
 
-    fn register_histogram(&self, _key: &Key, _metadata: &Metadata<'_>) -> Histogram {
-        Histogram::noop()
+    fn register_histogram(&self, key: &Key, _metadata: &Metadata<'_>) -> Histogram {
+        let key = key.clone();
+        let histogram = self.histograms.lock().unwrap().entry(key).or_default().clone();
+        Histogram::from_arc(histogram)
     }
 
-    fn describe_counter(&self, _key: KeyName, _unit: Option<Unit>, _description: SharedString) {}
+    fn describe_counter(&self, key: KeyName, unit: Option<Unit>, _description: SharedString) {
+        if let Some(unit) = unit {
+            self.units.lock().unwrap().insert(key, unit);
+        }
+    }
 
     fn describe_gauge(&self, _key: KeyName, _unit: Option<Unit>, _description: SharedString) {}
 
@@ -0,0 +1,185 @@
+//! Opens BPF maps which are not owned by an [`Ebpf`](crate::Ebpf).
+
+use std::{ffi::CStr, os::fd::BorrowedFd, path::Path};
+
+use aya::maps::{MapError, MapType};
+use aya_obj::generated::{bpf_attr, BPF_F_RDONLY};
+
+use crate::{Map, MapData};
+
+/// Where to open a BPF map from, which is shared with whoever else holds it.
+// Mock maps are not read only
+#[cfg_attr(feature = "mocks", allow(dead_code))]
+pub(crate) enum Source<'a> {
+    /// A map pinned to bpffs, which only permits reading its entries when `read_only`.
+    Pin { path: &'a Path, read_only: bool },
+    /// A map borrowed from its owner, such as an [`Ebpf`](crate::Ebpf).
+    Map(&'a Map),
+    /// The data of a map borrowed from its owner.
+    MapData(&'a MapData),
+    /// A file descriptor referring to a map.
+    Fd(BorrowedFd<'a>),
+}
+
+#[cfg(not(feature = "mocks"))]
+impl Source<'_> {
+    /// Open the map, with a file descriptor of its own so the map is not taken from its owner.
+    pub(crate) fn open<T: TryFrom<Map, Error = MapError>>(&self) -> Result<T, MapError> {
+        use std::os::fd::AsFd;
+
+        let fd = match *self {
+            Source::Pin { path, read_only: false } => return Self::open_data(MapData::from_pin(path)?),
+            Source::Pin { path, read_only: true } => obj_get_read_only(path)?,
+            Source::Map(map) => {
+                let (Map::Array(map)
+                | Map::BloomFilter(map)
+                | Map::CpuMap(map)
+                | Map::DevMap(map)
+                | Map::DevMapHash(map)
+                | Map::HashMap(map)
+                | Map::LpmTrie(map)
+                | Map::LruHashMap(map)
+                | Map::PerCpuArray(map)
+                | Map::PerCpuHashMap(map)
+                | Map::PerCpuLruHashMap(map)
+                | Map::PerfEventArray(map)
+                | Map::ProgramArray(map)
+                | Map::Queue(map)
+                | Map::RingBuf(map)
+                | Map::SockHash(map)
+                | Map::SockMap(map)
+                | Map::Stack(map)
+                | Map::StackTraceMap(map)
+                | Map::Unsupported(map)
+                | Map::XskMap(map)) = map;
+                map.fd().as_fd().try_clone_to_owned()?
+            }
+            Source::MapData(map) => map.fd().as_fd().try_clone_to_owned()?,
+            Source::Fd(fd) => fd.try_clone_to_owned()?,
+        };
+        Self::open_data(MapData::from_fd(fd)?)
+    }
+
+    /// Convert the data of a map which was opened, rejecting any type of map which does not hold counters.
+    fn open_data<T: TryFrom<Map, Error = MapError>>(map: MapData) -> Result<T, MapError> {
+        let map = if is_per_cpu(map.info()?.map_type()?)? {
+            Map::PerCpuArray(map)
+        } else {
+            Map::Array(map)
+        };
+        T::try_from(map)
+    }
+}
+
+/// Whether a map of the given type holds counters per CPU, rejecting any type of map which does not hold counters.
+// Mock maps have no type to check, any mock map of another type is rejected as it is opened
+#[cfg_attr(feature = "mocks", allow(dead_code))]
+fn is_per_cpu(map_type: MapType) -> Result<bool, MapError> {
+    match map_type {
+        MapType::Array => Ok(false),
+        MapType::PerCpuArray => Ok(true),
+        _ => Err(MapError::InvalidMapType {
+            map_type: map_type as u32,
+        }),
+    }
+}
+
+/// Get a new file descriptor for a map pinned to bpffs, which only permits reading its entries.
+///
+/// Unlike [`MapData::from_pin`], the map is opened read only, which is all that is needed to read counters and is
+/// permitted for pins which are not writable by this process.
+#[cfg(not(feature = "mocks"))]
+fn obj_get_read_only(path: &Path) -> Result<std::os::fd::OwnedFd, MapError> {
+    use std::{
+        ffi::CString,
+        io, mem,
+        os::{
+            fd::{FromRawFd, OwnedFd},
+            unix::ffi::OsStrExt,
+        },
+    };
+
+    use aya::{pin::PinError, sys::SyscallError};
+    use aya_obj::generated::bpf_cmd;
+
+    let pathname = CString::new(path.as_os_str().as_bytes()).map_err(|error| MapError::PinError {
+        name: None,
+        error: PinError::InvalidPinPath {
+            path: path.into(),
+            error,
+        },
+    })?;
+    let mut attr = obj_get_read_only_attr(&pathname);
+
+    // SAFETY: The path is a valid C string for the duration of the call.
+    let fd = unsafe {
+        libc::syscall(libc::SYS_bpf, bpf_cmd::BPF_OBJ_GET, &mut attr as *mut bpf_attr, mem::size_of::<bpf_attr>())
+    };
+    if fd < 0 {
+        return Err(MapError::SyscallError(SyscallError {
+            call: "BPF_OBJ_GET",
+            io_error: io::Error::last_os_error(),
+        }));
+    }
+    // SAFETY: BPF_OBJ_GET returns a new file descriptor, which is owned from here on.
+    Ok(unsafe { OwnedFd::from_raw_fd(fd as i32) })
+}
+
+/// The attributes of `BPF_OBJ_GET` for a pin at `pathname`, which is opened read only.
+///
+/// The path must outlive the syscall. Aya only opens pins writable, so this is the only place building their
+/// attributes. Their field is named by bindgen, as `__bindgen_anon_4` in aya-obj 0.2.1, and may be renamed by any later
+/// aya-obj release.
+// Mock maps are pinned without any attributes
+#[cfg_attr(feature = "mocks", allow(dead_code))]
+fn obj_get_read_only_attr(pathname: &CStr) -> bpf_attr {
+    // SAFETY: The attributes are plain data, for which zero is a valid value.
+    let mut attr: bpf_attr = unsafe { std::mem::zeroed() };
+    attr.__bindgen_anon_4.pathname = pathname.as_ptr() as u64;
+    attr.__bindgen_anon_4.file_flags = BPF_F_RDONLY;
+    attr
+}
+
+#[cfg(feature = "mocks")]
+impl Source<'_> {
+    /// Open a mock map, sharing its values when it was pinned with `pin` or referred to by a file descriptor from `fd`.
+    pub(crate) fn open<T: TryFrom<Map, Error = MapError> + Clone + 'static>(&self) -> Result<T, MapError> {
+        match *self {
+            Source::Pin { path, .. } => aya_metrics_mocks::from_pin(path),
+            Source::Map(map) | Source::MapData(map) => T::try_from(map.clone()),
+            Source::Fd(fd) => aya_metrics_mocks::from_fd(fd),
+        }
+    }
+}
+
+// GRCOV_STOP_COVERAGE
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_obj_get_read_only_attr() {
+        let pathname = c"/sys/fs/bpf/COUNTERS";
+        let attr = obj_get_read_only_attr(pathname);
+
+        // SAFETY: The attributes of BPF_OBJ_GET were written.
+        let obj = unsafe { attr.__bindgen_anon_4 };
+        assert_eq!(obj.pathname, pathname.as_ptr() as u64);
+        assert_eq!(obj.file_flags, BPF_F_RDONLY);
+        assert_eq!(obj.bpf_fd, 0);
+    }
+
+    #[test]
+    fn test_is_per_cpu() -> Result<(), anyhow::Error> {
+        assert!(!is_per_cpu(MapType::Array)?);
+        assert!(is_per_cpu(MapType::PerCpuArray)?);
+
+        // Maps which do not hold counters are rejected
+        for map_type in [MapType::Hash, MapType::PerCpuHash, MapType::RingBuf] {
+            let result = is_per_cpu(map_type);
+            assert!(matches!(result, Err(MapError::InvalidMapType { map_type: t }) if t == map_type as u32));
+        }
+
+        Ok(())
+    }
+}
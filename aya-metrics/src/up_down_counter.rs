@@ -0,0 +1,183 @@
+//! Collects [`UpDownCounter`]s.
+
+use aya::maps::MapError;
+use aya_metrics_common::{kind, MeterKind, UpDownCounter};
+
+use crate::{take_map, Collector, Ebpf, Handles, Metric, PerCpuArray};
+
+/// Up down counter handles.
+///
+/// Up down counters are emitted as gauges, as their value may decrease.
+pub struct UpDownCounterState {
+    handles: Handles<metrics::Gauge>,
+}
+
+impl<M: UpDownCounter> Collector<M> for kind::UpDownCounter {
+    type Map = PerCpuArray<i64>;
+    type Options = ();
+    type State = UpDownCounterState;
+
+    fn take_map(bpf: &mut Ebpf) -> Result<PerCpuArray<i64>, MapError> {
+        take_map(bpf, MeterKind::UpDownCounter.map_name())
+    }
+
+    fn max_entries(map: &PerCpuArray<i64>) -> Option<u32> {
+        Some(map.len())
+    }
+
+    fn register(metric: &Metric<M, kind::UpDownCounter>, cpus: &[u32], cpu_count: usize) -> UpDownCounterState {
+        let name = metric.meter.name();
+        metrics::describe_gauge!(name.clone(), metric.unit, metric.meter.description());
+
+        let handles = Handles::register(&metric.dimensions, cpus, cpu_count, metrics::Gauge::noop(), |labels| {
+            metrics::gauge!(name.clone(), labels)
+        });
+
+        UpDownCounterState { handles }
+    }
+
+    fn collect(
+        metric: &Metric<M, kind::UpDownCounter>,
+        state: &mut UpDownCounterState,
+        map: &mut PerCpuArray<i64>,
+        cpus: &[u32],
+    ) -> Result<(), MapError> {
+        // Get values per CPU
+        let values = map.get(&metric.meter.index(), 0)?;
+
+        // Sum across all possible CPUs, as a CPU which went offline still holds its increments and decrements.
+        // A CPU may only have seen decrements, so only the sum is meaningful.
+        let sum = values.iter().fold(0i64, |sum, value| sum.wrapping_add(*value));
+
+        // Iterate over each online CPU
+        for cpu_id in cpus {
+            let cpu_id = *cpu_id as usize;
+            if let Some(value) = values.get(cpu_id) {
+                // Emit metric by cpu number with any additional labels, mostly useful for debugging
+                for handles in &state.handles.by_cpu {
+                    handles[cpu_id].set(*value as f64);
+                }
+            }
+        }
+
+        // Emit metric with any additional labels
+        for handle in &state.handles.by {
+            handle.set(sum as f64);
+        }
+
+        Ok(())
+    }
+}
+
+// GRCOV_STOP_COVERAGE
+#[cfg(test)]
+mod test {
+
+    use aya::{
+        maps::PerCpuValues,
+        util::{nr_cpus, online_cpus},
+    };
+    use aya_metrics_mocks::PerCpuArray;
+    use metrics::{Key, Label, SharedString, Unit};
+    use tokio::time::{self, Duration};
+
+    use super::*;
+    use crate::{mocks::metrics::MockRecorder, Dimension, EbpfMetrics, METRIC_LABEL_CPU};
+
+    #[derive(Copy, Clone, Debug)]
+    enum MockUpDownCounter {
+        ActiveConnections,
+    }
+
+    impl UpDownCounter for MockUpDownCounter {
+        fn name(self) -> SharedString {
+            match self {
+                MockUpDownCounter::ActiveConnections => "active_connections".into(),
+            }
+        }
+
+        fn index(&self) -> u32 {
+            match self {
+                MockUpDownCounter::ActiveConnections => 0,
+            }
+        }
+    }
+
+    fn get_gauge(recorder: &MockRecorder, labels: Vec<Label>) -> f64 {
+        recorder
+            .get_gauge(&Key::from_parts(MockUpDownCounter::ActiveConnections.name(), labels))
+            .expect("Active connections gauge should be registered")
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_emit_metrics_sums_cpus() -> Result<(), anyhow::Error> {
+        let recorder = MockRecorder::new();
+        let _guard = metrics::set_default_local_recorder(&recorder);
+
+        let mut per_cpu_array = PerCpuArray::new(1, 0i64);
+
+        tokio::spawn(EbpfMetrics::emit_metrics(
+            per_cpu_array.clone(),
+            vec![Metric::<_, kind::UpDownCounter>::new(
+                MockUpDownCounter::ActiveConnections,
+                Unit::Count,
+                vec![Dimension::By(vec![]), Dimension::ByCpu(vec![])],
+            )],
+            Duration::from_secs(60),
+        ));
+
+        // Give the task a chance to run
+        tokio::task::yield_now().await;
+        // Validate the initial registration (time=0s)
+        assert_eq!(get_gauge(&recorder, vec![]), 0.0);
+
+        // Connections are opened on the first online CPU and closed on the others
+        let cpus = online_cpus().map_err(|(_, err)| err)?;
+        let mut values = vec![0i64; nr_cpus().map_err(|(_, err)| err)?];
+        values[cpus[0] as usize] = 10;
+        for cpu_id in &cpus[1..] {
+            values[*cpu_id as usize] = -1;
+        }
+        per_cpu_array.set(0, PerCpuValues::try_from(values)?, 0)?;
+        // Time travel 60 seconds forward!
+        time::advance(Duration::from_secs(60)).await;
+        // Give the task a chance to run
+        tokio::task::yield_now().await;
+
+        // Validate the sum and per CPU values (time=60s)
+        assert_eq!(get_gauge(&recorder, vec![]), 10.0 - (cpus.len() - 1) as f64);
+        let labels = vec![Label::new(METRIC_LABEL_CPU, cpus[0].to_string())];
+        assert_eq!(get_gauge(&recorder, labels), 10.0);
+        for cpu_id in &cpus[1..] {
+            let labels = vec![Label::new(METRIC_LABEL_CPU, cpu_id.to_string())];
+            assert_eq!(get_gauge(&recorder, labels), -1.0);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_collect_sums_offline_cpus() -> Result<(), anyhow::Error> {
+        let recorder = MockRecorder::new();
+        let _guard = metrics::set_default_local_recorder(&recorder);
+
+        let metric = Metric::<_, kind::UpDownCounter>::new(
+            MockUpDownCounter::ActiveConnections,
+            Unit::Count,
+            vec![Dimension::By(vec![]), Dimension::ByCpu(vec![])],
+        );
+        let cpu_count = nr_cpus().map_err(|(_, err)| err)?;
+        let mut per_cpu_array = PerCpuArray::new(1, 0i64);
+        per_cpu_array.set(0, PerCpuValues::try_from(vec![2i64; cpu_count])?, 0)?;
+
+        // Only the first CPU is online, every other CPU opened connections before going offline
+        let online = [0];
+        let mut state = <kind::UpDownCounter as Collector<_>>::register(&metric, &online, cpu_count);
+        <kind::UpDownCounter as Collector<_>>::collect(&metric, &mut state, &mut per_cpu_array, &online)?;
+
+        assert_eq!(get_gauge(&recorder, vec![]), 2.0 * cpu_count as f64);
+        assert_eq!(get_gauge(&recorder, vec![Label::new(METRIC_LABEL_CPU, "0")]), 2.0);
+
+        Ok(())
+    }
+}
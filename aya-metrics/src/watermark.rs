@@ -0,0 +1,248 @@
+//! Collects [`Max`] and [`Min`] meters.
+
+use aya::maps::MapError;
+use aya_metrics_common::{kind, Max, Meter, MeterKind, Min, WatermarkValue};
+
+use crate::{reset, take_map, Collector, Ebpf, Handles, Metric, PerCpuArray};
+
+/// Max or min handles.
+///
+/// Max and min meters are emitted as gauges, as their value may decrease from one period to the next.
+pub struct WatermarkState {
+    handles: Handles<metrics::Gauge>,
+    cpu_count: usize,
+}
+
+impl WatermarkState {
+    /// Describe and register a max or min metric for each of its dimensions.
+    fn register<M: Meter<K>, K: Collector<M>>(metric: &Metric<M, K>, cpus: &[u32], cpu_count: usize) -> Self {
+        let name = metric.meter.name();
+        metrics::describe_gauge!(name.clone(), metric.unit, metric.meter.description());
+
+        let handles = Handles::register(&metric.dimensions, cpus, cpu_count, metrics::Gauge::noop(), |labels| {
+            metrics::gauge!(name.clone(), labels)
+        });
+
+        WatermarkState { handles, cpu_count }
+    }
+
+    /// Read and reset the values of a max or min meter, then emit them merged across CPUs by `merge`.
+    ///
+    /// Gauges are left unchanged for a period in which no value was recorded.
+    fn collect(
+        &self,
+        index: u32,
+        map: &mut PerCpuArray<WatermarkValue>,
+        cpus: &[u32],
+        merge: fn(u64, u64) -> u64,
+    ) -> Result<(), MapError> {
+        // Get values per CPU
+        let values = map.get(&index, 0)?;
+
+        // Reset the values for the next period. Values recorded between the read and the reset are lost.
+        reset(map, index, WatermarkValue::default(), self.cpu_count)?;
+
+        // Keep the merged value across CPUs which recorded a value
+        let mut merged = None;
+
+        // Iterate over each CPU
+        for cpu_id in cpus {
+            let cpu_id = *cpu_id as usize;
+            if let Some(value) = values.get(cpu_id).filter(|value| value.count > 0) {
+                merged = Some(merged.map_or(value.value, |merged| merge(merged, value.value)));
+
+                // Emit metric by cpu number with any additional labels
+                for handles in &self.handles.by_cpu {
+                    handles[cpu_id].set(value.value as f64);
+                }
+            }
+        }
+
+        // Emit metric with any additional labels
+        if let Some(merged) = merged {
+            for handle in &self.handles.by {
+                handle.set(merged as f64);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<M: Max> Collector<M> for kind::Max {
+    type Map = PerCpuArray<WatermarkValue>;
+    type Options = ();
+    type State = WatermarkState;
+
+    fn take_map(bpf: &mut Ebpf) -> Result<PerCpuArray<WatermarkValue>, MapError> {
+        take_map(bpf, MeterKind::Max.map_name())
+    }
+
+    fn max_entries(map: &PerCpuArray<WatermarkValue>) -> Option<u32> {
+        Some(map.len())
+    }
+
+    fn register(metric: &Metric<M, kind::Max>, cpus: &[u32], cpu_count: usize) -> WatermarkState {
+        WatermarkState::register(metric, cpus, cpu_count)
+    }
+
+    fn collect(
+        metric: &Metric<M, kind::Max>,
+        state: &mut WatermarkState,
+        map: &mut PerCpuArray<WatermarkValue>,
+        cpus: &[u32],
+    ) -> Result<(), MapError> {
+        state.collect(Max::index(&metric.meter), map, cpus, u64::max)
+    }
+}
+
+impl<M: Min> Collector<M> for kind::Min {
+    type Map = PerCpuArray<WatermarkValue>;
+    type Options = ();
+    type State = WatermarkState;
+
+    fn take_map(bpf: &mut Ebpf) -> Result<PerCpuArray<WatermarkValue>, MapError> {
+        take_map(bpf, MeterKind::Min.map_name())
+    }
+
+    fn max_entries(map: &PerCpuArray<WatermarkValue>) -> Option<u32> {
+        Some(map.len())
+    }
+
+    fn register(metric: &Metric<M, kind::Min>, cpus: &[u32], cpu_count: usize) -> WatermarkState {
+        WatermarkState::register(metric, cpus, cpu_count)
+    }
+
+    fn collect(
+        metric: &Metric<M, kind::Min>,
+        state: &mut WatermarkState,
+        map: &mut PerCpuArray<WatermarkValue>,
+        cpus: &[u32],
+    ) -> Result<(), MapError> {
+        state.collect(Min::index(&metric.meter), map, cpus, u64::min)
+    }
+}
+
+// GRCOV_STOP_COVERAGE
+#[cfg(test)]
+mod test {
+
+    use aya::{
+        maps::PerCpuValues,
+        util::{nr_cpus, online_cpus},
+    };
+    use aya_metrics_mocks::PerCpuArray;
+    use metrics::{Key, Label, SharedString, Unit};
+    use tokio::time::{self, Duration};
+
+    use super::*;
+    use crate::{mocks::metrics::MockRecorder, Dimension, EbpfMetrics, METRIC_LABEL_CPU};
+
+    #[derive(Copy, Clone, Debug)]
+    enum MockWatermark {
+        Burst,
+    }
+
+    impl Max for MockWatermark {
+        fn name(self) -> SharedString {
+            match self {
+                MockWatermark::Burst => "max_burst".into(),
+            }
+        }
+
+        fn index(&self) -> u32 {
+            match self {
+                MockWatermark::Burst => 0,
+            }
+        }
+    }
+
+    impl Min for MockWatermark {
+        fn name(self) -> SharedString {
+            match self {
+                MockWatermark::Burst => "min_burst".into(),
+            }
+        }
+
+        fn index(&self) -> u32 {
+            match self {
+                MockWatermark::Burst => 0,
+            }
+        }
+    }
+
+    fn get_gauge(recorder: &MockRecorder, name: &'static str, labels: Vec<Label>) -> Option<f64> {
+        recorder.get_gauge(&Key::from_parts(name, labels))
+    }
+
+    /// Values where each online CPU recorded a larger value than the previous one.
+    fn get_values() -> Result<Vec<WatermarkValue>, anyhow::Error> {
+        let mut values = vec![WatermarkValue::default(); nr_cpus().map_err(|(_, err)| err)?];
+        let cpus = online_cpus().map_err(|(_, err)| err)?;
+        for (i, cpu_id) in cpus.iter().enumerate() {
+            values[*cpu_id as usize] = WatermarkValue {
+                value: 10 * (i as u64 + 1),
+                count: 1,
+            };
+        }
+        Ok(values)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_emit_metrics_max_and_min() -> Result<(), anyhow::Error> {
+        let recorder = MockRecorder::new();
+        let _guard = metrics::set_default_local_recorder(&recorder);
+
+        let dimensions = vec![Dimension::By(vec![]), Dimension::ByCpu(vec![])];
+        let mut maxes = PerCpuArray::new(1, WatermarkValue::default());
+        let mut mins = PerCpuArray::new(1, WatermarkValue::default());
+
+        tokio::spawn(EbpfMetrics::emit_metrics(
+            maxes.clone(),
+            vec![Metric::<_, kind::Max>::new(MockWatermark::Burst, Unit::Count, dimensions.clone())],
+            Duration::from_secs(60),
+        ));
+        tokio::spawn(EbpfMetrics::emit_metrics(
+            mins.clone(),
+            vec![Metric::<_, kind::Min>::new(MockWatermark::Burst, Unit::Count, dimensions)],
+            Duration::from_secs(60),
+        ));
+
+        // Give the tasks a chance to run
+        tokio::task::yield_now().await;
+        // Validate the initial registration (time=0s)
+        assert_eq!(get_gauge(&recorder, "max_burst", vec![]), Some(0.0));
+        assert_eq!(get_gauge(&recorder, "min_burst", vec![]), Some(0.0));
+
+        // Record some values
+        maxes.set(0, PerCpuValues::try_from(get_values()?)?, 0)?;
+        mins.set(0, PerCpuValues::try_from(get_values()?)?, 0)?;
+        // Time travel 60 seconds forward!
+        time::advance(Duration::from_secs(60)).await;
+        // Give the tasks a chance to run
+        tokio::task::yield_now().await;
+
+        // Validate the merged and per CPU values (time=60s)
+        let cpus = online_cpus().map_err(|(_, err)| err)?;
+        assert_eq!(get_gauge(&recorder, "max_burst", vec![]), Some(10.0 * cpus.len() as f64));
+        assert_eq!(get_gauge(&recorder, "min_burst", vec![]), Some(10.0));
+        for (i, cpu_id) in cpus.iter().enumerate() {
+            let labels = vec![Label::new(METRIC_LABEL_CPU, cpu_id.to_string())];
+            assert_eq!(get_gauge(&recorder, "max_burst", labels.clone()), Some(10.0 * (i as f64 + 1.0)));
+            assert_eq!(get_gauge(&recorder, "min_burst", labels), Some(10.0 * (i as f64 + 1.0)));
+        }
+
+        // Validate the values were reset for the next period
+        let reset = vec![WatermarkValue::default(); nr_cpus().map_err(|(_, err)| err)?];
+        assert_eq!(maxes.get(&0, 0)?.to_vec(), reset);
+        assert_eq!(mins.get(&0, 0)?.to_vec(), reset);
+
+        // Validate the gauges are left unchanged when no value is recorded (time=120s)
+        time::advance(Duration::from_secs(60)).await;
+        tokio::task::yield_now().await;
+        assert_eq!(get_gauge(&recorder, "max_burst", vec![]), Some(10.0 * cpus.len() as f64));
+        assert_eq!(get_gauge(&recorder, "min_burst", vec![]), Some(10.0));
+
+        Ok(())
+    }
+}
@@ -9,10 +9,10 @@ pub enum MyCounter {
 
 impl aya_metrics_common::Counter for MyCounter {
     #[cfg(any(test, feature = "user"))]
-    fn name(self) -> String {
+    fn name(self) -> aya_metrics_common::SharedString {
         match self {
-            MyCounter::Packets => "packets_counter".to_string(),
-            MyCounter::Bytes => "bytes_counter".to_string(),
+            MyCounter::Packets => "packets_counter".into(),
+            MyCounter::Bytes => "bytes_counter".into(),
         }
     }
 